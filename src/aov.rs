@@ -0,0 +1,189 @@
+use crate::pt_math::{Vec3, Ray, unit_vector, mul_component};
+use crate::objects::HitRecord;
+use crate::output::{ChannelData, vector_channels};
+
+// render passes (arbitrary output variables), saved with the image. The light passes add up to the image:
+// emission + direct diffuse + indirect diffuse + specular, so compositors can rebuild it after adjusting them
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Aov {
+    Emission,        // emitted by the first surface hit, or the background seen directly
+    DiffuseDirect,   // light reaching the first hit straight from a light or the background, reflected by its diffuse lobe
+    DiffuseIndirect, // light reaching the first hit after bouncing on other surfaces, reflected by its diffuse lobe
+    Specular,        // direct and indirect light reflected or refracted by the other lobes of the first hit
+    Albedo,          // base color of the first hit
+    Normal,          // shading normal of the first hit
+    Depth,           // distance of the nearest hit among the samples of the pixel, infinite for the background
+    ObjectId,        // index of the object hit first, starting from 1. The background is 0
+}
+
+impl Aov {
+    pub const ALL: [Aov; 8] = [Aov::Emission, Aov::DiffuseDirect, Aov::DiffuseIndirect, Aov::Specular,
+                               Aov::Albedo, Aov::Normal, Aov::Depth, Aov::ObjectId];
+
+    pub fn from_name(name: &str) -> Option<Aov> {
+        Aov::ALL.iter().copied().find(|aov| aov.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Emission => "emission",
+            Aov::DiffuseDirect => "diffuse_direct",
+            Aov::DiffuseIndirect => "diffuse_indirect",
+            Aov::Specular => "specular",
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::ObjectId => "id",
+        }
+    }
+
+    // names of the channels in the exr files
+    pub fn channels(self) -> &'static [&'static str] {
+        match self {
+            Aov::Normal => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::ObjectId => &["id"],
+            _ => &["R", "G", "B"],
+        }
+    }
+
+    // passes splitting the light along the paths, that only the path tracer computes
+    pub fn is_light(self) -> bool {
+        matches!(self, Aov::Emission | Aov::DiffuseDirect | Aov::DiffuseIndirect | Aov::Specular)
+    }
+
+    // passes averaged over the samples of the pixel, like the image
+    pub fn is_averaged(self) -> bool {
+        !matches!(self, Aov::Depth | Aov::ObjectId)
+    }
+
+    // value of the pass in pixels without samples. Scalar passes are stored in the first component
+    pub fn empty(self) -> Vec3 {
+        match self {
+            Aov::Depth => Vec3::new(f32::INFINITY, 0.0, 0.0),
+            _ => Vec3::new(0.0,0.0,0.0),
+        }
+    }
+
+    pub fn value(self, sample: &AovSample) -> Vec3 {
+        match self {
+            Aov::Emission => sample.emission,
+            Aov::DiffuseDirect => sample.diffuse_direct,
+            Aov::DiffuseIndirect => sample.diffuse_indirect,
+            Aov::Specular => sample.specular,
+            Aov::Albedo => sample.albedo,
+            Aov::Normal => sample.normal,
+            Aov::Depth => Vec3::new(sample.depth, 0.0, 0.0),
+            Aov::ObjectId => Vec3::new(sample.object_id as f32, 0.0, 0.0),
+        }
+    }
+
+    // channels of the pass in the exr files, with the names prefixed. The ids are stored as integers
+    pub fn exr_channels(self, prefix: &str, pixels: &[Vec3]) -> Vec<(String, ChannelData)> {
+        match self {
+            Aov::ObjectId => vec![(format!("{}{}", prefix, self.channels()[0]), ChannelData::Uint(pixels.iter().map(|p| p.x as u32).collect()))],
+            _ => vector_channels(prefix, self.channels(), pixels),
+        }
+    }
+
+    // combine the value of a new sample (or the samples of a tile) with the ones of the pixel: the depth keeps the
+    // nearest hit and the id the first object hit, as their averages would be meaningless
+    pub fn accumulate(self, pixel: Vec3, v: Vec3) -> Vec3 {
+        match self {
+            Aov::Depth => Vec3::new(pixel.x.min(v.x), 0.0, 0.0),
+            Aov::ObjectId => if pixel.x > 0.0 { pixel } else { v },
+            _ => pixel + v,
+        }
+    }
+}
+
+// values of the passes for a camera ray
+#[derive(Clone,Copy,Debug)]
+pub struct AovSample {
+    pub emission: Vec3,
+    pub diffuse_direct: Vec3,
+    pub diffuse_indirect: Vec3,
+    pub specular: Vec3,
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub depth: f32,
+    pub object_id: u32,
+}
+
+impl Default for AovSample {
+    fn default() -> AovSample {
+        let zero = Vec3::new(0.0,0.0,0.0);
+        AovSample { emission: zero, diffuse_direct: zero, diffuse_indirect: zero, specular: zero,
+                    albedo: zero, normal: zero, depth: f32::INFINITY, object_id: 0 }
+    }
+}
+
+impl AovSample {
+    // properties of the surface hit by the camera ray
+    pub fn set_first_hit(&mut self, ray: Ray, hit: &HitRecord) {
+        self.albedo = hit.material.albedo(hit);
+        self.normal = unit_vector(hit.normal);
+        self.depth = hit.t * ray.direction.length();
+        self.object_id = hit.object_id as u32 + 1;
+    }
+
+    // light found along the path, after being reflected by the given number of surfaces. Without bounces it is
+    // emission; otherwise it is split between the diffuse and specular passes, with the fraction of the throughput
+    // of the first hit due to its diffuse lobe. The light reflected only by the first hit is direct
+    pub fn add_light(&mut self, bounces: u32, light: Vec3, diffuse_fraction: Vec3) {
+        if bounces == 0 {
+            self.emission = self.emission + light;
+        } else if bounces == 1 {
+            let diffuse = mul_component(light, diffuse_fraction);
+            self.diffuse_direct = self.diffuse_direct + diffuse;
+            self.specular = self.specular + (light - diffuse);
+        } else {
+            let diffuse = mul_component(light, diffuse_fraction);
+            self.diffuse_indirect = self.diffuse_indirect + diffuse;
+            self.specular = self.specular + (light - diffuse);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aov_names() {
+        for &aov in &Aov::ALL {
+            assert_eq!(Aov::from_name(aov.name()), Some(aov));
+        }
+        assert_eq!(Aov::from_name("beauty"), None);
+        assert_eq!(Aov::ALL.iter().filter(|aov| aov.is_light()).count(), 4);
+        assert_eq!(Aov::Depth.channels(), &["Z"]);
+    }
+
+    #[test]
+    fn aov_accumulation() {
+        let v = |x: f32| Vec3::new(x, x, x);
+        assert_eq!(Aov::Albedo.accumulate(v(0.25), v(0.5)).y, 0.75);
+        // nearest depth, and the first id
+        let depth = Aov::Depth.empty();
+        assert_eq!(Aov::Depth.accumulate(Aov::Depth.accumulate(depth, v(3.0)), v(2.0)).x, 2.0);
+        assert_eq!(Aov::Depth.accumulate(depth, Aov::Depth.empty()).x, f32::INFINITY);
+        let id = Aov::ObjectId.accumulate(Aov::ObjectId.empty(), v(0.0));
+        assert_eq!(Aov::ObjectId.accumulate(Aov::ObjectId.accumulate(id, v(4.0)), v(2.0)).x, 4.0);
+    }
+
+    #[test]
+    fn aov_light_split() {
+        let mut sample = AovSample::default();
+        sample.add_light(0, Vec3::new(1.0, 2.0, 3.0), Vec3::new(0.0,0.0,0.0));
+        sample.add_light(2, Vec3::new(1.0, 1.0, 1.0), Vec3::new(1.0, 0.5, 0.0));
+        assert_eq!(Aov::Emission.value(&sample).z, 3.0);
+        let (diffuse, specular) = (Aov::DiffuseIndirect.value(&sample), Aov::Specular.value(&sample));
+        assert_eq!((diffuse.x, diffuse.y, diffuse.z), (1.0, 0.5, 0.0));
+        assert_eq!((specular.x, specular.y, specular.z), (0.0, 0.5, 1.0));
+        // the light reflected only by the first hit is direct
+        sample.add_light(1, Vec3::new(2.0, 2.0, 2.0), Vec3::new(0.5, 0.5, 0.5));
+        assert_eq!((Aov::DiffuseDirect.value(&sample).x, Aov::DiffuseIndirect.value(&sample).x), (1.0, 1.0));
+        assert_eq!(Aov::Specular.value(&sample).x, 1.0);
+        assert_eq!(Aov::Depth.value(&sample).x, f32::INFINITY);
+    }
+}
@@ -0,0 +1,374 @@
+use crate::pt_math::{Vec3, Ray, Aabb};
+use crate::objects::{Hitable, HitRecord};
+use crate::light::SphereLight;
+
+// bounding volume hierarchy, built using the surface area heuristic (SAH).
+// Nodes are stored in a flat array in depth first order: the left child of an inner node is
+// always the next node in the array, so only the index of the right child is stored.
+
+const SAH_BINS: usize = 16;
+const MAX_LEAF_SIZE: usize = 4;
+const MAX_DEPTH: usize = 64;
+// cost of traversing an inner node, relative to the cost of intersecting a primitive
+const TRAVERSAL_COST: f32 = 1.0;
+
+#[derive(Clone,Copy,Debug)]
+pub enum BvhNode {
+    Leaf { bbox: Aabb, first: usize, count: usize },
+    Inner { bbox: Aabb, right: usize, axis: usize },
+}
+
+pub struct Bvh<T: Hitable> {
+    nodes: Vec<BvhNode>,
+    objects: Vec<T>,   // bounded objects, ordered so that every leaf references a contiguous range
+    unbounded: Vec<T>, // objects without a bounding box, always tested
+    ids: Vec<usize>,   // original index of the bounded objects, reported in the hit records
+    unbounded_ids: Vec<usize>,
+}
+
+// per-object data used only while building the tree
+struct BuildPrim {
+    bbox: Aabb,
+    centroid: Vec3,
+    index: usize,
+}
+
+impl<T: Hitable> Bvh<T> {
+    pub fn new(objects: Vec<T>) -> Bvh<T> {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        let mut prims = Vec::new();
+        let mut bounded_ids = Vec::new();
+        let mut unbounded_ids = Vec::new();
+        for (id, obj) in objects.into_iter().enumerate() {
+            if let Some(bbox) = obj.bounding_box() {
+                prims.push(BuildPrim { bbox, centroid: bbox.centroid(), index: bounded.len() });
+                bounded.push(Some(obj));
+                bounded_ids.push(id);
+            } else {
+                unbounded.push(obj);
+                unbounded_ids.push(id);
+            }
+        }
+        let mut nodes = Vec::new();
+        if !prims.is_empty() {
+            nodes.reserve(2*prims.len());
+            build_recursive(&mut nodes, &mut prims, 0, 0);
+        }
+        // reorder the objects to match the leaves
+        let objects = prims.iter().map(|p| bounded[p.index].take().unwrap()).collect();
+        let ids = prims.iter().map(|p| bounded_ids[p.index]).collect();
+        Bvh { nodes, objects, unbounded, ids, unbounded_ids }
+    }
+
+    pub fn nodes(&self) -> &[BvhNode] {
+        &self.nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len() + self.unbounded.len()
+    }
+}
+
+fn node_bbox(node: &BvhNode) -> Aabb {
+    match *node {
+        BvhNode::Leaf { bbox, .. } => bbox,
+        BvhNode::Inner { bbox, .. } => bbox,
+    }
+}
+
+// build the subtree for the given primitives and return the index of its root node.
+// offset is the position of the first primitive of the slice in the final object array
+fn build_recursive(nodes: &mut Vec<BvhNode>, prims: &mut [BuildPrim], offset: usize, depth: usize) -> usize {
+    let bbox = prims.iter().fold(Aabb::empty(), |b, p| b.union(&p.bbox));
+    let node_idx = nodes.len();
+    let leaf = BvhNode::Leaf { bbox, first: offset, count: prims.len() };
+    if prims.len() == 1 || depth >= MAX_DEPTH - 2 {
+        nodes.push(leaf);
+        return node_idx;
+    }
+    let (axis, mid) = match find_split(prims, &bbox) {
+        Some((axis, split_cost, bin_of)) => {
+            // don't split if intersecting all the primitives is cheaper than traversing further
+            if prims.len() <= MAX_LEAF_SIZE && split_cost >= prims.len() as f32 {
+                nodes.push(leaf);
+                return node_idx;
+            }
+            let mid = partition(prims, |p| bin_of(p.centroid));
+            if mid == 0 || mid == prims.len() {
+                (axis, median_split(prims, axis))
+            } else {
+                (axis, mid)
+            }
+        }
+        None => {
+            // all centroids are coincident; no split can separate them
+            nodes.push(leaf);
+            return node_idx;
+        }
+    };
+    nodes.push(BvhNode::Inner { bbox, right: 0, axis });
+    let (left_prims, right_prims) = prims.split_at_mut(mid);
+    build_recursive(nodes, left_prims, offset, depth+1);
+    let right = build_recursive(nodes, right_prims, offset+mid, depth+1);
+    nodes[node_idx] = BvhNode::Inner { bbox, right, axis };
+    node_idx
+}
+
+// binned SAH: find the best splitting plane among SAH_BINS candidates on each axis.
+// Returns the axis, the relative cost of the split and a predicate telling if a centroid
+// falls on the left side of the split.
+fn find_split(prims: &[BuildPrim], bbox: &Aabb) -> Option<(usize, f32, impl Fn(Vec3) -> bool)> {
+    let centroid_bbox = prims.iter().fold(Aabb::empty(), |b, p| b.grow(p.centroid));
+    let extent = centroid_bbox.extent();
+    let parent_area = bbox.surface_area();
+    let mut best: Option<(usize, usize, f32)> = None; // axis, bin, cost
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+        let scale = SAH_BINS as f32 / extent[axis];
+        let bin_index = |c: Vec3| (((c[axis] - centroid_bbox.min[axis]) * scale) as usize).min(SAH_BINS-1);
+        let mut counts = [0usize; SAH_BINS];
+        let mut bounds = [Aabb::empty(); SAH_BINS];
+        for p in prims {
+            let b = bin_index(p.centroid);
+            counts[b] += 1;
+            bounds[b] = bounds[b].union(&p.bbox);
+        }
+        // sweep from the right to accumulate the area/count of the right side of each plane
+        let mut right_area = [0.0f32; SAH_BINS];
+        let mut right_count = [0usize; SAH_BINS];
+        let mut acc_box = Aabb::empty();
+        let mut acc_count = 0;
+        for b in (1..SAH_BINS).rev() {
+            acc_box = acc_box.union(&bounds[b]);
+            acc_count += counts[b];
+            right_area[b] = acc_box.surface_area();
+            right_count[b] = acc_count;
+        }
+        // sweep from the left and evaluate the cost of the plane between bin b-1 and b
+        let mut acc_box = Aabb::empty();
+        let mut acc_count = 0;
+        for b in 1..SAH_BINS {
+            acc_box = acc_box.union(&bounds[b-1]);
+            acc_count += counts[b-1];
+            if acc_count == 0 || right_count[b] == 0 {
+                continue;
+            }
+            let cost = TRAVERSAL_COST + (acc_box.surface_area() * acc_count as f32
+                                         + right_area[b] * right_count[b] as f32) / parent_area;
+            if match best { Some((_, _, c)) => cost < c, None => true } {
+                best = Some((axis, b, cost));
+            }
+        }
+    }
+    let (axis, bin, cost) = best?;
+    let min = centroid_bbox.min[axis];
+    let scale = SAH_BINS as f32 / extent[axis];
+    Some((axis, cost, move |c: Vec3| (((c[axis] - min) * scale) as usize).min(SAH_BINS-1) < bin))
+}
+
+// in place partition; returns the number of elements for which the predicate is true
+fn partition<F: Fn(&BuildPrim) -> bool>(prims: &mut [BuildPrim], pred: F) -> usize {
+    let mut mid = 0;
+    for i in 0..prims.len() {
+        if pred(&prims[i]) {
+            prims.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+fn median_split(prims: &mut [BuildPrim], axis: usize) -> usize {
+    let mid = prims.len() / 2;
+    prims.select_nth_unstable_by(mid, |a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+    mid
+}
+
+impl<T: Hitable> Hitable for Bvh<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let mut hit_record = None;
+        let mut closest = t_max;
+        for (obj, &id) in self.unbounded.iter().zip(&self.unbounded_ids) {
+            if let Some(mut hr) = obj.hit(ray, t_min, closest) {
+                closest = hr.t;
+                hr.object_id = id;
+                hit_record = Some(hr);
+            }
+        }
+        if self.nodes.is_empty() {
+            return hit_record;
+        }
+        let inv_dir = Vec3::new(1.0/ray.direction.x, 1.0/ray.direction.y, 1.0/ray.direction.z);
+        let mut stack = [0usize; MAX_DEPTH];
+        let mut stack_size = 1;
+        while stack_size > 0 {
+            stack_size -= 1;
+            let node_idx = stack[stack_size];
+            match self.nodes[node_idx] {
+                BvhNode::Leaf { bbox, first, count } => {
+                    if !bbox.hit(ray, inv_dir, t_min, closest) {
+                        continue;
+                    }
+                    for (obj, &id) in self.objects[first..first+count].iter().zip(&self.ids[first..first+count]) {
+                        if let Some(mut hr) = obj.hit(ray, t_min, closest) {
+                            closest = hr.t;
+                            hr.object_id = id;
+                            hit_record = Some(hr);
+                        }
+                    }
+                }
+                BvhNode::Inner { bbox, right, axis } => {
+                    if !bbox.hit(ray, inv_dir, t_min, closest) {
+                        continue;
+                    }
+                    // push the far child first, so that the near one is visited first
+                    let left = node_idx + 1;
+                    let (near, far) = if inv_dir[axis] < 0.0 { (right, left) } else { (left, right) };
+                    stack[stack_size] = far;
+                    stack[stack_size+1] = near;
+                    stack_size += 2;
+                }
+            }
+        }
+        hit_record
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        self.nodes.first().map(node_bbox)
+    }
+
+    fn lights(&self) -> Vec<SphereLight> {
+        self.objects.iter().chain(&self.unbounded).flat_map(|obj| obj.lights()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::{Sphere, HitableList};
+    use crate::material::Lambertian;
+
+    use rand::prelude::*;
+
+    fn sphere(x: f32, y: f32, z: f32, r: f32) -> Sphere {
+        Sphere::new(Vec3::new(x, y, z), r, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))))
+    }
+
+    fn random_spheres(n: usize, seed: u64) -> Vec<Sphere> {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        (0..n).map(|_| sphere(rng.gen::<f32>()*20.0-10.0, rng.gen::<f32>()*20.0-10.0, rng.gen::<f32>()*20.0-10.0,
+                              0.1+rng.gen::<f32>()*0.5)).collect()
+    }
+
+    // check that the tree is well formed: every object is referenced by exactly one leaf
+    // and every node bbox contains its children
+    fn check_node(bvh: &Bvh<Sphere>, idx: usize, seen: &mut Vec<bool>) -> Aabb {
+        match bvh.nodes[idx] {
+            BvhNode::Leaf { bbox, first, count } => {
+                for (obj, s) in bvh.objects[first..first+count].iter().zip(&mut seen[first..first+count]) {
+                    assert!(!*s);
+                    *s = true;
+                    let ob = obj.bounding_box().unwrap();
+                    assert!(ob.min.x >= bbox.min.x && ob.max.x <= bbox.max.x);
+                }
+                bbox
+            }
+            BvhNode::Inner { bbox, right, .. } => {
+                let l = check_node(bvh, idx+1, seen);
+                let r = check_node(bvh, right, seen);
+                let u = l.union(&r);
+                assert_eq!((u.min.x, u.min.y, u.min.z), (bbox.min.x, bbox.min.y, bbox.min.z));
+                assert_eq!((u.max.x, u.max.y, u.max.z), (bbox.max.x, bbox.max.y, bbox.max.z));
+                bbox
+            }
+        }
+    }
+
+    #[test]
+    fn bvh_empty() {
+        let bvh: Bvh<Sphere> = Bvh::new(Vec::new());
+        assert_eq!(bvh.len(), 0);
+        assert!(bvh.bounding_box().is_none());
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(bvh.hit(&ray, 0.0, 100.0).is_none());
+    }
+
+    #[test]
+    fn bvh_single_object() {
+        let bvh = Bvh::new(vec![sphere(0.0, 0.0, -1.0, 0.5)]);
+        assert_eq!(bvh.len(), 1);
+        assert_eq!(bvh.nodes().len(), 1);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = bvh.hit(&ray, 0.0, 100.0).unwrap();
+        assert!((hit.t - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn bvh_structure() {
+        let bvh = Bvh::new(random_spheres(500, 42));
+        assert_eq!(bvh.len(), 500);
+        assert!(bvh.nodes().len() > 1);
+        let mut seen = vec![false; 500];
+        check_node(&bvh, 0, &mut seen);
+        assert!(seen.iter().all(|&s| s));
+    }
+
+    #[test]
+    fn bvh_bounding_box() {
+        let bvh = Bvh::new(vec![sphere(0.0, 0.0, 0.0, 1.0), sphere(4.0, 0.0, 0.0, 1.0)]);
+        let bbox = bvh.bounding_box().unwrap();
+        assert_eq!(bbox.min.x, -1.0);
+        assert_eq!(bbox.max.x, 5.0);
+    }
+
+    #[test]
+    fn bvh_coincident_objects() {
+        // objects sharing the same centroid can't be split, but must all be found
+        let bvh = Bvh::new((0..20).map(|i| sphere(0.0, 0.0, -5.0, 0.1 + i as f32 * 0.1)).collect());
+        assert_eq!(bvh.len(), 20);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = bvh.hit(&ray, 0.0, 100.0).unwrap();
+        // largest sphere has radius 2.0
+        assert!((hit.t - 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn bvh_median_split_nan() {
+        // a mesh file with nan coordinates must not crash the build
+        let mut prims: Vec<BuildPrim> = [3.0, f32::NAN, 1.0, 2.0].iter().enumerate()
+            .map(|(index, &x)| BuildPrim { bbox: Aabb::empty(), centroid: Vec3::new(x, 0.0, 0.0), index }).collect();
+        assert_eq!(median_split(&mut prims, 0), 2);
+        // nan is ordered after the numbers
+        assert!(prims[..2].iter().all(|p| p.centroid.x < 3.0));
+        assert!(prims[3].centroid.x.is_nan());
+    }
+
+    #[test]
+    fn bvh_matches_linear_list() {
+        let mut list = HitableList::new();
+        for s in random_spheres(300, 7) {
+            list.add(s);
+        }
+        let bvh = Bvh::new(random_spheres(300, 7));
+        let mut rng = SmallRng::seed_from_u64(1234);
+        for _ in 0..1000 {
+            let origin = Vec3::new(rng.gen::<f32>()*30.0-15.0, rng.gen::<f32>()*30.0-15.0, rng.gen::<f32>()*30.0-15.0);
+            let dir = Vec3::new(rng.gen::<f32>()-0.5, rng.gen::<f32>()-0.5, rng.gen::<f32>()-0.5);
+            let ray = Ray::new(origin, dir);
+            let a = list.hit(&ray, 0.001, 1000.0);
+            let b = bvh.hit(&ray, 0.001, 1000.0);
+            assert_eq!(a.is_some(), b.is_some());
+            if let (Some(a), Some(b)) = (a, b) {
+                assert_eq!(a.t, b.t);
+                // the ids are the indices in the original list, even if the bvh reorders the objects
+                assert_eq!(a.object_id, b.object_id);
+            }
+        }
+    }
+}
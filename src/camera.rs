@@ -1,4 +1,4 @@
-use crate::pt_math::{Vec3, Ray, unit_vector, cross};
+use crate::pt_math::{Vec3, Ray, unit_vector, cross, random_f32};
 
 pub struct Camera {
     lower_left_corner: Vec3,
@@ -37,13 +37,27 @@ impl Camera {
 }
 
 
+// the parameters of Camera::new, except the aspect ratio which depends on the image size.
+// Used by scene files that define their own camera
+#[derive(Clone,Copy,Debug)]
+pub struct CameraParams {
+    pub from: Vec3,
+    pub to: Vec3,
+    pub up: Vec3,
+    pub vfov_deg: f32,
+    pub aperture: f32,
+    pub dist_to_focus: f32,
+}
 
-use rand::Rng;
+impl CameraParams {
+    pub fn build(&self, aspect_ratio: f32) -> Camera {
+        Camera::new(self.from, self.to, self.up, self.vfov_deg, aspect_ratio, self.aperture, self.dist_to_focus)
+    }
+}
 
 fn random_in_unit_disc() -> Vec3 {
-    let mut rng = rand::thread_rng();
     loop {
-        let p = 2.0*Vec3::new(rng.gen::<f32>(),rng.gen::<f32>(),0.0) - Vec3::new(1.0,1.0,0.0);
+        let p = 2.0*Vec3::new(random_f32(),random_f32(),0.0) - Vec3::new(1.0,1.0,0.0);
         if p.squared_length()<=1.0 {
             return p;
         }
@@ -198,6 +212,15 @@ mod tests {
         assert_eq!(camera.lens_radius, 1.0);
     }
 
+    #[test]
+    fn camera_params_build() {
+        let params = CameraParams { from: Vec3::new(1.0, 2.0, 3.0), to: Vec3::new(0.0, 0.0, 0.0), up: Vec3::new(0.0, 1.0, 0.0),
+                                    vfov_deg: 45.0, aperture: 0.5, dist_to_focus: 2.0 };
+        let camera = params.build(1.5);
+        assert_eq!(camera.origin.x, 1.0);
+        assert_eq!(camera.lens_radius, 0.25);
+    }
+
     #[test]
     fn random_in_unit_disc_test() {
         // Test that random points are within unit disc and z=0
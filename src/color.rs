@@ -0,0 +1,316 @@
+use crate::pt_math::{Vec3, dot};
+
+// colors are rendered in linear sRGB (Rec.709 primaries, D65 white). The output color spaces have their own
+// primaries and transfer function
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum ColorSpace {
+    Srgb,
+    DisplayP3,
+    Rec2020,
+}
+
+// chromaticities (x,y) of the red, green and blue primaries
+type Primaries = [(f32, f32); 3];
+
+const D65: (f32, f32) = (0.3127, 0.3290);
+const SRGB_PRIMARIES: Primaries = [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06)];
+const P3_PRIMARIES: Primaries = [(0.680, 0.320), (0.265, 0.690), (0.150, 0.060)];
+const REC2020_PRIMARIES: Primaries = [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046)];
+
+// constants of the Rec.2020 transfer function
+const REC2020_ALPHA: f32 = 1.099_296_8;
+const REC2020_BETA: f32 = 0.018_053_97;
+
+impl ColorSpace {
+    pub fn from_name(name: &str) -> Option<ColorSpace> {
+        match name {
+            "srgb" => Some(ColorSpace::Srgb),
+            "p3" => Some(ColorSpace::DisplayP3),
+            "rec2020" => Some(ColorSpace::Rec2020),
+            _ => None,
+        }
+    }
+
+    fn primaries(self) -> Primaries {
+        match self {
+            ColorSpace::Srgb => SRGB_PRIMARIES,
+            ColorSpace::DisplayP3 => P3_PRIMARIES,
+            ColorSpace::Rec2020 => REC2020_PRIMARIES,
+        }
+    }
+
+    // linear sRGB to the linear values of this color space
+    pub fn convert_linear_srgb(self, c: Vec3) -> Vec3 {
+        if self == ColorSpace::Srgb {
+            return c;
+        }
+        let m = rgb_to_xyz(self.primaries()).inverse().mul(&rgb_to_xyz(SRGB_PRIMARIES));
+        m.transform(c)
+    }
+
+    // transfer function (OETF), from linear values in [0,1] to the encoded ones. Display P3 uses the sRGB curve
+    pub fn encode(self, v: f32) -> f32 {
+        let v = v.clamp(0.0, 1.0);
+        match self {
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => linear_to_srgb(v),
+            ColorSpace::Rec2020 => {
+                if v < REC2020_BETA { 4.5*v } else { REC2020_ALPHA*v.powf(0.45) - (REC2020_ALPHA - 1.0) }
+            }
+        }
+    }
+
+    // linear sRGB color, in [0,1] after tone mapping, to the encoded values of this color space. Colors outside
+    // of the gamut are clipped
+    pub fn encode_color(self, c: Vec3) -> Vec3 {
+        let c = self.convert_linear_srgb(c);
+        Vec3::new(self.encode(c.x), self.encode(c.y), self.encode(c.z))
+    }
+
+    // contents of the png cHRM chunk: white point and primaries, times 100000
+    pub fn png_chromaticities(self) -> Vec<u8> {
+        let [r, g, b] = self.primaries();
+        [D65, r, g, b].iter()
+            .flat_map(|&(x, y)| [x, y].to_vec())
+            .flat_map(|v| ((v*100000.0).round() as u32).to_be_bytes().to_vec())
+            .collect()
+    }
+
+    // ICC v4 display profile with the primaries and the transfer function of this color space
+    pub fn icc_profile(self) -> Vec<u8> {
+        let to_d50 = bradford_d65_to_d50();
+        let colorants = to_d50.mul(&rgb_to_xyz(self.primaries()));
+        // parametric curve Y = (aX + b)^g for X >= d, Y = cX below, inverse of `encode`
+        let curve = match self {
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => [2.4, 1.0/1.055, 0.055/1.055, 1.0/12.92, 0.04045],
+            ColorSpace::Rec2020 => [1.0/0.45, 1.0/REC2020_ALPHA, (REC2020_ALPHA - 1.0)/REC2020_ALPHA, 1.0/4.5, 4.5*REC2020_BETA],
+        };
+        let description = match self {
+            ColorSpace::Srgb => "sRGB",
+            ColorSpace::DisplayP3 => "Display P3",
+            ColorSpace::Rec2020 => "Rec. ITU-R BT.2020",
+        };
+        let column = |i: usize| Vec3::new(colorants.m[0][i], colorants.m[1][i], colorants.m[2][i]);
+        let mut para = b"para\0\0\0\0\0\x03\0\0".to_vec();
+        curve.iter().for_each(|&v| para.extend_from_slice(&s15_fixed16(v)));
+        let chad: Vec<u8> = b"sf32\0\0\0\0".iter().cloned()
+            .chain(to_d50.m.iter().flatten().flat_map(|&v| s15_fixed16(v).to_vec()))
+            .collect();
+        let tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+            (b"desc", icc_text(description)),
+            (b"cprt", icc_text("No copyright, use freely")),
+            (b"wtpt", icc_xyz(D50)),
+            (b"chad", chad),
+            (b"rXYZ", icc_xyz(column(0))),
+            (b"gXYZ", icc_xyz(column(1))),
+            (b"bXYZ", icc_xyz(column(2))),
+            (b"rTRC", para.clone()),
+            (b"gTRC", para.clone()),
+            (b"bTRC", para),
+        ];
+        // tag table after the 128 bytes header, then the tag data aligned to 4 bytes
+        let mut data = Vec::new();
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let data_start = 128 + 4 + 12*tags.len();
+        for (signature, tag) in &tags {
+            table.extend_from_slice(*signature);
+            table.extend_from_slice(&((data_start + data.len()) as u32).to_be_bytes());
+            table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            data.extend_from_slice(tag);
+            data.resize(data.len().div_ceil(4)*4, 0);
+        }
+        let mut header = vec![0u8; 128];
+        header[0..4].copy_from_slice(&((128 + table.len() + data.len()) as u32).to_be_bytes());
+        header[8..12].copy_from_slice(&[4, 0x30, 0, 0]); // version 4.3
+        header[12..16].copy_from_slice(b"mntr");
+        header[16..20].copy_from_slice(b"RGB ");
+        header[20..24].copy_from_slice(b"XYZ ");
+        header[36..40].copy_from_slice(b"acsp");
+        header[68..80].copy_from_slice(&icc_xyz(D50)[8..]); // illuminant of the connection space
+        [header, table, data].concat()
+    }
+}
+
+// sRGB transfer function, from linear values to encoded ones
+pub fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 { 12.92*v } else { 1.055*v.powf(1.0/2.4) - 0.055 }
+}
+
+// inverse of the sRGB transfer function, from encoded values to linear ones
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+// ////////////// //
+// Color matrices //
+// ////////////// //
+#[derive(Clone,Copy,Debug)]
+struct Mat3 {
+    m: [[f32; 3]; 3],
+}
+
+impl Mat3 {
+    fn diagonal(v: Vec3) -> Mat3 {
+        Mat3 { m: [[v.x, 0.0, 0.0], [0.0, v.y, 0.0], [0.0, 0.0, v.z]] }
+    }
+
+    fn row(&self, i: usize) -> Vec3 {
+        Vec3::new(self.m[i][0], self.m[i][1], self.m[i][2])
+    }
+
+    fn transform(&self, v: Vec3) -> Vec3 {
+        Vec3::new(dot(self.row(0), v), dot(self.row(1), v), dot(self.row(2), v))
+    }
+
+    fn mul(&self, other: &Mat3) -> Mat3 {
+        let mut m = [[0.0; 3]; 3];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = (0..3).map(|k| self.m[i][k]*other.m[k][j]).sum();
+            }
+        }
+        Mat3 { m }
+    }
+
+    fn inverse(&self) -> Mat3 {
+        let m = &self.m;
+        let cofactor = |i: usize, j: usize| {
+            let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+            let (c0, c1) = ((j + 1) % 3, (j + 2) % 3);
+            m[r0][c0]*m[r1][c1] - m[r0][c1]*m[r1][c0]
+        };
+        let det = (0..3).map(|j| m[0][j]*cofactor(0, j)).sum::<f32>();
+        let mut inv = [[0.0; 3]; 3];
+        for (i, row) in inv.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = cofactor(j, i) / det;
+            }
+        }
+        Mat3 { m: inv }
+    }
+}
+
+fn xy_to_xyz((x, y): (f32, f32)) -> Vec3 {
+    Vec3::new(x/y, 1.0, (1.0 - x - y)/y)
+}
+
+// matrix from the linear rgb values of a color space with the given primaries and D65 white to XYZ
+fn rgb_to_xyz(primaries: Primaries) -> Mat3 {
+    let [r, g, b] = primaries.map(xy_to_xyz);
+    let p = Mat3 { m: [[r.x, g.x, b.x], [r.y, g.y, b.y], [r.z, g.z, b.z]] };
+    // scale the primaries so that rgb white is the white point
+    let s = p.inverse().transform(xy_to_xyz(D65));
+    p.mul(&Mat3::diagonal(s))
+}
+
+// white of the ICC profile connection space
+const D50: Vec3 = Vec3 { x: 0.9642, y: 1.0, z: 0.8249 };
+
+// chromatic adaptation from D65 to D50 (Bradford), as required by the ICC profiles
+fn bradford_d65_to_d50() -> Mat3 {
+    let bradford = Mat3 { m: [[0.8951, 0.2664, -0.1614], [-0.7502, 1.7135, 0.0367], [0.0389, -0.0685, 1.0296]] };
+    let (src, dst) = (bradford.transform(xy_to_xyz(D65)), bradford.transform(D50));
+    let scale = Mat3::diagonal(Vec3::new(dst.x/src.x, dst.y/src.y, dst.z/src.z));
+    bradford.inverse().mul(&scale).mul(&bradford)
+}
+
+// //////////// //
+// ICC encoding //
+// //////////// //
+fn s15_fixed16(v: f32) -> [u8; 4] {
+    ((v as f64*65536.0).round() as i32).to_be_bytes()
+}
+
+fn icc_xyz(v: Vec3) -> Vec<u8> {
+    [b"XYZ \0\0\0\0".to_vec(), s15_fixed16(v.x).to_vec(), s15_fixed16(v.y).to_vec(), s15_fixed16(v.z).to_vec()].concat()
+}
+
+// multi localized unicode text, with a single english record
+fn icc_text(text: &str) -> Vec<u8> {
+    let utf16: Vec<u8> = text.encode_utf16().flat_map(|c| c.to_be_bytes().to_vec()).collect();
+    let mut tag = b"mluc\0\0\0\0".to_vec();
+    tag.extend_from_slice(&1u32.to_be_bytes());
+    tag.extend_from_slice(&12u32.to_be_bytes());
+    tag.extend_from_slice(b"enUS");
+    tag.extend_from_slice(&(utf16.len() as u32).to_be_bytes());
+    tag.extend_from_slice(&28u32.to_be_bytes());
+    tag.extend_from_slice(&utf16);
+    tag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approx_eq(a: f32, b: f32, epsilon: f32) -> bool {
+        (a - b).abs() < epsilon
+    }
+
+    fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    }
+
+    #[test]
+    fn srgb_transfer_function() {
+        assert_eq!(linear_to_srgb(0.0), 0.0);
+        assert!(approx_eq(linear_to_srgb(1.0), 1.0, 1e-6));
+        assert!(approx_eq(linear_to_srgb(0.5), 0.7354, 1e-4));
+        assert!(approx_eq(linear_to_srgb(0.001), 0.01292, 1e-6));
+        // continuous at the junction of the linear segment, and inverted by srgb_to_linear
+        assert!(approx_eq(linear_to_srgb(0.003_130_8), linear_to_srgb(0.003_130_9), 1e-5));
+        for &v in &[0.001, 0.02, 0.18, 0.5, 0.9] {
+            assert!(approx_eq(srgb_to_linear(linear_to_srgb(v)), v, 1e-5));
+        }
+        assert!(approx_eq(ColorSpace::Rec2020.encode(1.0), 1.0, 1e-5));
+        assert!(approx_eq(ColorSpace::Rec2020.encode(0.01), 0.045, 1e-6));
+        assert_eq!(ColorSpace::DisplayP3.encode(2.0), ColorSpace::Srgb.encode(1.0));
+    }
+
+    #[test]
+    fn gamut_conversion() {
+        // white stays white, and the sRGB primaries are inside the wider gamuts
+        for &space in &[ColorSpace::Srgb, ColorSpace::DisplayP3, ColorSpace::Rec2020] {
+            let w = space.convert_linear_srgb(Vec3::new(1.0, 1.0, 1.0));
+            assert!(approx_eq(w.x, 1.0, 1e-4) && approx_eq(w.y, 1.0, 1e-4) && approx_eq(w.z, 1.0, 1e-4));
+            let r = space.convert_linear_srgb(Vec3::new(1.0, 0.0, 0.0));
+            assert!(r.x > 0.0 && r.y >= -1e-4 && r.z >= -1e-4);
+        }
+        // published conversion matrices
+        let r = ColorSpace::DisplayP3.convert_linear_srgb(Vec3::new(1.0, 0.0, 0.0));
+        assert!(approx_eq(r.x, 0.8225, 1e-3) && approx_eq(r.y, 0.0332, 1e-3) && approx_eq(r.z, 0.0171, 1e-3));
+        let r = ColorSpace::Rec2020.convert_linear_srgb(Vec3::new(1.0, 0.0, 0.0));
+        assert!(approx_eq(r.x, 0.6274, 1e-3) && approx_eq(r.y, 0.0691, 1e-3) && approx_eq(r.z, 0.0164, 1e-3));
+        assert_eq!(ColorSpace::from_name("p3"), Some(ColorSpace::DisplayP3));
+        assert_eq!(ColorSpace::from_name("adobe"), None);
+    }
+
+    #[test]
+    fn icc_profile() {
+        let profile = ColorSpace::DisplayP3.icc_profile();
+        assert_eq!(be_u32(&profile, 0) as usize, profile.len());
+        assert_eq!(&profile[36..40], b"acsp");
+        let tags = be_u32(&profile, 128) as usize;
+        assert_eq!(tags, 10);
+        // every tag is inside the profile
+        for i in 0..tags {
+            let entry = 132 + 12*i;
+            let (offset, size) = (be_u32(&profile, entry + 4) as usize, be_u32(&profile, entry + 8) as usize);
+            assert!(offset % 4 == 0 && offset + size <= profile.len());
+        }
+        // the red colorant of sRGB, adapted to D50, has the published value
+        let srgb = ColorSpace::Srgb.icc_profile();
+        let offset = be_u32(&srgb, 132 + 12*4 + 4) as usize;
+        assert_eq!(&srgb[132 + 12*4..132 + 12*4 + 4], b"rXYZ");
+        let fixed = |o: usize| be_u32(&srgb, o) as i32 as f32 / 65536.0;
+        assert!(approx_eq(fixed(offset + 8), 0.4361, 1e-3) && approx_eq(fixed(offset + 12), 0.2225, 1e-3)
+                && approx_eq(fixed(offset + 16), 0.0139, 1e-3));
+    }
+
+    #[test]
+    fn png_chromaticities() {
+        let chrm = ColorSpace::Srgb.png_chromaticities();
+        assert_eq!(chrm.len(), 32);
+        assert_eq!(be_u32(&chrm, 0), 31270);
+        assert_eq!(be_u32(&chrm, 8), 64000);
+        assert_eq!(be_u32(&ColorSpace::Rec2020.png_chromaticities(), 28), 4600);
+    }
+}
@@ -0,0 +1,385 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::pt_math::{Vec3, unit_vector, cross};
+use crate::objects::HitableList;
+use crate::mesh::{MeshVertices, TriangleMesh};
+use crate::material::{Material, DiffuseLight, Principled, PrincipledParams};
+use crate::texture::{solid, constant};
+use crate::camera::CameraParams;
+
+// glTF 2.0 loader (.gltf with external or embedded buffers, and binary .glb).
+// The node hierarchy of the default scene is flattened: every triangle primitive becomes a
+// TriangleMesh in world space. The first perspective camera found is returned as well.
+
+#[derive(Debug)]
+pub enum GltfError {
+    Io { path: PathBuf, error: std::io::Error },
+    Gltf { path: PathBuf, error: gltf::Error },
+    Data { path: PathBuf, message: String },
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GltfError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            GltfError::Gltf { path, error } => write!(f, "{}: {}", path.display(), error),
+            GltfError::Data { path, message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {}
+
+pub struct GltfScene {
+    pub world: HitableList,
+    pub camera: Option<CameraParams>,
+}
+
+// column major 4x4 matrix, as stored by glTF
+type Mat4 = [[f32; 4]; 4];
+
+const IDENTITY: Mat4 = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+
+fn mat_mul(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut m = [[0.0; 4]; 4];
+    for (c, col) in m.iter_mut().enumerate() {
+        for (r, v) in col.iter_mut().enumerate() {
+            *v = (0..4).map(|k| a[k][r] * b[c][k]).sum();
+        }
+    }
+    m
+}
+
+fn transform_point(m: &Mat4, p: Vec3) -> Vec3 {
+    Vec3::new(m[0][0]*p.x + m[1][0]*p.y + m[2][0]*p.z + m[3][0],
+              m[0][1]*p.x + m[1][1]*p.y + m[2][1]*p.z + m[3][1],
+              m[0][2]*p.x + m[1][2]*p.y + m[2][2]*p.z + m[3][2])
+}
+
+fn transform_vector(m: &Mat4, v: Vec3) -> Vec3 {
+    Vec3::new(m[0][0]*v.x + m[1][0]*v.y + m[2][0]*v.z,
+              m[0][1]*v.x + m[1][1]*v.y + m[2][1]*v.z,
+              m[0][2]*v.x + m[1][2]*v.y + m[2][2]*v.z)
+}
+
+// normals transform with the inverse transpose of the upper 3x3 block. The columns of the
+// inverse transpose are the cross products of the columns of the matrix (up to the determinant,
+// which only matters for its sign since the normals are normalized anyway)
+fn normal_matrix(m: &Mat4) -> [Vec3; 3] {
+    let c0 = Vec3::new(m[0][0], m[0][1], m[0][2]);
+    let c1 = Vec3::new(m[1][0], m[1][1], m[1][2]);
+    let c2 = Vec3::new(m[2][0], m[2][1], m[2][2]);
+    let det = crate::pt_math::dot(c0, cross(c1, c2));
+    let s = if det < 0.0 { -1.0 } else { 1.0 };
+    [s*cross(c1, c2), s*cross(c2, c0), s*cross(c0, c1)]
+}
+
+fn transform_normal(n: &[Vec3; 3], v: Vec3) -> Vec3 {
+    unit_vector(v.x*n[0] + v.y*n[1] + v.z*n[2])
+}
+
+pub fn load_gltf(path: &Path) -> Result<GltfScene, GltfError> {
+    let data = std::fs::read(path).map_err(|error| GltfError::Io { path: path.to_path_buf(), error })?;
+    parse_gltf(&data, path)
+}
+
+// the contents of every buffer of the document
+fn load_buffers(gltf: &gltf::Gltf, path: &Path) -> Result<Vec<Vec<u8>>, GltfError> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut buffers = Vec::new();
+    for buffer in gltf.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => gltf.blob.clone().ok_or_else(|| GltfError::Data {
+                path: path.to_path_buf(), message: "missing binary chunk".to_string() })?,
+            gltf::buffer::Source::Uri(uri) if uri.starts_with("data:") => {
+                let encoded = uri.split(";base64,").nth(1).ok_or_else(|| GltfError::Data {
+                    path: path.to_path_buf(), message: format!("buffer {}: unsupported data uri", buffer.index()) })?;
+                base64::decode(encoded).map_err(|e| GltfError::Data {
+                    path: path.to_path_buf(), message: format!("buffer {}: {}", buffer.index(), e) })?
+            }
+            gltf::buffer::Source::Uri(uri) => {
+                let buffer_path = dir.join(uri.replace("%20", " "));
+                std::fs::read(&buffer_path).map_err(|error| GltfError::Io { path: buffer_path, error })?
+            }
+        };
+        if data.len() < buffer.length() {
+            return Err(GltfError::Data { path: path.to_path_buf(),
+                                         message: format!("buffer {} is {} bytes long, expected {}", buffer.index(), data.len(), buffer.length()) });
+        }
+        buffers.push(data);
+    }
+    Ok(buffers)
+}
+
+// glTF metallic-roughness materials map onto the principled material; emissive materials become lights
+fn convert_material(material: &gltf::Material) -> Box<dyn Material> {
+    let pbr = material.pbr_metallic_roughness();
+    let [r, g, b, a] = pbr.base_color_factor();
+    let [er, eg, eb] = material.emissive_factor();
+    let emission = Vec3::new(er, eg, eb) * material.emissive_strength().unwrap_or(1.0);
+    if emission.x.max(emission.y).max(emission.z) > 0.0 {
+        return Box::new(DiffuseLight::new(emission));
+    }
+    // blended materials are treated as glass, as transparent as they are
+    let blend = if material.alpha_mode() == gltf::material::AlphaMode::Blend { 1.0 - a } else { 0.0 };
+    let transmission = material.transmission().map_or(0.0, |t| t.transmission_factor());
+    Box::new(Principled::new(PrincipledParams {
+        base_color: solid(Vec3::new(r, g, b)),
+        metallic: constant(pbr.metallic_factor()),
+        roughness: constant(pbr.roughness_factor()),
+        transmission: constant(transmission.max(blend)),
+        ior: material.ior().unwrap_or(1.5),
+        ..PrincipledParams::default()
+    }))
+}
+
+struct Loader<'a> {
+    path: &'a Path,
+    buffers: Vec<Vec<u8>>,
+    world: HitableList,
+    camera: Option<CameraParams>,
+}
+
+impl<'a> Loader<'a> {
+    fn error(&self, message: String) -> GltfError {
+        GltfError::Data { path: self.path.to_path_buf(), message }
+    }
+
+    fn load_node(&mut self, node: gltf::Node, parent: &Mat4) -> Result<(), GltfError> {
+        let transform = mat_mul(parent, &node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            self.load_mesh(&mesh, &transform)?;
+        }
+        if let (Some(camera), None) = (node.camera(), self.camera) {
+            if let gltf::camera::Projection::Perspective(p) = camera.projection() {
+                // glTF cameras look down their local -z axis, with y up
+                let from = transform_point(&transform, Vec3::new(0.0, 0.0, 0.0));
+                let forward = unit_vector(transform_vector(&transform, Vec3::new(0.0, 0.0, -1.0)));
+                let up = transform_vector(&transform, Vec3::new(0.0, 1.0, 0.0));
+                self.camera = Some(CameraParams { from, to: from + forward, up, vfov_deg: p.yfov().to_degrees(),
+                                                  aperture: 0.0, dist_to_focus: 1.0 });
+            }
+        }
+        for child in node.children() {
+            self.load_node(child, &transform)?;
+        }
+        Ok(())
+    }
+
+    fn load_mesh(&mut self, mesh: &gltf::Mesh, transform: &Mat4) -> Result<(), GltfError> {
+        let normal_transform = normal_matrix(transform);
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                continue; // points, lines and strips are not supported
+            }
+            let name = mesh.name().map_or(format!("mesh {}", mesh.index()), |n| format!("mesh '{}'", n));
+            let buffers = &self.buffers;
+            let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|b| &b[..]));
+            let positions: Vec<Vec3> = match reader.read_positions() {
+                Some(iter) => iter.map(|p| transform_point(transform, Vec3::new(p[0], p[1], p[2]))).collect(),
+                None => return Err(self.error(format!("{}: primitive without positions", name))),
+            };
+            let normals: Vec<Vec3> = reader.read_normals().map_or(Vec::new(), |iter| {
+                iter.map(|n| transform_normal(&normal_transform, Vec3::new(n[0], n[1], n[2]))).collect()
+            });
+            let uvs: Vec<(f32, f32)> = reader.read_tex_coords(0).map_or(Vec::new(), |iter| {
+                iter.into_f32().map(|t| (t[0], t[1])).collect()
+            });
+            let colors: Vec<Vec3> = reader.read_colors(0).map_or(Vec::new(), |iter| {
+                iter.into_rgb_f32().map(|c| Vec3::new(c[0], c[1], c[2])).collect()
+            });
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(iter) => iter.into_u32().collect(),
+                None => (0..positions.len() as u32).collect(),
+            };
+            if normals.len() != positions.len() && !normals.is_empty()
+                || uvs.len() != positions.len() && !uvs.is_empty()
+                || colors.len() != positions.len() && !colors.is_empty() {
+                return Err(self.error(format!("{}: vertex attributes have different lengths", name)));
+            }
+            if let Some(&i) = indices.iter().find(|&&i| i as usize >= positions.len()) {
+                return Err(self.error(format!("{}: index {} out of range ({} vertices)", name, i, positions.len())));
+            }
+            let triangles: Vec<[u32; 3]> = indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect();
+            let vertices = MeshVertices::new(positions, normals, uvs).with_colors(colors);
+            let material = convert_material(&primitive.material());
+            self.world.add(TriangleMesh::new(Arc::new(vertices), triangles, material));
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_gltf(data: &[u8], path: &Path) -> Result<GltfScene, GltfError> {
+    let gltf = gltf::Gltf::from_slice(data).map_err(|error| GltfError::Gltf { path: path.to_path_buf(), error })?;
+    let buffers = load_buffers(&gltf, path)?;
+    let scene = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+        Some(scene) => scene,
+        None => return Err(GltfError::Data { path: path.to_path_buf(), message: "the file contains no scene".to_string() }),
+    };
+    let mut loader = Loader { path, buffers, world: HitableList::new(), camera: None };
+    for node in scene.nodes() {
+        loader.load_node(node, &IDENTITY)?;
+    }
+    Ok(GltfScene { world: loader.world, camera: loader.camera })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pt_math::Ray;
+    use crate::objects::Hitable;
+
+    fn approx_eq(a: f32, b: f32, epsilon: f32) -> bool {
+        (a - b).abs() < epsilon
+    }
+
+    // a triangle in the xy plane, instanced by a node translated along -z and by a child node
+    // that is also rotated by 90 degrees around y. A camera sits at the origin.
+    fn document(buffer_uri: &str) -> String {
+        r#"{
+  "asset": {"version": "2.0"},
+  "scene": 0,
+  "scenes": [{"nodes": [0, 2]}],
+  "nodes": [
+    {"mesh": 0, "translation": [0, 0, -5], "children": [1]},
+    {"mesh": 0, "rotation": [0, 0.7071068, 0, 0.7071068], "translation": [10, 0, 0]},
+    {"camera": 0, "translation": [0, 1, 0]}
+  ],
+  "cameras": [{"type": "perspective", "perspective": {"yfov": 0.7853982, "znear": 0.1}}],
+  "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1, "material": 0}]}],
+  "materials": [{"pbrMetallicRoughness": {"baseColorFactor": [0.9, 0.1, 0.1, 1.0], "metallicFactor": 1.0, "roughnessFactor": 0.5}}],
+  "buffers": [{"byteLength": 42, "uri": "URI"}],
+  "bufferViews": [
+    {"buffer": 0, "byteOffset": 0, "byteLength": 36},
+    {"buffer": 0, "byteOffset": 36, "byteLength": 6}
+  ],
+  "accessors": [
+    {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [-1, -1, 0], "max": [1, 1, 0]},
+    {"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}
+  ]
+}"#.replace("URI", buffer_uri)
+    }
+
+    fn buffer() -> Vec<u8> {
+        let mut data = Vec::new();
+        for v in &[-1.0f32, -1.0, 0.0, 1.0, -1.0, 0.0, 0.0, 1.0, 0.0] {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        for i in &[0u16, 1, 2] {
+            data.extend_from_slice(&i.to_le_bytes());
+        }
+        data
+    }
+
+    fn check_scene(scene: &GltfScene) {
+        assert_eq!(scene.world.objects.len(), 2);
+        // translated instance
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = scene.world.hit(&ray, 0.0, 100.0).unwrap();
+        assert!(approx_eq(hit.t, 5.0, 1e-4));
+        assert!(approx_eq(hit.normal.z, 1.0, 1e-4));
+        // the child is rotated so that it faces +x, at (10, 0, -5)
+        let ray = Ray::new(Vec3::new(20.0, 0.0, -5.0), Vec3::new(-1.0, 0.0, 0.0));
+        let hit = scene.world.hit(&ray, 0.0, 100.0).unwrap();
+        assert!(approx_eq(hit.t, 10.0, 1e-4));
+        assert!(approx_eq(hit.normal.x, 1.0, 1e-4));
+        // camera
+        let camera = scene.camera.unwrap();
+        assert!(approx_eq(camera.from.y, 1.0, 1e-5));
+        assert!(approx_eq(camera.to.z, -1.0, 1e-5));
+        assert!(approx_eq(camera.vfov_deg, 45.0, 1e-3));
+    }
+
+    #[test]
+    fn gltf_embedded_buffer() {
+        let uri = format!("data:application/octet-stream;base64,{}", base64::encode(buffer()));
+        let scene = parse_gltf(document(&uri).as_bytes(), Path::new("test.gltf")).unwrap();
+        check_scene(&scene);
+    }
+
+    #[test]
+    fn gltf_external_buffer() {
+        let dir = std::env::temp_dir().join(format!("path_tracer_gltf_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("triangle.bin"), buffer()).unwrap();
+        std::fs::write(dir.join("triangle.gltf"), document("triangle.bin")).unwrap();
+        let scene = load_gltf(&dir.join("triangle.gltf"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        check_scene(&scene.unwrap());
+    }
+
+    #[test]
+    fn gltf_binary() {
+        let json = document("").replace(r#", "uri": """#, "");
+        let mut json = json.into_bytes();
+        while !json.len().is_multiple_of(4) {
+            json.push(b' ');
+        }
+        let mut bin = buffer();
+        while !bin.len().is_multiple_of(4) {
+            bin.push(0);
+        }
+        let mut glb = Vec::new();
+        glb.extend_from_slice(b"glTF");
+        glb.extend_from_slice(&2u32.to_le_bytes());
+        glb.extend_from_slice(&((12 + 8 + json.len() + 8 + bin.len()) as u32).to_le_bytes());
+        glb.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        glb.extend_from_slice(b"JSON");
+        glb.extend_from_slice(&json);
+        glb.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+        glb.extend_from_slice(b"BIN\0");
+        glb.extend_from_slice(&bin);
+        let scene = parse_gltf(&glb, Path::new("test.glb")).unwrap();
+        check_scene(&scene);
+    }
+
+    #[test]
+    fn gltf_emissive_material() {
+        let uri = format!("data:application/octet-stream;base64,{}", base64::encode(buffer()));
+        let doc = document(&uri).replace(r#""materials": [{"#, r#""materials": [{"emissiveFactor": [1.0, 0.5, 0.25], "extensions": {"KHR_materials_emissive_strength": {"emissiveStrength": 4.0}}, "#);
+        let scene = parse_gltf(doc.as_bytes(), Path::new("test.gltf")).unwrap();
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = scene.world.hit(&ray, 0.0, 100.0).unwrap();
+        let e = hit.material.emitted(&hit);
+        assert!(approx_eq(e.x, 4.0, 1e-5) && approx_eq(e.y, 2.0, 1e-5) && approx_eq(e.z, 1.0, 1e-5));
+    }
+
+    #[test]
+    fn gltf_principled_material() {
+        let uri = format!("data:application/octet-stream;base64,{}", base64::encode(buffer()));
+        let scene = parse_gltf(document(&uri).as_bytes(), Path::new("test.gltf")).unwrap();
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = scene.world.hit(&ray, 0.0, 100.0).unwrap();
+        let albedo = hit.material.albedo(&hit);
+        assert!(approx_eq(albedo.x, 0.9, 1e-5) && approx_eq(albedo.y, 0.1, 1e-5));
+        assert!(match hit.material.scatter(ray, &hit) { Some(s) => s.pdf.is_some(), None => true });
+    }
+
+    #[test]
+    fn gltf_errors() {
+        assert!(matches!(parse_gltf(b"{not json", Path::new("test.gltf")), Err(GltfError::Gltf { .. })));
+        assert!(matches!(parse_gltf(document("missing.bin").as_bytes(), Path::new("test.gltf")), Err(GltfError::Io { .. })));
+        // buffer shorter than declared
+        let uri = format!("data:application/octet-stream;base64,{}", base64::encode(&buffer()[..20]));
+        assert!(matches!(parse_gltf(document(&uri).as_bytes(), Path::new("test.gltf")), Err(GltfError::Data { .. })));
+    }
+
+    #[test]
+    fn matrix_helpers() {
+        // translation after scaling
+        let scale: Mat4 = [[2.0, 0.0, 0.0, 0.0], [0.0, 2.0, 0.0, 0.0], [0.0, 0.0, 2.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+        let translate: Mat4 = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [1.0, 2.0, 3.0, 1.0]];
+        let m = mat_mul(&translate, &scale);
+        let p = transform_point(&m, Vec3::new(1.0, 1.0, 1.0));
+        assert_eq!((p.x, p.y, p.z), (3.0, 4.0, 5.0));
+        let v = transform_vector(&m, Vec3::new(1.0, 1.0, 1.0));
+        assert_eq!((v.x, v.y, v.z), (2.0, 2.0, 2.0));
+        // non uniform scale: normals must stay perpendicular to the surface
+        let squash: Mat4 = [[1.0, 0.0, 0.0, 0.0], [0.0, 0.5, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+        let n = transform_normal(&normal_matrix(&squash), unit_vector(Vec3::new(1.0, 1.0, 0.0)));
+        let tangent = transform_vector(&squash, Vec3::new(1.0, -1.0, 0.0));
+        assert!(approx_eq(crate::pt_math::dot(n, tangent), 0.0, 1e-5));
+    }
+}
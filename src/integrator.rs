@@ -0,0 +1,742 @@
+use crate::pt_math::{Vec3, Ray, dot, mul_component, unit_vector, random_f32, random_unit_vector};
+use crate::objects::{Hitable, HitRecord};
+use crate::light::{SphereLight, lights_pdf};
+use crate::aov::AovSample;
+
+pub const MIN_DIST : f32 = 0.0001; //10.0 * std::f32::MIN_POSITIVE;
+pub const MAX_DIST : f32 = 1000000.0;
+
+// maximum number of bounces of a path, and the depth after which russian roulette can terminate it
+pub const DEFAULT_MAX_DEPTH: u32 = 50;
+pub const DEFAULT_RR_DEPTH: u32 = 3;
+
+// computes the radiance arriving along a camera ray
+pub trait Integrator: Send + Sync {
+    fn radiance(&self, ray: Ray, world: &dyn Hitable, lights: &[SphereLight]) -> Vec3;
+    // same as `radiance`, also filling the render passes. By default only the properties of the first hit are
+    // filled: splitting the light needs the path tracer
+    fn radiance_aovs(&self, ray: Ray, world: &dyn Hitable, lights: &[SphereLight], aovs: &mut AovSample) -> Vec3 {
+        if let Some(hit) = world.hit(&ray, MIN_DIST, MAX_DIST) {
+            aovs.set_first_hit(ray, &hit);
+        }
+        self.radiance(ray, world, lights)
+    }
+}
+
+// the integrators that can be selected by name
+pub fn create_integrator(name: &str, ao_distance: f32, max_depth: u32, rr_depth: u32, background: Background) -> Option<Box<dyn Integrator>> {
+    match name {
+        "path" => Some(Box::new(PathTracer::new(max_depth, rr_depth).with_background(background))),
+        "whitted" => Some(Box::new(Whitted::new(max_depth).with_background(background))),
+        "ao" => Some(Box::new(AmbientOcclusion::new(ao_distance))),
+        _ => None,
+    }
+}
+
+// light coming from the directions that miss the scene
+#[derive(Clone,Copy,Debug,Default)]
+pub enum Background {
+    #[default]
+    Sky,         // white to blue gradient, from the bottom to the top
+    Color(Vec3), // the same color in all directions; black for scenes only lit by their lights
+}
+
+impl Background {
+    // "sky", or the components of a color separated by commas (e.g. "0,0,0")
+    pub fn from_name(name: &str) -> Option<Background> {
+        if name == "sky" {
+            return Some(Background::Sky);
+        }
+        let c = name.split(',').map(|v| v.trim().parse::<f32>().ok().filter(|v| *v >= 0.0)).collect::<Option<Vec<f32>>>()?;
+        match c.as_slice() {
+            [r, g, b] => Some(Background::Color(Vec3::new(*r, *g, *b))),
+            _ => None,
+        }
+    }
+
+    pub fn radiance(self, ray: Ray) -> Vec3 {
+        match self {
+            Background::Sky => {
+                let unit_dir = unit_vector(ray.direction);
+                let t = 0.5 * (unit_dir.y + 1.0);
+                Vec3::new(1.0,1.0,1.0)*(1.0-t) + Vec3::new(0.5,0.7,1.0)*t
+            }
+            Background::Color(c) => c,
+        }
+    }
+}
+
+// power heuristic for multiple importance sampling, with exponent 2
+fn mis_weight(pdf: f32, other_pdf: f32) -> f32 {
+    let (p2, o2) = (pdf*pdf, other_pdf*other_pdf);
+    if p2 + o2 > 0.0 { p2 / (p2 + o2) } else { 0.0 }
+}
+
+// emitted radiance at the hit point. `scattered_from` is the point and pdf of the previous non specular scattering
+// event: the light could also have been reached by sampling it directly, so the emission is weighted accordingly
+fn emission(ray: Ray, hit: &HitRecord, lights: &[SphereLight], scattered_from: Option<(Vec3, f32)>) -> Vec3 {
+    let emitted = hit.material.emitted(hit);
+    match scattered_from {
+        Some((from, pdf)) => emitted * mis_weight(pdf, lights_pdf(lights, from, ray.direction)),
+        None => emitted,
+    }
+}
+
+// light reaching a hit point from one of the lights, and the reflectance of the material towards it
+struct LightSample {
+    direction: Vec3,
+    reflectance: Vec3,
+    light: Vec3, // incoming radiance, weighted by the pdf and by the multiple importance sampling
+}
+
+impl LightSample {
+    fn radiance(&self) -> Vec3 {
+        mul_component(self.reflectance, self.light)
+    }
+}
+
+// direct lighting from a light picked at random, weighted against the chance of the material sampling the same direction
+fn sample_light(ray: Ray, hit: &HitRecord, world: &dyn Hitable, lights: &[SphereLight]) -> Option<LightSample> {
+    if lights.is_empty() {
+        return None;
+    }
+    let index = ((random_f32() * lights.len() as f32) as usize).min(lights.len()-1);
+    let (dir, pdf) = lights[index].sample(hit.point)?;
+    let (f, material_pdf) = hit.material.eval(ray, hit, dir);
+    if f.x <= 0.0 && f.y <= 0.0 && f.z <= 0.0 {
+        return None;
+    }
+    // same as lights_pdf, but the sampled light uses the pdf of the sample, that can't be zero because of rounding
+    let others: f32 = lights.iter().enumerate().filter(|&(i, _)| i != index).map(|(_, l)| l.pdf(hit.point, dir)).sum();
+    let light_pdf = (pdf + others) / lights.len() as f32;
+    // shadow ray: whatever it hits first contributes its emission
+    let light_hit = world.hit(&Ray::new(hit.point, dir), MIN_DIST, f32::MAX)?;
+    let light = light_hit.material.emitted(&light_hit) * (mis_weight(light_pdf, material_pdf) / light_pdf);
+    Some(LightSample { direction: dir, reflectance: f, light })
+}
+
+// /////////// //
+// Path tracer //
+// /////////// //
+// unidirectional path tracer. At every non specular hit one of the lights is sampled directly (next event estimation),
+// and the contribution is combined with the light found by the scattered ray through multiple importance sampling.
+// After `rr_depth` bounces, paths carrying little energy are terminated at random (russian roulette)
+pub struct PathTracer {
+    max_depth: u32,
+    rr_depth: u32,
+    background: Background,
+}
+
+impl PathTracer {
+    pub fn new(max_depth: u32, rr_depth: u32) -> PathTracer {
+        PathTracer { max_depth, rr_depth, background: Background::Sky }
+    }
+
+    pub fn with_background(self, background: Background) -> PathTracer {
+        PathTracer { background, ..self }
+    }
+
+    // follows the path, splitting the light found along it in the render passes if given
+    fn trace(&self, ray: Ray, world: &dyn Hitable, lights: &[SphereLight], mut aovs: Option<&mut AovSample>) -> Vec3 {
+        let mut ray = ray;
+        let mut radiance = Vec3::new(0.0,0.0,0.0);
+        let mut throughput = Vec3::new(1.0,1.0,1.0); // product of the scattering colors along the path
+        let mut scattered_from = None;
+        let mut depth = 0;
+        // for the passes: fraction of the scattering color of the first hit due to its diffuse lobe
+        let mut diffuse_fraction = Vec3::new(0.0,0.0,0.0);
+        loop {
+            let Some(hitrecord) = world.hit(&ray, MIN_DIST, MAX_DIST) else {
+                let background = mul_component(throughput, self.background.radiance(ray));
+                if let Some(aovs) = aovs {
+                    aovs.add_light(depth, background, diffuse_fraction);
+                }
+                return radiance + background;
+            };
+            if depth == 0 {
+                if let Some(aovs) = aovs.as_deref_mut() {
+                    aovs.set_first_hit(ray, &hitrecord);
+                }
+            }
+            if depth >= self.max_depth {
+                return radiance;
+            }
+            let emitted = mul_component(throughput, emission(ray, &hitrecord, lights, scattered_from));
+            if let Some(aovs) = aovs.as_deref_mut() {
+                aovs.add_light(depth, emitted, diffuse_fraction);
+            }
+            radiance = radiance + emitted;
+            let Some(scatter) = hitrecord.material.scatter(ray, &hitrecord) else { return radiance };
+            scattered_from = None;
+            if let Some(pdf) = scatter.pdf {
+                if let Some(sample) = sample_light(ray, &hitrecord, world, lights) {
+                    let direct = mul_component(throughput, sample.radiance());
+                    match aovs.as_deref_mut() {
+                        Some(aovs) if depth == 0 => {
+                            let diffuse = mul_component(hitrecord.material.eval_diffuse(ray, &hitrecord, sample.direction), sample.light);
+                            aovs.diffuse_direct = aovs.diffuse_direct + diffuse;
+                            aovs.specular = aovs.specular + (direct - diffuse);
+                        }
+                        Some(aovs) => aovs.add_light(depth+1, direct, diffuse_fraction),
+                        None => (),
+                    }
+                    radiance = radiance + direct;
+                }
+                scattered_from = Some((hitrecord.point, pdf));
+            }
+            if depth == 0 && aovs.is_some() {
+                if let Some(pdf) = scatter.pdf.filter(|&pdf| pdf > 0.0) {
+                    let diffuse = hitrecord.material.eval_diffuse(ray, &hitrecord, scatter.ray.direction) / pdf;
+                    let fraction = |d: f32, c: f32| if c > 0.0 { (d/c).clamp(0.0, 1.0) } else { 0.0 };
+                    diffuse_fraction = Vec3::new(fraction(diffuse.x, scatter.color.x), fraction(diffuse.y, scatter.color.y),
+                                                 fraction(diffuse.z, scatter.color.z));
+                }
+            }
+            throughput = mul_component(throughput, scatter.color);
+            depth += 1;
+            // russian roulette: continue with a probability proportional to the throughput, and compensate the survivors
+            if depth >= self.rr_depth {
+                let survival = throughput.x.max(throughput.y).max(throughput.z).min(0.95);
+                if random_f32() >= survival {
+                    return radiance;
+                }
+                throughput = throughput / survival;
+            }
+            ray = scatter.ray;
+        }
+    }
+}
+
+impl Integrator for PathTracer {
+    fn radiance(&self, ray: Ray, world: &dyn Hitable, lights: &[SphereLight]) -> Vec3 {
+        self.trace(ray, world, lights, None)
+    }
+
+    fn radiance_aovs(&self, ray: Ray, world: &dyn Hitable, lights: &[SphereLight], aovs: &mut AovSample) -> Vec3 {
+        self.trace(ray, world, lights, Some(aovs))
+    }
+}
+
+// //////////////////// //
+// Whitted-style tracer //
+// //////////////////// //
+// follows perfect reflections and refractions recursively, but only computes direct lighting (lights and background)
+// at the other surfaces, without indirect diffuse bounces
+pub struct Whitted {
+    max_depth: u32,
+    background: Background,
+}
+
+impl Whitted {
+    pub fn new(max_depth: u32) -> Whitted {
+        Whitted { max_depth, background: Background::Sky }
+    }
+
+    pub fn with_background(self, background: Background) -> Whitted {
+        Whitted { background, ..self }
+    }
+
+    fn trace(&self, ray: Ray, world: &dyn Hitable, lights: &[SphereLight], depth: u32) -> Vec3 {
+        let Some(hitrecord) = world.hit(&ray, MIN_DIST, MAX_DIST) else { return self.background.radiance(ray) };
+        if depth >= self.max_depth {
+            return Vec3::new(0.0,0.0,0.0);
+        }
+        let emitted = hitrecord.material.emitted(&hitrecord);
+        match hitrecord.material.scatter(ray, &hitrecord) {
+            None => emitted,
+            Some(scatter) => match scatter.pdf {
+                // specular: keep following the ray
+                None => emitted + mul_component(scatter.color, self.trace(scatter.ray, world, lights, depth+1)),
+                // direct lighting only: the scattered ray contributes what it hits without bouncing further
+                Some(pdf) => {
+                    let direct = sample_light(ray, &hitrecord, world, lights).map_or(Vec3::new(0.0,0.0,0.0), |s| s.radiance());
+                    let indirect = match world.hit(&scatter.ray, MIN_DIST, MAX_DIST) {
+                        Some(hit) => emission(scatter.ray, &hit, lights, Some((hitrecord.point, pdf))),
+                        None => self.background.radiance(scatter.ray),
+                    };
+                    emitted + direct + mul_component(scatter.color, indirect)
+                }
+            },
+        }
+    }
+}
+
+impl Integrator for Whitted {
+    fn radiance(&self, ray: Ray, world: &dyn Hitable, lights: &[SphereLight]) -> Vec3 {
+        self.trace(ray, world, lights, 0)
+    }
+}
+
+// ///////////////// //
+// Ambient occlusion //
+// ///////////////// //
+// cosine weighted fraction of the hemisphere around the first hit that is not occluded within the given distance.
+// Rays missing the scene are white
+pub struct AmbientOcclusion {
+    distance: f32,
+}
+
+impl AmbientOcclusion {
+    pub fn new(distance: f32) -> AmbientOcclusion {
+        AmbientOcclusion { distance }
+    }
+}
+
+impl Integrator for AmbientOcclusion {
+    fn radiance(&self, ray: Ray, world: &dyn Hitable, _lights: &[SphereLight]) -> Vec3 {
+        let Some(hit) = world.hit(&ray, MIN_DIST, MAX_DIST) else { return Vec3::new(1.0,1.0,1.0) };
+        let normal = if dot(ray.direction, hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        let dir = normal + random_unit_vector();
+        if dir.squared_length() < 1e-8 {
+            return Vec3::new(1.0,1.0,1.0);
+        }
+        // grazing rays easily hit the surface they start from (e.g. large spheres used as floors): move the origin off it
+        let origin = hit.point + normal*(1e-3*hit.point.length().max(1.0));
+        match world.hit(&Ray::new(origin, unit_vector(dir)), MIN_DIST, self.distance) {
+            Some(_) => Vec3::new(0.0,0.0,0.0),
+            None => Vec3::new(1.0,1.0,1.0),
+        }
+    }
+}
+
+// ////////////////////// //
+// Debug visualizations   //
+// ////////////////////// //
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum DebugMode {
+    Normal,   // shading normal at the first hit, mapped to [0,1]
+    Depth,    // distance of the first hit
+    Albedo,   // base color of the material at the first hit
+    Uv,       // surface coordinates at the first hit, in the red and green channels
+    ObjectId, // a different color for every object
+    Bounces,  // number of surfaces hit by the path before leaving the scene or being absorbed
+}
+
+impl DebugMode {
+    pub fn from_name(name: &str) -> Option<DebugMode> {
+        match name {
+            "normal" => Some(DebugMode::Normal),
+            "depth" => Some(DebugMode::Depth),
+            "albedo" => Some(DebugMode::Albedo),
+            "uv" => Some(DebugMode::Uv),
+            "id" => Some(DebugMode::ObjectId),
+            "bounces" => Some(DebugMode::Bounces),
+            _ => None,
+        }
+    }
+}
+
+// blue-green-red color ramp, for values in [0,1]
+fn false_color(v: f32) -> Vec3 {
+    let v = v.clamp(0.0, 1.0);
+    let channel = |center: f32| (1.5 - (4.0*v - center).abs()).clamp(0.0, 1.0);
+    Vec3::new(channel(3.0), channel(2.0), channel(1.0))
+}
+
+// pseudo random color from an integer id
+fn id_color(id: usize) -> Vec3 {
+    let h = (id as u32).wrapping_add(1).wrapping_mul(2654435761);
+    Vec3::new(((h >> 24) & 255) as f32, ((h >> 16) & 255) as f32, ((h >> 8) & 255) as f32) / 255.0
+}
+
+// false color images to inspect the scene, instead of rendering it. Misses are black
+pub struct DebugIntegrator {
+    mode: DebugMode,
+    view_distance: f32, // distances are mapped so that this one is in the middle of the color ramp
+    max_depth: u32,
+}
+
+impl DebugIntegrator {
+    pub fn new(mode: DebugMode, view_distance: f32, max_depth: u32) -> DebugIntegrator {
+        DebugIntegrator { mode, view_distance, max_depth }
+    }
+
+    fn bounces(&self, ray: Ray, world: &dyn Hitable) -> u32 {
+        let mut ray = ray;
+        let mut count = 0;
+        while let Some(hit) = world.hit(&ray, MIN_DIST, MAX_DIST) {
+            count += 1;
+            match hit.material.scatter(ray, &hit) {
+                Some(scatter) if count < self.max_depth => ray = scatter.ray,
+                _ => break,
+            }
+        }
+        count
+    }
+}
+
+impl Integrator for DebugIntegrator {
+    fn radiance(&self, ray: Ray, world: &dyn Hitable, _lights: &[SphereLight]) -> Vec3 {
+        if self.mode == DebugMode::Bounces {
+            // up to 16 bounces are distinguishable
+            return false_color(self.bounces(ray, world) as f32 / 16.0);
+        }
+        let Some(hit) = world.hit(&ray, MIN_DIST, MAX_DIST) else { return Vec3::new(0.0,0.0,0.0) };
+        match self.mode {
+            DebugMode::Normal => (unit_vector(hit.normal) + Vec3::new(1.0,1.0,1.0)) * 0.5,
+            DebugMode::Depth => {
+                let distance = hit.t * ray.direction.length();
+                false_color(distance / (distance + self.view_distance))
+            }
+            DebugMode::Albedo => hit.material.albedo(&hit),
+            DebugMode::Uv => Vec3::new(hit.u, hit.v, 0.0),
+            DebugMode::ObjectId => id_color(hit.object_id),
+            DebugMode::Bounces => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pt_math::seed_rng;
+    use crate::objects::{Sphere, HitableList};
+    use crate::material::{Lambertian, Metal, DiffuseLight, Principled, PrincipledParams};
+
+    fn approx_eq(a: f32, b: f32, epsilon: f32) -> bool {
+        (a - b).abs() < epsilon
+    }
+
+    #[test]
+    fn path_miss_returns_sky() {
+        let world = HitableList::new(); // Empty world
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        
+        let col = PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH).radiance(ray, &world, &[]);
+        
+        // Should return sky gradient (blue-white)
+        assert!(col.x >= 0.5 && col.x <= 1.0);
+        assert!(col.y >= 0.7 && col.y <= 1.0);
+        assert!(col.z >= 1.0);
+    }
+
+    #[test]
+    fn path_sky_gradient() {
+        let world = HitableList::new();
+        
+        // Ray pointing straight up should give bluer color
+        let ray_up = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let col_up = PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH).radiance(ray_up, &world, &[]);
+        
+        // Ray pointing down should give whiter color
+        let ray_down = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let col_down = PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH).radiance(ray_down, &world, &[]);
+        
+        // The gradient blends between white (1,1,1) at bottom and blue (0.5,0.7,1.0) at top
+        // So rays pointing down should have higher overall brightness
+        let brightness_up = col_up.x + col_up.y + col_up.z;
+        let brightness_down = col_down.x + col_down.y + col_down.z;
+        
+        // Down ray should be brighter (more white)
+        assert!(brightness_down > brightness_up);
+    }
+
+    #[test]
+    fn path_background_color() {
+        let mut world = HitableList::new();
+        world.add(Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        let black = PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH).with_background(Background::Color(Vec3::new(0.0, 0.0, 0.0)));
+        // without lights, a black background leaves the scene dark
+        for (x, y) in [(0.0, 1.0), (0.0, -1.0), (0.3, -0.5)] {
+            let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(x, y, -1.0));
+            assert_eq!(black.radiance(ray, &world, &[]).length(), 0.0);
+        }
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let red = Whitted::new(DEFAULT_MAX_DEPTH).with_background(Background::Color(Vec3::new(1.0, 0.0, 0.0)));
+        let col = red.radiance(ray, &world, &[]);
+        assert_eq!((col.x, col.y, col.z), (1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn background_names() {
+        assert!(matches!(Background::from_name("sky"), Some(Background::Sky)));
+        match Background::from_name("0.1, 0.2,0.3") {
+            Some(Background::Color(c)) => assert_eq!((c.x, c.y, c.z), (0.1, 0.2, 0.3)),
+            _ => panic!("expected a color"),
+        }
+        assert!(Background::from_name("0,0").is_none());
+        assert!(Background::from_name("-1,0,0").is_none());
+        assert!(Background::from_name("black").is_none());
+    }
+
+    #[test]
+    fn path_hit_returns_material_color() {
+        let mut world = HitableList::new();
+        world.add(Sphere::new(
+            Vec3::new(0.0, 0.0, -1.0),
+            0.5,
+            Box::new(Lambertian::new(Vec3::new(0.8, 0.3, 0.3)))
+        ));
+        
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let col = PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH).radiance(ray, &world, &[]);
+        
+        // Should return some color (not black, not pure sky)
+        assert!(col.x > 0.0 || col.y > 0.0 || col.z > 0.0);
+    }
+
+    #[test]
+    fn path_hit_light() {
+        let mut world = HitableList::new();
+        world.add(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, Box::new(DiffuseLight::new(Vec3::new(3.0, 2.0, 1.0)))));
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let col = PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH).radiance(ray, &world, &[]);
+        assert_eq!((col.x, col.y, col.z), (3.0, 2.0, 1.0));
+    }
+
+    #[test]
+    fn path_direct_lighting() {
+        // small light above a diffuse floor: the light reflected by the floor is albedo*L*r^2/d^2, plus the sky
+        let floor = |world: &mut HitableList| world.add(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0,
+                                                                    Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        let mut world = HitableList::new();
+        floor(&mut world);
+        world.add(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 0.1, Box::new(DiffuseLight::new(Vec3::new(100.0, 100.0, 100.0)))));
+        let mut sky = HitableList::new();
+        floor(&mut sky);
+        let lights = world.lights();
+        assert_eq!(lights.len(), 1);
+
+        let ray = Ray::new(Vec3::new(0.0, 0.5, 0.5), Vec3::new(0.0, -0.5, -0.5));
+        let average = |world: &HitableList, lights: &[SphereLight], samples: u64| {
+            let mut sum = 0.0;
+            for s in 0..samples {
+                seed_rng(5, 0, s);
+                sum += PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH).radiance(ray, world, lights).y;
+            }
+            sum / samples as f32
+        };
+        let sky_only = average(&sky, &[], 20000);
+        let direct = average(&world, &lights, 20000) - sky_only;
+        assert!(approx_eq(direct, 0.5, 0.03), "{}", direct);
+        // without light sampling the result is the same, but much noisier
+        let unsampled = average(&world, &[], 20000) - sky_only;
+        assert!(approx_eq(unsampled, 0.5, 0.2), "{}", unsampled);
+    }
+
+    #[test]
+    fn path_light_passes() {
+        // diffuse floor, a principled sphere with both diffuse and specular lobes, a mirror and a light
+        let mut world = HitableList::new();
+        world.add(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        world.add(Sphere::new(Vec3::new(0.0, 0.5, 0.0), 0.5, Box::new(Principled::new(PrincipledParams::default()))));
+        world.add(Sphere::new(Vec3::new(1.2, 0.5, 0.0), 0.5, Box::new(Metal::new(Vec3::new(0.9, 0.9, 0.9), 0.0))));
+        world.add(Sphere::new(Vec3::new(0.0, 2.0, 1.0), 0.2, Box::new(DiffuseLight::new(Vec3::new(20.0, 20.0, 20.0)))));
+        let lights = world.lights();
+        let integrator = PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH);
+        let passes = |aovs: &AovSample| aovs.emission + aovs.diffuse_direct + aovs.diffuse_indirect + aovs.specular;
+        let (mut diffuse, mut specular) = (0.0, 0.0);
+        for s in 0..2000 {
+            seed_rng(11, 1, s);
+            let ray = Ray::new(Vec3::new(0.0, 1.0, 4.0), Vec3::new(0.4*random_f32() - 0.2, -0.1 - 0.4*random_f32(), -1.0));
+            // the same path is followed with and without the passes, and they add up to the radiance
+            seed_rng(11, 0, s);
+            let radiance = integrator.radiance(ray, &world, &lights);
+            seed_rng(11, 0, s);
+            let mut aovs = AovSample::default();
+            assert_eq!(integrator.radiance_aovs(ray, &world, &lights, &mut aovs).y, radiance.y);
+            assert!(approx_eq(passes(&aovs).y, radiance.y, 1e-3*radiance.y.max(1.0)), "{:?} {:?}", aovs, radiance);
+            assert!(aovs.object_id > 0 && aovs.depth.is_finite());
+            // the mirror has no diffuse lobe
+            if aovs.object_id == 3 {
+                assert_eq!(aovs.diffuse_direct.y + aovs.diffuse_indirect.y, 0.0);
+            }
+            diffuse += aovs.diffuse_direct.y + aovs.diffuse_indirect.y;
+            specular += aovs.specular.y;
+        }
+        assert!(diffuse > 0.0 && specular > 0.0);
+        // the background seen directly is emission
+        let mut aovs = AovSample::default();
+        let sky = integrator.radiance_aovs(Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0)), &world, &[], &mut aovs);
+        assert_eq!((aovs.emission.z, aovs.object_id, aovs.depth), (sky.z, 0, f32::INFINITY));
+    }
+
+    #[test]
+    fn path_sky_light_is_direct() {
+        // a single sphere lit only by the sky: the scattered rays can't hit it again, so all the light is direct
+        let mut world = HitableList::new();
+        world.add(Sphere::new(Vec3::new(0.0, 0.0, -2.0), 0.5, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        let integrator = PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH);
+        let mut direct = 0.0;
+        for s in 0..100 {
+            seed_rng(3, 0, s);
+            let mut aovs = AovSample::default();
+            let radiance = integrator.radiance_aovs(Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), &world, &[], &mut aovs);
+            assert_eq!(aovs.diffuse_indirect.length(), 0.0);
+            assert!(approx_eq(aovs.diffuse_direct.y, radiance.y, 1e-5));
+            direct += aovs.diffuse_direct.y;
+        }
+        assert!(direct > 0.0);
+    }
+
+    #[test]
+    fn first_hit_passes() {
+        // the other integrators only fill the properties of the first hit
+        let world = debug_world();
+        let mut aovs = AovSample::default();
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        AmbientOcclusion::new(1.0).radiance_aovs(ray, &world, &[], &mut aovs);
+        assert_eq!((aovs.object_id, aovs.depth, aovs.normal.z), (1, 1.0, 1.0));
+        assert_eq!((aovs.albedo.x, aovs.emission.x), (0.8, 0.0));
+    }
+
+    #[test]
+    fn path_russian_roulette_is_unbiased() {
+        // two diffuse spheres facing each other under the sky: light bounces between them several times
+        let mut world = HitableList::new();
+        world.add(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Box::new(Lambertian::new(Vec3::new(0.7, 0.7, 0.7)))));
+        world.add(Sphere::new(Vec3::new(0.0, 1.5, 0.0), 1.0, Box::new(Lambertian::new(Vec3::new(0.7, 0.7, 0.7)))));
+        let ray = Ray::new(Vec3::new(1.0, 0.2, 1.0), Vec3::new(-1.0, -0.2, -1.0));
+        let average = |integrator: &PathTracer| {
+            let mut sum = 0.0;
+            for s in 0..20000 {
+                seed_rng(7, 0, s);
+                sum += integrator.radiance(ray, &world, &[]).y;
+            }
+            sum / 20000.0
+        };
+        let exact = average(&PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_MAX_DEPTH));
+        let roulette = average(&PathTracer::new(DEFAULT_MAX_DEPTH, 0));
+        assert!(approx_eq(exact, roulette, 0.02), "{} {}", exact, roulette);
+    }
+
+    #[test]
+    fn mis_weights() {
+        assert_eq!(mis_weight(1.0, 0.0), 1.0);
+        assert_eq!(mis_weight(0.0, 1.0), 0.0);
+        assert_eq!(mis_weight(0.0, 0.0), 0.0);
+        assert!(approx_eq(mis_weight(1.0, 2.0) + mis_weight(2.0, 1.0), 1.0, 1e-6));
+    }
+
+    #[test]
+    fn path_max_depth() {
+        let mut world = HitableList::new();
+        world.add(Sphere::new(
+            Vec3::new(0.0, 0.0, -1.0),
+            0.5,
+            Box::new(Lambertian::new(Vec3::new(1.0, 1.0, 1.0)))
+        ));
+        
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        
+        // Past max depth, should return black
+        let col = PathTracer::new(0, DEFAULT_RR_DEPTH).radiance(ray, &world, &[]);
+        
+        assert_eq!(col.x, 0.0);
+        assert_eq!(col.y, 0.0);
+        assert_eq!(col.z, 0.0);
+    }
+
+    #[test]
+    fn whitted_follows_mirrors() {
+        // a mirror in front of a light: the light is seen through the mirror
+        let mut world = HitableList::new();
+        world.add(Sphere::new(Vec3::new(0.0, 0.0, -1002.0), 1000.0, Box::new(Metal::new(Vec3::new(0.5, 0.5, 0.5), 0.0))));
+        world.add(Sphere::new(Vec3::new(0.0, 0.0, 2.0), 0.5, Box::new(DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0)))));
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let col = Whitted::new(DEFAULT_MAX_DEPTH).radiance(ray, &world, &world.lights());
+        assert!(approx_eq(col.x, 2.0, 1e-4));
+    }
+
+    #[test]
+    fn whitted_matches_path_tracer_for_direct_light() {
+        // a diffuse floor only reflects light once towards the sky, so both integrators converge to the same value
+        let mut world = HitableList::new();
+        world.add(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        world.add(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 0.1, Box::new(DiffuseLight::new(Vec3::new(100.0, 100.0, 100.0)))));
+        let lights = world.lights();
+        let ray = Ray::new(Vec3::new(0.0, 0.5, 0.5), Vec3::new(0.0, -0.5, -0.5));
+        let average = |integrator: &dyn Integrator| {
+            let mut sum = 0.0;
+            for s in 0..20000 {
+                seed_rng(3, 0, s);
+                sum += integrator.radiance(ray, &world, &lights).y;
+            }
+            sum / 20000.0
+        };
+        assert!(approx_eq(average(&Whitted::new(DEFAULT_MAX_DEPTH)), average(&PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH)), 0.03));
+    }
+
+    #[test]
+    fn ambient_occlusion() {
+        let mut world = HitableList::new();
+        world.add(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        let ao = AmbientOcclusion::new(1.0);
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        // nothing around the floor
+        for s in 0..100 {
+            seed_rng(1, 0, s);
+            assert_eq!(ao.radiance(ray, &world, &[]).x, 1.0);
+        }
+        // a large sphere resting on the floor occludes part of the hemisphere
+        world.add(Sphere::new(Vec3::new(0.5, 0.5, 0.0), 0.5, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        let ray = Ray::new(Vec3::new(-0.1, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let visible: f32 = (0..1000).map(|s| { seed_rng(1, 0, s); ao.radiance(ray, &world, &[]).x }).sum::<f32>() / 1000.0;
+        assert!(visible > 0.3 && visible < 0.9, "{}", visible);
+        // misses are white
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(ao.radiance(ray, &world, &[]).x, 1.0);
+    }
+
+    fn debug_world() -> HitableList {
+        let mut world = HitableList::new();
+        world.add(Sphere::new(Vec3::new(0.0, 0.0, -2.0), 1.0, Box::new(Lambertian::new(Vec3::new(0.8, 0.4, 0.2)))));
+        world.add(Sphere::new(Vec3::new(5.0, 0.0, -2.0), 1.0, Box::new(Metal::new(Vec3::new(0.9, 0.9, 0.9), 0.0))));
+        world
+    }
+
+    #[test]
+    fn debug_first_hit_modes() {
+        let world = debug_world();
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let radiance = |mode| DebugIntegrator::new(mode, 1.0, DEFAULT_MAX_DEPTH).radiance(ray, &world, &[]);
+        let n = radiance(DebugMode::Normal);
+        assert!(approx_eq(n.x, 0.5, 1e-5) && approx_eq(n.y, 0.5, 1e-5) && approx_eq(n.z, 1.0, 1e-5));
+        let a = radiance(DebugMode::Albedo);
+        assert_eq!((a.x, a.y, a.z), (0.8, 0.4, 0.2));
+        let uv = radiance(DebugMode::Uv);
+        assert!(approx_eq(uv.x, 0.25, 1e-5) && approx_eq(uv.y, 0.5, 1e-5));
+        // the hit is at the view distance: middle of the ramp
+        let d = radiance(DebugMode::Depth);
+        assert!(approx_eq(d.y, 1.0, 1e-5) && approx_eq(d.x, d.z, 1e-5));
+        // objects have different colors
+        let other = Ray::new(Vec3::new(5.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let id0 = radiance(DebugMode::ObjectId);
+        let id1 = DebugIntegrator::new(DebugMode::ObjectId, 1.0, DEFAULT_MAX_DEPTH).radiance(other, &world, &[]);
+        assert!(id0.x != id1.x || id0.y != id1.y || id0.z != id1.z);
+        // misses are black
+        let miss = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(DebugIntegrator::new(DebugMode::Normal, 1.0, DEFAULT_MAX_DEPTH).radiance(miss, &world, &[]).y, 0.0);
+    }
+
+    #[test]
+    fn debug_bounces() {
+        let mut world = debug_world();
+        let integrator = DebugIntegrator::new(DebugMode::Bounces, 1.0, DEFAULT_MAX_DEPTH);
+        assert_eq!(integrator.bounces(Ray::new(Vec3::new(0.0, 5.0, 0.0), Vec3::new(0.0, 1.0, 0.0)), &world), 0);
+        // the mirror reflects the ray back to the sky
+        assert_eq!(integrator.bounces(Ray::new(Vec3::new(5.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)), &world), 1);
+        // between two parallel mirrors, the path is cut at the maximum depth
+        world.add(Sphere::new(Vec3::new(0.0, 10.0, 1010.0), 1000.0, Box::new(Metal::new(Vec3::new(0.9, 0.9, 0.9), 0.0))));
+        world.add(Sphere::new(Vec3::new(0.0, 10.0, -1020.0), 1000.0, Box::new(Metal::new(Vec3::new(0.9, 0.9, 0.9), 0.0))));
+        assert_eq!(integrator.bounces(Ray::new(Vec3::new(0.0, 10.0, 0.0), Vec3::new(0.0, 0.0, 1.0)), &world), 50);
+    }
+
+    #[test]
+    fn false_colors() {
+        let low = false_color(0.0);
+        let high = false_color(1.0);
+        assert!(low.z > 0.0 && low.x == 0.0);
+        assert!(high.x > 0.0 && high.z == 0.0);
+        assert_eq!(DebugMode::from_name("id"), Some(DebugMode::ObjectId));
+        assert_eq!(DebugMode::from_name("color"), None);
+    }
+
+    #[test]
+    fn integrator_by_name() {
+        assert!(create_integrator("path", 1.0, 50, 3, Background::Sky).is_some());
+        assert!(create_integrator("whitted", 1.0, 50, 3, Background::Sky).is_some());
+        assert!(create_integrator("ao", 1.0, 50, 3, Background::Sky).is_some());
+        assert!(create_integrator("photon", 1.0, 50, 3, Background::Sky).is_none());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::pt_math::{Vec3, dot, unit_vector, orthonormal_basis, random_f32};
+
+// spherical light source, sampled uniformly in the cone of directions it subtends from the shaded point.
+// The emitted radiance is not stored here: it comes from the material of the surface hit by the shadow ray
+#[derive(Clone,Copy,Debug)]
+pub struct SphereLight {
+    center: Vec3,
+    radius: f32,
+}
+
+impl SphereLight {
+    pub fn new(center: Vec3, radius: f32) -> SphereLight {
+        SphereLight { center, radius: radius.abs() }
+    }
+
+    // cosine of the half-angle of the cone, and the cone solid angle divided by 2pi.
+    // None if the point is inside the sphere
+    fn cone(&self, from: Vec3) -> Option<(f32, f32)> {
+        let dist2 = (self.center - from).squared_length();
+        let radius2 = self.radius*self.radius;
+        if dist2 <= radius2 {
+            return None;
+        }
+        let sin2_max = radius2 / dist2;
+        let cos_max = (1.0 - sin2_max).max(0.0).sqrt();
+        // 1-cos computed as sin^2/(1+cos), that stays accurate for small and distant lights
+        Some((cos_max, sin2_max / (1.0 + cos_max)))
+    }
+
+    // sample a direction (normalized) towards the light, and its pdf with respect to solid angle
+    pub fn sample(&self, from: Vec3) -> Option<(Vec3, f32)> {
+        let (cos_max, one_minus_cos_max) = self.cone(from)?;
+        let w = unit_vector(self.center - from);
+        let (u, v) = orthonormal_basis(w);
+        let cos_theta = 1.0 - random_f32()*one_minus_cos_max;
+        let sin_theta = (1.0 - cos_theta*cos_theta).max(0.0).sqrt();
+        let phi = 2.0*std::f32::consts::PI*random_f32();
+        let dir = u*(sin_theta*phi.cos()) + v*(sin_theta*phi.sin()) + w*cos_theta.max(cos_max);
+        Some((dir, 1.0 / (2.0*std::f32::consts::PI*one_minus_cos_max)))
+    }
+
+    // pdf of sampling the given direction with `sample`; zero if the direction misses the light
+    pub fn pdf(&self, from: Vec3, dir: Vec3) -> f32 {
+        match self.cone(from) {
+            Some((cos_max, one_minus_cos_max)) if dot(unit_vector(dir), unit_vector(self.center - from)) >= cos_max => {
+                1.0 / (2.0*std::f32::consts::PI*one_minus_cos_max)
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+// pdf of sampling a direction when one of the lights is picked at random and then sampled
+pub fn lights_pdf(lights: &[SphereLight], from: Vec3, dir: Vec3) -> f32 {
+    if lights.is_empty() {
+        return 0.0;
+    }
+    lights.iter().map(|l| l.pdf(from, dir)).sum::<f32>() / lights.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pt_math::seed_rng;
+
+    fn approx_eq(a: f32, b: f32, epsilon: f32) -> bool {
+        (a - b).abs() < epsilon
+    }
+
+    #[test]
+    fn sphere_light_sample_hits_light() {
+        seed_rng(1, 0, 0);
+        let light = SphereLight::new(Vec3::new(1.0, 4.0, -2.0), 0.5);
+        let from = Vec3::new(0.0, 0.0, 0.0);
+        for _ in 0..1000 {
+            let (dir, pdf) = light.sample(from).unwrap();
+            assert!(approx_eq(dir.length(), 1.0, 1e-4));
+            // the ray towards the sampled direction intersects the sphere
+            let oc = from - light.center;
+            let b = dot(oc, dir);
+            assert!(b*b - (oc.squared_length() - 0.25) >= -1e-4);
+            assert!(approx_eq(pdf, light.pdf(from, dir), 1e-3));
+        }
+        // directions missing the light have zero pdf
+        assert_eq!(light.pdf(from, Vec3::new(0.0, -1.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn sphere_light_pdf_matches_solid_angle() {
+        let light = SphereLight::new(Vec3::new(0.0, 0.0, -10.0), 1.0);
+        let from = Vec3::new(0.0, 0.0, 0.0);
+        // solid angle of the cone: 2pi(1-cos_max)
+        let cos_max = (1.0f32 - 0.01).sqrt();
+        let pdf = light.pdf(from, Vec3::new(0.0, 0.0, -1.0));
+        assert!(approx_eq(pdf, 1.0 / (2.0*std::f32::consts::PI*(1.0 - cos_max)), 1e-1));
+        // very small and distant lights don't lose precision
+        let tiny = SphereLight::new(Vec3::new(0.0, 0.0, -1000.0), 0.01);
+        let pdf = tiny.pdf(from, Vec3::new(0.0, 0.0, -1.0));
+        let solid_angle = std::f32::consts::PI*0.01*0.01/(1000.0*1000.0);
+        assert!(approx_eq(pdf*solid_angle, 1.0, 1e-3));
+    }
+
+    #[test]
+    fn sphere_light_inside() {
+        let light = SphereLight::new(Vec3::new(0.0, 0.0, 0.0), 1.0);
+        assert!(light.sample(Vec3::new(0.0, 0.5, 0.0)).is_none());
+        assert_eq!(light.pdf(Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, 1.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn lights_pdf_averages() {
+        let from = Vec3::new(0.0, 0.0, 0.0);
+        let a = SphereLight::new(Vec3::new(0.0, 0.0, -5.0), 1.0);
+        let b = SphereLight::new(Vec3::new(0.0, 5.0, 0.0), 1.0);
+        let dir = Vec3::new(0.0, 0.0, -1.0);
+        assert!(approx_eq(lights_pdf(&[a, b], from, dir), 0.5*a.pdf(from, dir), 1e-5));
+        assert_eq!(lights_pdf(&[], from, dir), 0.0);
+    }
+}
@@ -1,17 +1,40 @@
 mod pt_math;
 mod camera;
+mod microfacet;
+mod noise;
+mod texture;
 mod material;
 mod objects;
+mod light;
+mod bvh;
+mod mesh;
+mod obj_loader;
+mod ply_loader;
+mod gltf_loader;
+mod scene;
+mod integrator;
+mod aov;
 mod path_tracer;
+mod output;
+mod tonemap;
+mod color;
+#[cfg(feature = "preview")]
+mod preview;
 
-use pt_math::Vec3;
+use pt_math::{Vec3, Aabb, unit_vector};
 use camera::Camera;
-use path_tracer::{Image, render_step, create_book_scene};
+use bvh::Bvh;
+use path_tracer::{Image, render, create_book_scene};
+use integrator::{create_integrator, Background, DebugIntegrator, DebugMode, DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH};
+use objects::Hitable;
+use scene::{Scene, ImageSettings, load_scene};
+use output::{OutputFormat, PngDepth, ExrPrecision};
+use tonemap::{ToneMapping, Operator};
+use color::ColorSpace;
+use aov::Aov;
 
 extern crate getopts;
-use getopts::Options;
-extern crate minifb;
-use minifb::{Key, WindowOptions, Window};
+use getopts::{Options, Matches};
 extern crate indicatif;
 use indicatif::ProgressBar;
 
@@ -20,6 +43,26 @@ fn print_usage(program: &str, opts: Options) {
     print!("{}", opts.usage(&brief));
 }
 
+// value of a numeric option, if given. Exits with an error if it is not a valid number
+fn opt_number<T: std::str::FromStr>(matches: &Matches, name: &str, what: &str) -> Option<T> {
+    match matches.opt_get::<T>(name) {
+        Ok(value) => value,
+        Err(_) => { eprintln!("invalid {} '{}'", what, matches.opt_str(name).unwrap_or_default());
+                    std::process::exit(1);
+        }
+    }
+}
+
+// camera looking at the whole bounding box, for scenes that don't define their own camera.
+// Returns the camera and its distance from the center of the box
+fn framing_camera(bbox: &Aabb, vfov_deg: f32, aspect_ratio: f32) -> (Camera, f32) {
+    let center = bbox.centroid();
+    let radius = 0.5 * bbox.extent().length();
+    let distance = 1.1 * radius / (0.5 * vfov_deg.to_radians()).sin();
+    let lookfrom = center + distance * unit_vector(Vec3::new(1.0, 0.5, 1.5));
+    (Camera::new(lookfrom, center, Vec3::new(0.0,1.0,0.0), vfov_deg, aspect_ratio, 0.0, distance), distance)
+}
+
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
@@ -30,12 +73,32 @@ fn main() {
     opts.optopt("w", "width", "image width (default=640)", "");
     opts.optopt("h", "height", "image height (default=360)", "");
     opts.optopt("s", "samples", "number of samples (default=10)", "");
+    opts.optopt("t", "threads", "number of rendering threads (default=number of cores)", "");
+    opts.optopt("", "seed", "seed of the random number generator; the same seed gives the same image (default=0)", "");
+    opts.optopt("", "integrator", "rendering algorithm: path (default), whitted, ao", "NAME");
+    opts.optopt("", "max-depth", "maximum number of bounces of a path (default=50)", "");
+    opts.optopt("", "rr-depth", "number of bounces before russian roulette can terminate a path (default=3)", "");
+    opts.optopt("", "ao-distance", "maximum distance of the occluders for the ao integrator (default=1)", "");
+    opts.optopt("", "background", "color of the directions missing the scene: sky (default gradient) or r,g,b, e.g. 0,0,0", "COLOR");
+    opts.optopt("", "mode", "false color debug image instead of the rendering: normal, depth, albedo, uv, id, bounces", "NAME");
+    opts.optopt("", "tonemap", "tone mapping of the preview and of the png output: clamp (default), reinhard, extended, aces, hable", "NAME");
+    opts.optopt("", "exposure", "exposure correction in stops, before tone mapping (default=0)", "");
+    opts.optopt("", "white", "luminance mapped to white by the extended tone mapping (default=4)", "");
+    opts.optopt("", "color-space", "color space of the preview and of the png output: srgb (default), p3, rec2020", "NAME");
+    opts.optopt("", "png-depth", "bits per channel of the png output: 8 (default), 16", "BITS");
+    opts.optflag("", "alpha", "add an alpha channel with the coverage of the scene to the png or exr output, leaving the background transparent");
+    opts.optopt("", "exr", "channel type of the .exr output: half (default), float", "TYPE");
+    opts.optopt("", "aov", "render passes saved as layers of the .exr output, or as .exr files next to the other formats, \
+                            comma separated: emission, diffuse_direct, diffuse_indirect, specular, albedo, normal, depth, id, all", "NAMES");
+    opts.optopt("i", "input", "load the scene from a file (.toml, .obj, .ply, .gltf, .glb) instead of using the built-in one", "FILE");
+    opts.optflag("", "headless", "render without the preview window");
+    opts.optflag("", "no-preview", "same as --headless");
     opts.optflag("", "help", "print this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
-        Err(f) => { println!("{}", f.to_string());
-                    print_usage(&program, opts); 
-                    return;
+        Err(f) => { eprintln!("{}", f);
+                    print_usage(&program, opts);
+                    std::process::exit(1);
         }
     };
     if matches.opt_present("help") {
@@ -43,55 +106,208 @@ fn main() {
         return;
     }
     let output_filename = matches.opt_str("o").unwrap_or(String::from("image.png"));
-    let width = matches.opt_get_default::<u32>("w", 640).expect("invalid width");
-    let height = matches.opt_get_default::<u32>("h", 360).expect("invalid heigh");
-    let samples = matches.opt_get_default::<u32>("s", 10).expect("invalid number of samples");
+    // the output format is chosen from the file extension: png, or exr, pfm and hdr for linear radiance
+    let exr_precision = match matches.opt_str("exr") {
+        Some(name) => match ExrPrecision::from_name(&name) {
+            Some(precision) => precision,
+            None => { eprintln!("unknown exr channel type '{}'", name);
+                      std::process::exit(1);
+            }
+        },
+        None => ExrPrecision::Half,
+    };
+    let png_depth = match matches.opt_get_default::<u32>("png-depth", 8).ok().and_then(PngDepth::from_bits) {
+        Some(depth) => depth,
+        None => { eprintln!("invalid png depth (expected 8 or 16)");
+                  std::process::exit(1);
+        }
+    };
+    let output_path = std::path::Path::new(&output_filename);
+    let output_format = match OutputFormat::from_path(output_path, png_depth, exr_precision) {
+        Some(format) => format,
+        None => { eprintln!("unsupported output format '{}' (expected .png, .exr, .pfm or .hdr)", output_filename);
+                  std::process::exit(1);
+        }
+    };
+    let alpha = matches.opt_present("alpha");
+    if alpha && !output_format.has_alpha() {
+        eprintln!("the alpha channel needs a .png or .exr output");
+        std::process::exit(1);
+    }
+    let aovs = match matches.opt_str("aov") {
+        Some(names) if names == "all" => Aov::ALL.to_vec(),
+        Some(names) => {
+            let mut aovs = Vec::new();
+            for name in names.split(',') {
+                match Aov::from_name(name.trim()) {
+                    Some(aov) => if !aovs.contains(&aov) { aovs.push(aov) },
+                    None => { eprintln!("unknown render pass '{}'", name);
+                              std::process::exit(1);
+                    }
+                }
+            }
+            aovs
+        }
+        None => Vec::new(),
+    };
     println!("sample path tracing. Rendering scene...");
-    // create empty image
-    let mut image = Image::new(width, height);
-    // camera
-    let aperture = 0.051;
-    let lookfrom = Vec3::new(10.0, 1.8, 2.4);
-    let lookat = Vec3::new(0.0, 0.0, 0.5);
-    let up = Vec3::new(0.0,1.0,0.0); 
-    let dist_to_focus = (lookfrom-Vec3::new(4.0, 1.0, 0.0)).length();
-    let camera = Camera::new(lookfrom, lookat, up, 
-                             30.0, (width as f32)/(height as f32),
-                             aperture, dist_to_focus);
     // create scene
-    let world = create_book_scene();
-    // let world = create_test_scene();
-    // create window with live framebuffer 
-    let mut buffer: Vec<u32> = vec![0; (width * height) as usize];
-    let mut window = Window::new("Test - ESC to exit",
-                                 width as usize,
-                                 height as usize,
-                                 WindowOptions::default()).unwrap_or_else(|e| { panic!("{}", e); });
-    // progress bar
-    let bar = ProgressBar::new(samples as u64);
-    bar.set_style(indicatif::ProgressStyle::default_bar()
-                  .template("[{elapsed}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta} rem.)")
-                  .progress_chars("##-"));
-    // render image and update  window
-    for _s in 0..samples {
-        if window.is_open() && !window.is_key_down(Key::Escape) {
-            render_step(&world, &camera, &mut image);
-            // update framebuffer
-            for j in 0..image.height {
-                for i in 0..image.width {
-                    let (r,g,b) = image.val_rgb(i,j);
-                    let rgb: u32 = 0xff << 24 | (r as u32) << 16 | (g as u32) << 8 | b as u32;
-                    buffer[(i+j*image.width) as usize] = rgb;
+    let scene = match matches.opt_str("i") {
+        Some(filename) => match load_scene(std::path::Path::new(&filename)) {
+            Ok(scene) => scene,
+            Err(e) => { eprintln!("error loading the scene: {}", e);
+                        std::process::exit(1);
+            }
+        },
+        None => Scene { world: create_book_scene(), camera: None, image: ImageSettings::default() },
+        // None => Scene { world: create_test_scene(), camera: None, image: ImageSettings::default() },
+    };
+    // command line options override the settings of the scene file
+    let width = opt_number::<u32>(&matches, "w", "width").or(scene.image.width).unwrap_or(640);
+    let height = opt_number::<u32>(&matches, "h", "height").or(scene.image.height).unwrap_or(360);
+    let samples = opt_number::<u32>(&matches, "s", "number of samples").or(scene.image.samples).unwrap_or(10);
+    let threads = opt_number::<usize>(&matches, "t", "number of threads")
+                         .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let seed = opt_number::<u64>(&matches, "seed", "seed").unwrap_or(0);
+    let max_depth = opt_number::<u32>(&matches, "max-depth", "max depth").unwrap_or(DEFAULT_MAX_DEPTH);
+    let rr_depth = opt_number::<u32>(&matches, "rr-depth", "russian roulette depth").unwrap_or(DEFAULT_RR_DEPTH);
+    let ao_distance = opt_number::<f32>(&matches, "ao-distance", "ao distance").unwrap_or(1.0);
+    let background = match matches.opt_str("background") {
+        Some(name) => match Background::from_name(&name) {
+            Some(background) => background,
+            None => { eprintln!("invalid background '{}' (expected sky or r,g,b)", name);
+                      std::process::exit(1);
+            }
+        },
+        None => scene.image.background.unwrap_or_default(),
+    };
+    let debug_mode = match matches.opt_str("mode") {
+        Some(name) => match DebugMode::from_name(&name) {
+            Some(mode) => Some(mode),
+            None => { eprintln!("unknown mode '{}'", name);
+                      std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let tone_mapping = ToneMapping {
+        operator: match matches.opt_str("tonemap") {
+            Some(name) => match Operator::from_name(&name) {
+                Some(operator) => operator,
+                None => { eprintln!("unknown tone mapping '{}'", name);
+                          std::process::exit(1);
                 }
+            },
+            None => Operator::Clamp,
+        },
+        exposure: opt_number::<f32>(&matches, "exposure", "exposure").unwrap_or(0.0),
+        white: opt_number::<f32>(&matches, "white", "white point").unwrap_or(4.0),
+    };
+    let color_space = match matches.opt_str("color-space") {
+        Some(name) => match ColorSpace::from_name(&name) {
+            Some(space) => space,
+            None => { eprintln!("unknown color space '{}'", name);
+                      std::process::exit(1);
+            }
+        },
+        None => ColorSpace::Srgb,
+    };
+    // create empty image
+    let mut image = if alpha { Image::new(width, height).with_alpha() } else { Image::new(width, height) }.with_aovs(&aovs);
+    image.tone_mapping = tone_mapping;
+    image.color_space = color_space;
+    // camera, and the distance of the point it looks at
+    let aspect_ratio = (width as f32)/(height as f32);
+    let (camera, view_distance) = if let Some(params) = scene.camera {
+        (params.build(aspect_ratio), (params.to - params.from).length())
+    } else if matches.opt_present("i") {
+        match scene.world.bounding_box() {
+            Some(bbox) => framing_camera(&bbox, 30.0, aspect_ratio),
+            None => { eprintln!("the scene is empty or unbounded");
+                      std::process::exit(1);
             }
-            window.update_with_buffer(&buffer).unwrap();
         }
-        bar.inc(1);
+    } else {
+        let aperture = 0.051;
+        let lookfrom = Vec3::new(10.0, 1.8, 2.4);
+        let lookat = Vec3::new(0.0, 0.0, 0.5);
+        let up = Vec3::new(0.0,1.0,0.0); 
+        let dist_to_focus = (lookfrom-Vec3::new(4.0, 1.0, 0.0)).length();
+        (Camera::new(lookfrom, lookat, up, 
+                     30.0, aspect_ratio,
+                     aperture, dist_to_focus),
+         (lookat-lookfrom).length())
+    };
+    // the debug modes replace the integrator
+    let integrator_name = matches.opt_str("integrator").unwrap_or_else(|| String::from("path"));
+    if aovs.iter().any(|aov| aov.is_light()) && (debug_mode.is_some() || integrator_name != "path") {
+        eprintln!("the light passes need the path integrator");
+        std::process::exit(1);
     }
+    let integrator = match debug_mode {
+        Some(mode) => Box::new(DebugIntegrator::new(mode, view_distance, max_depth)),
+        None => {
+            match create_integrator(&integrator_name, ao_distance, max_depth, rr_depth, background) {
+                Some(integrator) => integrator,
+                None => { eprintln!("unknown integrator '{}'", integrator_name);
+                          std::process::exit(1);
+                }
+            }
+        }
+    };
+    let world = Bvh::new(scene.world.objects);
+    println!("scene has {} objects, {} BVH nodes", world.len(), world.nodes().len());
+    // create window with live framebuffer, unless running headless
+    let headless = matches.opt_present("headless") || matches.opt_present("no-preview");
+    #[cfg(feature = "preview")]
+    let mut preview = if headless {
+        None
+    } else {
+        match preview::Preview::new(width, height) {
+            Ok(preview) => Some(preview),
+            Err(e) => { eprintln!("cannot open the preview window ({}), rendering without it", e);
+                        None
+            }
+        }
+    };
+    #[cfg(not(feature = "preview"))]
+    let _ = headless; // built without the preview window
+    // progress bar, advancing as tiles are completed
+    let num_tiles = width.div_ceil(path_tracer::TILE_SIZE) * height.div_ceil(path_tracer::TILE_SIZE);
+    let bar = ProgressBar::new(num_tiles as u64);
+    bar.set_style(indicatif::ProgressStyle::default_bar()
+                  .template("[{elapsed}] [{wide_bar:.cyan/blue}] {pos}/{len} tiles ({eta} rem.)")
+                  .progress_chars("##-"));
+    // render image on all the threads, updating the preview as tiles are done
+    println!("rendering {} samples per pixel on {} threads", samples, threads);
+    let done = render(&world, &*integrator, &camera, &mut image, samples, seed, threads, |image, tile| {
+        bar.inc(1);
+        // stop rendering if the window is closed
+        #[cfg(feature = "preview")]
+        if let Some(preview) = preview.as_mut() {
+            return preview.update(image, tile);
+        }
+        #[cfg(not(feature = "preview"))]
+        let _ = (image, tile);
+        true
+    });
     bar.finish();
+    if !done {
+        println!("rendering cancelled, saving the tiles rendered so far");
+    }
 
     println!("...Done!");
     // save image to file
-    image.save(&output_filename);
+    if let Err(e) = image.save(output_path, output_format) {
+        eprintln!("error saving the image: {}", e);
+        std::process::exit(1);
+    }
+    // the exr output already has the render passes as layers
+    if !matches!(output_format, OutputFormat::Exr(_)) {
+        if let Err(e) = image.save_aovs(output_path, exr_precision) {
+            eprintln!("error saving the render passes: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
@@ -1,40 +1,95 @@
-use crate::pt_math::{Vec3, Ray, dot, unit_vector};
-
-use rand::Rng;
+use crate::pt_math::{Vec3, Ray, dot, unit_vector, mul_component, random_f32, random_unit_vector};
+use crate::objects::HitRecord;
+use crate::microfacet::{Frame, Ggx, fresnel_dielectric, fresnel_schlick, refract_microfacet};
+use crate::texture::{Texture, solid, constant};
+use std::sync::Arc;
 
 pub struct Scatter {
     pub ray: Ray,
     pub color: Vec3,
+    // solid angle pdf of the scattered direction, or None for specular scattering (that lights can't be sampled for)
+    pub pdf: Option<f32>,
 }
 
 impl Scatter {
     pub fn new(ray: Ray, color: Vec3) -> Scatter {
-        Scatter { ray, color }
+        Scatter { ray, color, pdf: None }
+    }
+
+    pub fn with_pdf(ray: Ray, color: Vec3, pdf: f32) -> Scatter {
+        Scatter { ray, color, pdf: Some(pdf) }
     }
 }
 
-pub trait Material {
-    // given an input ray, hit point and normal, calculate the scattered output ray and its attenuation
-    fn scatter(&self, ray_in: Ray, hit_point: Vec3, hit_normal: Vec3) -> Option<Scatter>;
+pub trait Material: Send + Sync {
+    // given an input ray and the hit record, calculate the scattered output ray and its attenuation
+    fn scatter(&self, ray_in: Ray, hit: &HitRecord) -> Option<Scatter>;
+    // for materials that scatter with a pdf, the reflectance times the cosine term for light leaving towards
+    // the given direction, and the pdf of `scatter` choosing that direction. Used when sampling the lights
+    fn eval(&self, _ray_in: Ray, _hit: &HitRecord, _direction: Vec3) -> (Vec3, f32) {
+        (Vec3::new(0.0,0.0,0.0), 0.0)
+    }
+    // the part of `eval` reflected by the diffuse lobe of the material, to split the lighting in the render passes
+    fn eval_diffuse(&self, _ray_in: Ray, _hit: &HitRecord, _direction: Vec3) -> Vec3 {
+        Vec3::new(0.0,0.0,0.0)
+    }
+    // radiance emitted by the surface at the hit point; only lights emit
+    fn emitted(&self, _hit: &HitRecord) -> Vec3 {
+        Vec3::new(0.0,0.0,0.0)
+    }
+    // base color of the surface at the hit point, used for debugging
+    fn albedo(&self, hit: &HitRecord) -> Vec3;
+    fn is_emissive(&self) -> bool {
+        false
+    }
 }
 
 // /////////////////// //
 // Lambertian material //
 // /////////////////// //
 pub struct Lambertian {
-    albedo: Vec3
+    albedo: Arc<dyn Texture>,
 }
 
 impl Lambertian {
     pub fn new(albedo: Vec3) -> Lambertian {
+        Lambertian::textured(solid(albedo))
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>) -> Lambertian {
         Lambertian { albedo }
     }
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _ray_in: Ray, hit_point: Vec3, hit_normal: Vec3) -> Option<Scatter> {
-        let target = hit_point + hit_normal + random_in_unit_sphere();
-        return Some( Scatter::new( Ray::new(hit_point, target-hit_point), self.albedo ) );
+    fn scatter(&self, ray_in: Ray, hit: &HitRecord) -> Option<Scatter> {
+        let hit_point = hit.point;
+        let hit_normal = facing_normal(ray_in, hit.normal);
+        // cosine weighted direction: the cosine term and the pdf cancel out, leaving the albedo as attenuation
+        let mut dir = hit_normal + random_unit_vector();
+        if dir.squared_length() < 1e-8 {
+            dir = hit_normal;
+        }
+        let pdf = dot(unit_vector(dir), hit_normal).max(0.0) / std::f32::consts::PI;
+        Some( Scatter::with_pdf( Ray::new(hit_point, dir), self.albedo(hit), pdf ) )
+    }
+
+    fn eval(&self, ray_in: Ray, hit: &HitRecord, direction: Vec3) -> (Vec3, f32) {
+        let cosine = dot(unit_vector(direction), facing_normal(ray_in, hit.normal)).max(0.0);
+        (self.albedo(hit) * (cosine / std::f32::consts::PI), cosine / std::f32::consts::PI)
+    }
+
+    fn eval_diffuse(&self, ray_in: Ray, hit: &HitRecord, direction: Vec3) -> Vec3 {
+        self.eval(ray_in, hit, direction).0
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        // vertex colors (if any) modulate the albedo
+        let albedo = self.albedo.value(hit.u, hit.v, hit.point);
+        match hit.vertex_color {
+            Some(c) => mul_component(albedo, c),
+            None => albedo,
+        }
     }
 }
 
@@ -42,25 +97,399 @@ impl Material for Lambertian {
 // Metal material //
 // ////////////// //
 pub struct Metal {
-    albedo: Vec3,
-    fuzz: f32,
+    albedo: Arc<dyn Texture>,
+    fuzz: Arc<dyn Texture>,
 }
 
 impl Metal {
     pub fn new(albedo: Vec3, fuzz: f32) -> Metal {
-        Metal { albedo, fuzz }
+        Metal::textured(solid(albedo), constant(fuzz))
     }
 
+    pub fn textured(albedo: Arc<dyn Texture>, fuzz: Arc<dyn Texture>) -> Metal {
+        Metal { albedo, fuzz }
+    }
 }
 
 impl Material for Metal {
-    fn scatter(&self, ray_in: Ray, hit_point: Vec3, hit_normal: Vec3) -> Option<Scatter> {
-        let reflected_dir = reflect(unit_vector(ray_in.direction), hit_normal) + self.fuzz*random_in_unit_sphere();
+    fn scatter(&self, ray_in: Ray, hit: &HitRecord) -> Option<Scatter> {
+        let hit_point = hit.point;
+        let hit_normal = facing_normal(ray_in, hit.normal);
+        let fuzz = self.fuzz.value(hit.u, hit.v, hit.point).x;
+        let reflected_dir = reflect(unit_vector(ray_in.direction), hit_normal) + fuzz*random_in_unit_sphere();
         if dot(reflected_dir, hit_normal)>0.0 {
-            return Some( Scatter::new( Ray::new(hit_point, reflected_dir), self.albedo ));
+            return Some( Scatter::new( Ray::new(hit_point, reflected_dir), self.albedo(hit) ));
+        }
+        None
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.albedo.value(hit.u, hit.v, hit.point)
+    }
+}
+
+// ///////////////////////////////// //
+// Rough conductor (GGX microfacets) //
+// ///////////////////////////////// //
+// metal with a GGX distribution of microfacets, sampled through the visible normals. `color` is the reflectance at
+// normal incidence, that goes to white at grazing angles
+pub struct RoughConductor {
+    color: Arc<dyn Texture>,
+    roughness: Arc<dyn Texture>,
+}
+
+impl RoughConductor {
+    pub fn new(color: Arc<dyn Texture>, roughness: Arc<dyn Texture>) -> RoughConductor {
+        RoughConductor { color, roughness }
+    }
+
+    fn distribution(&self, hit: &HitRecord) -> Ggx {
+        Ggx::from_roughness(self.roughness.value(hit.u, hit.v, hit.point).x)
+    }
+}
+
+impl Material for RoughConductor {
+    fn scatter(&self, ray_in: Ray, hit: &HitRecord) -> Option<Scatter> {
+        let frame = Frame::new(facing_normal(ray_in, hit.normal));
+        let wo = frame.to_local(-unit_vector(ray_in.direction));
+        let distribution = self.distribution(hit);
+        let m = distribution.sample_visible(wo, random_f32(), random_f32());
+        let wi = reflect(-wo, m);
+        if wi.z <= 0.0 || dot(wo, m) <= 0.0 {
+            return None;
+        }
+        // f*cos/pdf: D and the visible fraction of the microfacets cancel out
+        let color = fresnel_schlick(self.albedo(hit), dot(wo, m)) * (distribution.g2(wo, wi) / distribution.g1(wo));
+        let pdf = distribution.pdf_visible(wo, m) / (4.0*dot(wo, m));
+        Some( Scatter::with_pdf( Ray::new(hit.point, frame.to_world(wi)), color, pdf ) )
+    }
+
+    fn eval(&self, ray_in: Ray, hit: &HitRecord, direction: Vec3) -> (Vec3, f32) {
+        let frame = Frame::new(facing_normal(ray_in, hit.normal));
+        let wo = frame.to_local(-unit_vector(ray_in.direction));
+        let wi = frame.to_local(unit_vector(direction));
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return (Vec3::new(0.0,0.0,0.0), 0.0);
+        }
+        let m = unit_vector(wo + wi);
+        let distribution = self.distribution(hit);
+        let f = fresnel_schlick(self.albedo(hit), dot(wo, m))
+              * (distribution.d(m) * distribution.g2(wo, wi) / (4.0*wo.z));
+        (f, distribution.pdf_visible(wo, m) / (4.0*dot(wo, m)))
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.color.value(hit.u, hit.v, hit.point)
+    }
+}
+
+// ////////////////////////////////// //
+// Rough dielectric (GGX microfacets) //
+// ////////////////////////////////// //
+// glass with a GGX distribution of microfacets, that both reflect and refract (Walter et al. 2007)
+pub struct RoughDielectric {
+    refraction_index: f32,
+    roughness: Arc<dyn Texture>,
+}
+
+impl RoughDielectric {
+    pub fn new(refraction_index: f32, roughness: Arc<dyn Texture>) -> RoughDielectric {
+        RoughDielectric { refraction_index, roughness }
+    }
+
+    fn distribution(&self, hit: &HitRecord) -> Ggx {
+        Ggx::from_roughness(self.roughness.value(hit.u, hit.v, hit.point).x)
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, ray_in: Ray, hit: &HitRecord) -> Option<Scatter> {
+        let (frame, eta) = interface_frame(ray_in, hit, self.refraction_index);
+        let wo = frame.to_local(-unit_vector(ray_in.direction));
+        let distribution = self.distribution(hit);
+        let wi = sample_dielectric_lobe(&distribution, wo, eta)?;
+        let (f, pdf) = dielectric_lobe(&distribution, wo, wi, eta);
+        if pdf <= 0.0 {
+            return None;
         }
+        Some( Scatter::with_pdf( Ray::new(hit.point, frame.to_world(wi)), Vec3::new(f, f, f) / pdf, pdf ) )
+    }
+
+    fn eval(&self, ray_in: Ray, hit: &HitRecord, direction: Vec3) -> (Vec3, f32) {
+        let (frame, eta) = interface_frame(ray_in, hit, self.refraction_index);
+        let (f, pdf) = dielectric_lobe(&self.distribution(hit), frame.to_local(-unit_vector(ray_in.direction)),
+                                       frame.to_local(unit_vector(direction)), eta);
+        (Vec3::new(f, f, f), pdf)
+    }
+
+    fn albedo(&self, _hit: &HitRecord) -> Vec3 {
+        Vec3::new(1.0,1.0,1.0)
+    }
+}
+
+// shading frame on the side of the incoming ray, and the ratio of the refraction indices across the surface
+fn interface_frame(ray_in: Ray, hit: &HitRecord, refraction_index: f32) -> (Frame, f32) {
+    if dot(ray_in.direction, hit.normal) < 0.0 {
+        (Frame::new(hit.normal), refraction_index)
+    } else {
+        (Frame::new(-hit.normal), 1.0/refraction_index)
+    }
+}
+
+// reflect or refract on a visible microfacet, with the probability given by the Fresnel term
+fn sample_dielectric_lobe(distribution: &Ggx, wo: Vec3, eta: f32) -> Option<Vec3> {
+    let m = distribution.sample_visible(wo, random_f32(), random_f32());
+    let cos_o = dot(wo, m);
+    if wo.z <= 0.0 || cos_o <= 0.0 {
         return None;
     }
+    // directions ending up on the wrong side of the surface are discarded
+    if random_f32() < fresnel_dielectric(cos_o, eta) {
+        Some(reflect(-wo, m)).filter(|wi| wi.z > 0.0)
+    } else {
+        refract_microfacet(wo, m, eta).filter(|wi| wi.z < 0.0)
+    }
+}
+
+// reflectance times cosine of a rough dielectric interface, and the pdf of `sample_dielectric_lobe`.
+// Directions are in the local frame, with wo on the positive side
+fn dielectric_lobe(distribution: &Ggx, wo: Vec3, wi: Vec3, eta: f32) -> (f32, f32) {
+    if wo.z <= 0.0 || wi.z == 0.0 {
+        return (0.0, 0.0);
+    }
+    if wi.z > 0.0 {
+        let m = unit_vector(wo + wi);
+        let fresnel = fresnel_dielectric(dot(wo, m), eta);
+        (fresnel * distribution.d(m) * distribution.g2(wo, wi) / (4.0*wo.z),
+         fresnel * distribution.pdf_visible(wo, m) / (4.0*dot(wo, m)))
+    } else {
+        // the microfacet normal that refracts wo into wi
+        let m = unit_vector(wo + wi*eta);
+        let m = if m.z < 0.0 { -m } else { m };
+        let (cos_o, cos_i) = (dot(wo, m), dot(wi, m));
+        if cos_o <= 0.0 || cos_i >= 0.0 {
+            return (0.0, 0.0);
+        }
+        let transmitted = 1.0 - fresnel_dielectric(cos_o, eta);
+        let denom = cos_i + cos_o/eta;
+        (transmitted * distribution.d(m) * distribution.g2(wo, wi) * (cos_i*cos_o).abs() / (wo.z*denom*denom),
+         transmitted * distribution.pdf_visible(wo, m) * cos_i.abs() / (denom*denom))
+    }
+}
+
+// //////////////////////////// //
+// Principled (Disney) material //
+// //////////////////////////// //
+// parameters of the principled material. All of them are in [0,1], except the refraction index
+#[derive(Clone)]
+pub struct PrincipledParams {
+    pub base_color: Arc<dyn Texture>,
+    pub metallic: Arc<dyn Texture>,
+    pub roughness: Arc<dyn Texture>,
+    pub specular: Arc<dyn Texture>,        // reflectance of the dielectric part at normal incidence; 0.5 is 4%
+    pub sheen: Arc<dyn Texture>,           // white rim at grazing angles, for cloth
+    pub clearcoat: Arc<dyn Texture>,       // strength of a second, colorless specular layer on top
+    pub clearcoat_gloss: Arc<dyn Texture>, // smoothness of the clear coat
+    pub transmission: Arc<dyn Texture>,    // fraction of the dielectric part that lets light through
+    pub ior: f32,
+}
+
+impl Default for PrincipledParams {
+    fn default() -> PrincipledParams {
+        PrincipledParams {
+            base_color: solid(Vec3::new(0.8,0.8,0.8)),
+            metallic: constant(0.0),
+            roughness: constant(0.5),
+            specular: constant(0.5),
+            sheen: constant(0.0),
+            clearcoat: constant(0.0),
+            clearcoat_gloss: constant(1.0),
+            transmission: constant(0.0),
+            ior: 1.5,
+        }
+    }
+}
+
+// Disney's principled BSDF (Burley 2012, 2015): diffuse with retro-reflection and sheen, a GGX specular lobe going
+// from dielectric to metallic, a clear coat and rough transmission. One of the lobes is sampled at random, and the
+// pdf of the direction combines the pdfs of all of them
+pub struct Principled {
+    params: PrincipledParams,
+}
+
+// the principled parameters evaluated at a hit point
+struct PrincipledLobes {
+    base_color: Vec3,
+    roughness: f32,
+    sheen: f32,
+    specular_f0: Vec3,
+    specular: Ggx,
+    clearcoat: Ggx,
+    // weights of the diffuse, specular reflection, clear coat and transmission lobes, also used to pick the lobe to sample
+    weights: [f32; 4],
+}
+
+impl Principled {
+    pub fn new(params: PrincipledParams) -> Principled {
+        Principled { params }
+    }
+
+    fn base_color(&self, hit: &HitRecord) -> Vec3 {
+        let base_color = self.params.base_color.value(hit.u, hit.v, hit.point);
+        match hit.vertex_color {
+            Some(c) => mul_component(base_color, c),
+            None => base_color,
+        }
+    }
+
+    fn lobes(&self, hit: &HitRecord) -> PrincipledLobes {
+        let p = &self.params;
+        // textures can go outside of [0,1] (e.g. hdr images), and the lobes would then create energy
+        let scalar = |t: &Arc<dyn Texture>| t.value(hit.u, hit.v, hit.point).x.clamp(0.0, 1.0);
+        let (metallic, roughness, transmission) = (scalar(&p.metallic), scalar(&p.roughness), scalar(&p.transmission));
+        let base_color = self.base_color(hit);
+        let dielectric = 1.0 - metallic;
+        // the clear coat roughness goes from 0.1 to 0.001 in alpha, as in the Disney model
+        let clearcoat_alpha = 0.1 + (0.001 - 0.1)*scalar(&p.clearcoat_gloss);
+        PrincipledLobes {
+            base_color,
+            roughness,
+            sheen: scalar(&p.sheen),
+            specular_f0: Vec3::new(1.0,1.0,1.0)*(0.08*scalar(&p.specular)*dielectric) + base_color*metallic,
+            specular: Ggx::from_roughness(roughness),
+            clearcoat: Ggx::from_roughness(clearcoat_alpha.sqrt()),
+            weights: [dielectric*(1.0 - transmission), 1.0 - dielectric*transmission, 0.25*scalar(&p.clearcoat), dielectric*transmission],
+        }
+    }
+
+    // diffuse lobe, with retro-reflection and sheen, for directions on the same side of the surface
+    fn diffuse_local(&self, lobes: &PrincipledLobes, wo: Vec3, wi: Vec3) -> Vec3 {
+        let diffuse = lobes.weights[0];
+        if wo.z <= 0.0 || wi.z <= 0.0 || diffuse <= 0.0 {
+            return Vec3::new(0.0,0.0,0.0);
+        }
+        let cos_d = dot(wi, unit_vector(wo + wi));
+        let schlick_weight = |cos: f32| (1.0 - cos.clamp(0.0, 1.0)).powi(5);
+        let fd90 = 0.5 + 2.0*lobes.roughness*cos_d*cos_d;
+        let retro = (1.0 + (fd90 - 1.0)*schlick_weight(wi.z)) * (1.0 + (fd90 - 1.0)*schlick_weight(wo.z));
+        let sheen = lobes.sheen*schlick_weight(cos_d);
+        (lobes.base_color*(retro/std::f32::consts::PI) + Vec3::new(1.0,1.0,1.0)*sheen) * (diffuse*wi.z)
+    }
+
+    fn eval_local(&self, lobes: &PrincipledLobes, wo: Vec3, wi: Vec3, eta: f32) -> (Vec3, f32) {
+        let [diffuse, specular, clearcoat, transmission] = lobes.weights;
+        let total = diffuse + specular + clearcoat + transmission;
+        let white = Vec3::new(1.0,1.0,1.0);
+        let (mut f, mut pdf) = (Vec3::new(0.0,0.0,0.0), 0.0);
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return (f, pdf);
+        }
+        if wi.z < 0.0 {
+            // refraction is tinted by the base color
+            let (ft, pt) = dielectric_lobe(&lobes.specular, wo, wi, eta);
+            return (lobes.base_color * (transmission*ft), transmission/total * pt);
+        }
+        let h = unit_vector(wo + wi);
+        let cos_d = dot(wi, h);
+        if diffuse > 0.0 {
+            f = f + self.diffuse_local(lobes, wo, wi);
+            pdf += diffuse/total * wi.z/std::f32::consts::PI;
+        }
+        if specular > 0.0 {
+            f = f + fresnel_schlick(lobes.specular_f0, cos_d) * (specular*lobes.specular.d(h)*lobes.specular.g2(wo, wi) / (4.0*wo.z));
+            pdf += specular/total * lobes.specular.pdf_visible(wo, h) / (4.0*dot(wo, h));
+        }
+        if clearcoat > 0.0 {
+            let fc = fresnel_schlick(white*0.04, cos_d);
+            f = f + fc * (clearcoat*lobes.clearcoat.d(h)*lobes.clearcoat.g2(wo, wi) / (4.0*wo.z));
+            pdf += clearcoat/total * lobes.clearcoat.pdf_visible(wo, h) / (4.0*dot(wo, h));
+        }
+        if transmission > 0.0 {
+            let (fr, pr) = dielectric_lobe(&lobes.specular, wo, wi, eta);
+            f = f + white*(transmission*fr);
+            pdf += transmission/total * pr;
+        }
+        (f, pdf)
+    }
+}
+
+impl Material for Principled {
+    fn scatter(&self, ray_in: Ray, hit: &HitRecord) -> Option<Scatter> {
+        let (frame, eta) = interface_frame(ray_in, hit, self.params.ior);
+        let wo = frame.to_local(-unit_vector(ray_in.direction));
+        let lobes = self.lobes(hit);
+        let [diffuse, specular, clearcoat, transmission] = lobes.weights;
+        let u = random_f32() * (diffuse + specular + clearcoat + transmission);
+        let wi = if u < diffuse {
+            let dir = Vec3::new(0.0,0.0,1.0) + random_unit_vector();
+            if dir.squared_length() < 1e-8 { Vec3::new(0.0,0.0,1.0) } else { unit_vector(dir) }
+        } else if u < diffuse + specular {
+            reflect(-wo, lobes.specular.sample_visible(wo, random_f32(), random_f32()))
+        } else if u < diffuse + specular + clearcoat {
+            reflect(-wo, lobes.clearcoat.sample_visible(wo, random_f32(), random_f32()))
+        } else {
+            sample_dielectric_lobe(&lobes.specular, wo, eta)?
+        };
+        // reflections below the surface would be taken for transmission
+        if wi.z <= 0.0 && u < diffuse + specular + clearcoat {
+            return None;
+        }
+        let (f, pdf) = self.eval_local(&lobes, wo, wi, eta);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some( Scatter::with_pdf( Ray::new(hit.point, frame.to_world(wi)), f / pdf, pdf ) )
+    }
+
+    fn eval(&self, ray_in: Ray, hit: &HitRecord, direction: Vec3) -> (Vec3, f32) {
+        let (frame, eta) = interface_frame(ray_in, hit, self.params.ior);
+        self.eval_local(&self.lobes(hit), frame.to_local(-unit_vector(ray_in.direction)), frame.to_local(unit_vector(direction)), eta)
+    }
+
+    fn eval_diffuse(&self, ray_in: Ray, hit: &HitRecord, direction: Vec3) -> Vec3 {
+        let (frame, _) = interface_frame(ray_in, hit, self.params.ior);
+        self.diffuse_local(&self.lobes(hit), frame.to_local(-unit_vector(ray_in.direction)), frame.to_local(unit_vector(direction)))
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.base_color(hit)
+    }
+}
+
+// ///////////////////// //
+// Diffuse light material //
+// ///////////////////// //
+// emits the same radiance in all directions, from both sides of the surface, and doesn't reflect light
+pub struct DiffuseLight {
+    emit: Arc<dyn Texture>,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Vec3) -> DiffuseLight {
+        DiffuseLight::textured(solid(emit))
+    }
+
+    pub fn textured(emit: Arc<dyn Texture>) -> DiffuseLight {
+        DiffuseLight { emit }
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _ray_in: Ray, _hit: &HitRecord) -> Option<Scatter> {
+        None
+    }
+
+    fn emitted(&self, hit: &HitRecord) -> Vec3 {
+        self.emit.value(hit.u, hit.v, hit.point)
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.emitted(hit)
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
 }
 
 // /////////////////// //
@@ -68,18 +497,23 @@ impl Material for Metal {
 // /////////////////// //
 pub struct Dielectric {
     refraction_index: f32,
-    fuzz: f32,
+    fuzz: Arc<dyn Texture>,
     attenuation: Vec3,
 }
 
 impl Dielectric {
     pub fn new(refraction_index: f32, fuzz: f32) -> Dielectric {
+        Dielectric::textured(refraction_index, constant(fuzz))
+    }
+
+    pub fn textured(refraction_index: f32, fuzz: Arc<dyn Texture>) -> Dielectric {
         Dielectric { refraction_index, fuzz, attenuation: Vec3::new(1.0,1.0,1.0) }
     }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, ray_in: Ray, hit_point: Vec3, hit_normal: Vec3) -> Option<Scatter> {
+    fn scatter(&self, ray_in: Ray, hit: &HitRecord) -> Option<Scatter> {
+        let (hit_point, hit_normal) = (hit.point, hit.normal);
         let dn_dot = dot(ray_in.direction, hit_normal);
         let ray_dir_len = ray_in.direction.length();
         let mut outward_normal = -hit_normal;
@@ -92,23 +526,32 @@ impl Material for Dielectric {
             cosine = -dn_dot / ray_dir_len;
         }
         // calc reflected/refracted ray
-        let reflected_dir = reflect(unit_vector(ray_in.direction), hit_normal) + self.fuzz*random_in_unit_sphere();
+        let fuzz = self.fuzz.value(hit.u, hit.v, hit.point).x;
+        let reflected_dir = reflect(unit_vector(ray_in.direction), hit_normal) + fuzz*random_in_unit_sphere();
         if let Some(refracted_dir) = refract(ray_in.direction, outward_normal, ni_over_nt) {
             let reflect_prob = schlick(cosine, self.refraction_index);
-            let mut rng = rand::thread_rng();
-            if rng.gen::<f32>() < reflect_prob {
+            if random_f32() < reflect_prob {
                 return Some( Scatter::new( Ray::new(hit_point, reflected_dir), self.attenuation) );
             } else {
-                let refracted_dir = refracted_dir + self.fuzz*random_in_unit_sphere();
+                let refracted_dir = refracted_dir + fuzz*random_in_unit_sphere();
                 return Some( Scatter::new( Ray::new(hit_point, refracted_dir), self.attenuation) );
             }
         }
-        return Some( Scatter::new( Ray::new(hit_point, reflected_dir), self.attenuation) );
+        Some( Scatter::new( Ray::new(hit_point, reflected_dir), self.attenuation) )
+    }
+
+    fn albedo(&self, _hit: &HitRecord) -> Vec3 {
+        self.attenuation
     }
 }
 
 
 
+// opaque surfaces can be hit from the back (e.g. open meshes): flip the normal towards the incoming ray
+fn facing_normal(ray_in: Ray, n: Vec3) -> Vec3 {
+    if dot(ray_in.direction, n) > 0.0 { -n } else { n }
+}
+
 fn reflect(v: Vec3, n: Vec3) -> Vec3 {
     v - 2.0*dot(v, n)*n
 }
@@ -121,7 +564,7 @@ fn refract(v: Vec3, n: Vec3, ni_over_nt: f32) -> Option<Vec3> {
         let refracted = ni_over_nt * (u - n*dt) - n*discriminant.sqrt();
         return Some(refracted);
     }
-    return None;
+    None
 }
 
 // Schlick's approximation for refelctivity function of angle
@@ -132,9 +575,8 @@ fn schlick(cosine: f32, refraction_index: f32) -> f32 {
 }
 
 fn random_in_unit_sphere() -> Vec3 {
-    let mut rng = rand::thread_rng();
     loop {
-        let p = Vec3::new(rng.gen::<f32>(),rng.gen::<f32>(),rng.gen::<f32>());
+        let p = Vec3::new(random_f32(),random_f32(),random_f32());
         let p = p*2.0 - Vec3::new(1.0,1.0,1.0); // values are in the range [-1,1) now
         if p.squared_length()<=1.0 {
             return p;
@@ -145,11 +587,16 @@ fn random_in_unit_sphere() -> Vec3 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pt_math::seed_rng;
 
     fn approx_eq(a: f32, b: f32, epsilon: f32) -> bool {
         (a - b).abs() < epsilon
     }
 
+    fn hit_record(point: Vec3, normal: Vec3, material: &dyn Material) -> HitRecord<'_> {
+        HitRecord { t: 1.0, point, normal, u: 0.0, v: 0.0, vertex_color: None, object_id: 0, material }
+    }
+
     #[test]
     fn test_reflect() {
         // Reflect a vector off a horizontal surface (normal pointing up)
@@ -263,9 +710,10 @@ mod tests {
         let albedo = Vec3::new(0.8, 0.3, 0.3);
         let lambertian = Lambertian::new(albedo);
         
-        assert_eq!(lambertian.albedo.x, 0.8);
-        assert_eq!(lambertian.albedo.y, 0.3);
-        assert_eq!(lambertian.albedo.z, 0.3);
+        let a = lambertian.albedo.value(0.0, 0.0, Vec3::new(0.0, 0.0, 0.0));
+        assert_eq!(a.x, 0.8);
+        assert_eq!(a.y, 0.3);
+        assert_eq!(a.z, 0.3);
     }
 
     #[test]
@@ -277,7 +725,7 @@ mod tests {
         let hit_point = Vec3::new(0.0, 0.0, 0.0);
         let hit_normal = Vec3::new(0.0, 1.0, 0.0);
         
-        let scatter = lambertian.scatter(ray_in, hit_point, hit_normal);
+        let scatter = lambertian.scatter(ray_in, &hit_record(hit_point, hit_normal, &lambertian));
         assert!(scatter.is_some());
         
         let s = scatter.unwrap();
@@ -286,14 +734,219 @@ mod tests {
         assert_eq!(s.color.z, 0.3);
     }
 
+    #[test]
+    fn lambertian_scatter_back_face() {
+        let lambertian = Lambertian::new(Vec3::new(0.8, 0.3, 0.3));
+
+        // ray hitting the surface from below, with the normal pointing up
+        let ray_in = Ray::new(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let hit_normal = Vec3::new(0.0, 1.0, 0.0);
+        for _ in 0..10 {
+            let s = lambertian.scatter(ray_in, &hit_record(Vec3::new(0.0, 0.0, 0.0), hit_normal, &lambertian)).unwrap();
+            // scattered ray must stay on the side of the incoming ray
+            assert!(s.ray.direction.y <= 0.0);
+        }
+    }
+
+    #[test]
+    fn lambertian_scatter_vertex_color() {
+        let lambertian = Lambertian::new(Vec3::new(0.8, 0.5, 1.0));
+
+        let ray_in = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let mut hit = hit_record(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), &lambertian);
+        hit.vertex_color = Some(Vec3::new(0.5, 1.0, 0.0));
+
+        let s = lambertian.scatter(ray_in, &hit).unwrap();
+        assert_eq!(s.color.x, 0.4);
+        assert_eq!(s.color.y, 0.5);
+        assert_eq!(s.color.z, 0.0);
+    }
+
+    #[test]
+    fn lambertian_eval_matches_scatter() {
+        let lambertian = Lambertian::new(Vec3::new(0.5, 0.5, 0.5));
+        let ray_in = Ray::new(Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0));
+        let hit = hit_record(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), &lambertian);
+        for _ in 0..100 {
+            let s = lambertian.scatter(ray_in, &hit).unwrap();
+            let (f, pdf) = lambertian.eval(ray_in, &hit, s.ray.direction);
+            assert!(approx_eq(pdf, s.pdf.unwrap(), 1e-4));
+            // attenuation is reflectance*cosine/pdf
+            if pdf > 1e-3 {
+                assert!(approx_eq(f.x / pdf, s.color.x, 1e-4));
+            }
+        }
+        // nothing is reflected below the surface
+        let (f, pdf) = lambertian.eval(ray_in, &hit, Vec3::new(0.0, -1.0, 0.0));
+        assert_eq!((f.x, pdf), (0.0, 0.0));
+        let (f, pdf) = lambertian.eval(ray_in, &hit, Vec3::new(0.0, 2.0, 0.0));
+        assert!(approx_eq(pdf, 1.0 / std::f32::consts::PI, 1e-5) && approx_eq(f.x, 0.5 / std::f32::consts::PI, 1e-5));
+    }
+
+    // the pdf and reflectance returned by eval are consistent with the sampled directions; returns the average weight
+    fn check_eval_matches_scatter(material: &dyn Material, ray_in: Ray, normal: Vec3) -> f32 {
+        let hit = hit_record(Vec3::new(0.0, 0.0, 0.0), normal, material);
+        let samples = 2000;
+        let mut sum = 0.0;
+        for _ in 0..samples {
+            let Some(s) = material.scatter(ray_in, &hit) else { continue };
+            let (f, pdf) = material.eval(ray_in, &hit, s.ray.direction);
+            let expected = s.pdf.unwrap();
+            assert!(approx_eq(pdf, expected, 1e-2*expected.max(1.0)), "{} {}", pdf, expected);
+            assert!(approx_eq(f.y / pdf, s.color.y, 1e-2*s.color.y.max(1.0)), "{} {}", f.y / pdf, s.color.y);
+            sum += s.color.y;
+        }
+        sum / samples as f32
+    }
+
+    #[test]
+    fn rough_conductor() {
+        let ray_in = Ray::new(Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0));
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        for &roughness in &[0.1, 0.3, 0.8] {
+            // a white conductor loses only the light that is reflected more than once between the microfacets:
+            // little for smooth surfaces, and up to ~40% for very rough ones
+            let average = check_eval_matches_scatter(&RoughConductor::new(solid(Vec3::new(1.0, 1.0, 1.0)), constant(roughness)), ray_in, up);
+            assert!(average <= 1.0 && average > 1.0 - 0.6*roughness, "{} {}", roughness, average);
+        }
+        // smooth conductors reflect like a mirror
+        let smooth = RoughConductor::new(solid(Vec3::new(0.9, 0.9, 0.9)), constant(0.0));
+        let s = smooth.scatter(ray_in, &hit_record(Vec3::new(0.0, 0.0, 0.0), up, &smooth)).unwrap();
+        let dir = unit_vector(s.ray.direction);
+        assert!(approx_eq(dir.x, -std::f32::consts::FRAC_1_SQRT_2, 1e-2) && approx_eq(dir.y, std::f32::consts::FRAC_1_SQRT_2, 1e-2));
+        // the same material hit from the back of the surface
+        check_eval_matches_scatter(&RoughConductor::new(solid(Vec3::new(0.9, 0.6, 0.3)), constant(0.5)), ray_in, -up);
+    }
+
+    #[test]
+    fn rough_dielectric() {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let outside = Ray::new(Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0));
+        let inside = Ray::new(Vec3::new(0.5, -1.0, 0.0), Vec3::new(-0.5, 1.0, 0.0));
+        for &roughness in &[0.1, 0.5] {
+            let glass = RoughDielectric::new(1.5, constant(roughness));
+            // rays entering and leaving the glass
+            for &ray_in in &[outside, inside] {
+                let average = check_eval_matches_scatter(&glass, ray_in, up);
+                assert!(average <= 1.0 && average > 0.8, "{} {}", roughness, average);
+                if roughness > 0.3 {
+                    assert!(approx_eq(average, integrate_eval(&glass, ray_in, up), 0.03));
+                }
+            }
+        }
+        // most of the light goes through
+        let glass = RoughDielectric::new(1.5, constant(0.1));
+        let hit = hit_record(Vec3::new(0.0, 0.0, 0.0), up, &glass);
+        let transmitted = (0..1000).filter_map(|_| glass.scatter(outside, &hit)).filter(|s| s.ray.direction.y < 0.0).count();
+        assert!(transmitted > 850, "{}", transmitted);
+    }
+
+    // reflectance of the material integrated over the whole sphere of directions, on a regular grid
+    fn integrate_eval(material: &dyn Material, ray_in: Ray, normal: Vec3) -> f32 {
+        let hit = hit_record(Vec3::new(0.0, 0.0, 0.0), normal, material);
+        let n = 300;
+        let mut sum = 0.0;
+        for i in 0..n*n {
+            let z = 1.0 - 2.0*((i / n) as f32 + 0.5) / n as f32;
+            let phi = 2.0*std::f32::consts::PI*((i % n) as f32 + 0.5) / n as f32;
+            let r = (1.0 - z*z).sqrt();
+            sum += material.eval(ray_in, &hit, Vec3::new(r*phi.cos(), z, r*phi.sin())).0.y;
+        }
+        sum * 4.0*std::f32::consts::PI / (n*n) as f32
+    }
+
+    #[test]
+    fn principled() {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let outside = Ray::new(Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0));
+        let inside = Ray::new(Vec3::new(0.5, -1.0, 0.0), Vec3::new(-0.5, 1.0, 0.0));
+        let base = PrincipledParams { base_color: solid(Vec3::new(0.7, 0.7, 0.7)), roughness: constant(0.6), ..PrincipledParams::default() };
+        let materials = [
+            base.clone(),
+            PrincipledParams { metallic: constant(1.0), ..base.clone() },
+            PrincipledParams { metallic: constant(0.3), sheen: constant(1.0), clearcoat: constant(1.0), clearcoat_gloss: constant(0.0), ..base.clone() },
+            PrincipledParams { transmission: constant(1.0), ..base.clone() },
+            PrincipledParams { transmission: constant(0.5), metallic: constant(0.2), ..base.clone() },
+        ];
+        for (i, params) in materials.iter().enumerate() {
+            let material = Principled::new(params.clone());
+            for &ray_in in &[outside, inside] {
+                // the sampled directions have the pdf returned by eval, and the average weight is the integral of
+                // the reflectance: the lobes are sampled with the right pdfs
+                seed_rng(1, 0, 0);
+                let samples = 20;
+                let average = (0..samples).map(|_| check_eval_matches_scatter(&material, ray_in, up)).sum::<f32>() / samples as f32;
+                let integral = integrate_eval(&material, ray_in, up);
+                assert!(approx_eq(average, integral, 0.01), "material {}: {} {}", i, average, integral);
+                assert!(average < 1.0, "material {}: {}", i, average);
+            }
+        }
+        // opaque materials don't let light through
+        let material = Principled::new(base);
+        let hit = hit_record(Vec3::new(0.0, 0.0, 0.0), up, &material);
+        assert_eq!(material.eval(outside, &hit, Vec3::new(0.0, -1.0, 0.0)).1, 0.0);
+        assert_eq!(material.albedo(&hit).x, 0.7);
+    }
+
+    #[test]
+    fn principled_clamps_parameters() {
+        // textures out of [0,1] behave as the nearest valid value, instead of creating energy
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let ray_in = Ray::new(Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0));
+        let base = PrincipledParams { roughness: constant(0.5), ..PrincipledParams::default() };
+        let wild = Principled::new(PrincipledParams { metallic: constant(-1.0), specular: constant(5.0), sheen: constant(3.0),
+                                                      clearcoat: constant(4.0), transmission: constant(-2.0), ..base.clone() });
+        let clamped = Principled::new(PrincipledParams { metallic: constant(0.0), specular: constant(1.0), sheen: constant(1.0),
+                                                         clearcoat: constant(1.0), transmission: constant(0.0), ..base });
+        let (hit_wild, hit_clamped) = (hit_record(Vec3::new(0.0, 0.0, 0.0), up, &wild), hit_record(Vec3::new(0.0, 0.0, 0.0), up, &clamped));
+        for &dir in &[Vec3::new(0.3, 1.0, 0.2), Vec3::new(-1.0, 0.4, 0.0), Vec3::new(1.0, 1.0, 0.0)] {
+            let (f, pdf) = wild.eval(ray_in, &hit_wild, dir);
+            let (f_clamped, pdf_clamped) = clamped.eval(ray_in, &hit_clamped, dir);
+            assert_eq!((f.x, f.y, f.z, pdf), (f_clamped.x, f_clamped.y, f_clamped.z, pdf_clamped));
+        }
+    }
+
+    #[test]
+    fn diffuse_part_of_eval() {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let ray_in = Ray::new(Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0));
+        let directions = [Vec3::new(0.3, 1.0, 0.2), Vec3::new(-1.0, 0.4, 0.0), Vec3::new(1.0, 1.0, 0.0)];
+        let lambertian = Lambertian::new(Vec3::new(0.5, 0.5, 0.5));
+        let hit = hit_record(Vec3::new(0.0, 0.0, 0.0), up, &lambertian);
+        for &dir in &directions {
+            assert_eq!(lambertian.eval_diffuse(ray_in, &hit, dir).y, lambertian.eval(ray_in, &hit, dir).0.y);
+        }
+        // the diffuse lobe of the principled material is only part of the reflectance, and metals have none
+        let material = Principled::new(PrincipledParams { clearcoat: constant(1.0), ..PrincipledParams::default() });
+        let hit = hit_record(Vec3::new(0.0, 0.0, 0.0), up, &material);
+        for &dir in &directions {
+            let (diffuse, f) = (material.eval_diffuse(ray_in, &hit, dir).y, material.eval(ray_in, &hit, dir).0.y);
+            assert!(diffuse > 0.0 && diffuse < f, "{} {}", diffuse, f);
+        }
+        let metal = Principled::new(PrincipledParams { metallic: constant(1.0), ..PrincipledParams::default() });
+        assert_eq!(metal.eval_diffuse(ray_in, &hit_record(Vec3::new(0.0, 0.0, 0.0), up, &metal), directions[0]).y, 0.0);
+        let mirror = Metal::new(Vec3::new(0.8, 0.8, 0.8), 0.0);
+        assert_eq!(mirror.eval_diffuse(ray_in, &hit_record(Vec3::new(0.0, 0.0, 0.0), up, &mirror), directions[2]).y, 0.0);
+    }
+
+    #[test]
+    fn specular_materials_have_no_pdf() {
+        let ray_in = Ray::new(Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0));
+        let metal = Metal::new(Vec3::new(0.8, 0.8, 0.8), 0.0);
+        assert!(metal.scatter(ray_in, &hit_record(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), &metal)).unwrap().pdf.is_none());
+        let glass = Dielectric::new(1.5, 0.0);
+        assert!(glass.scatter(ray_in, &hit_record(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), &glass)).unwrap().pdf.is_none());
+    }
+
     #[test]
     fn metal_creation() {
         let albedo = Vec3::new(0.8, 0.6, 0.2);
         let fuzz = 0.1;
         let metal = Metal::new(albedo, fuzz);
         
-        assert_eq!(metal.albedo.x, 0.8);
-        assert_eq!(metal.fuzz, 0.1);
+        let p = Vec3::new(0.0, 0.0, 0.0);
+        assert_eq!(metal.albedo.value(0.0, 0.0, p).x, 0.8);
+        assert_eq!(metal.fuzz.value(0.0, 0.0, p).x, 0.1);
     }
 
     #[test]
@@ -305,7 +958,7 @@ mod tests {
         let hit_point = Vec3::new(0.0, 0.0, 0.0);
         let hit_normal = Vec3::new(0.0, 1.0, 0.0);
         
-        let scatter = metal.scatter(ray_in, hit_point, hit_normal);
+        let scatter = metal.scatter(ray_in, &hit_record(hit_point, hit_normal, &metal));
         assert!(scatter.is_some());
         
         let s = scatter.unwrap();
@@ -323,12 +976,27 @@ mod tests {
         let hit_point = Vec3::new(0.0, 0.0, 0.0);
         let hit_normal = Vec3::new(0.0, 1.0, 0.0);
         
-        let scatter = metal.scatter(ray_in, hit_point, hit_normal);
+        let scatter = metal.scatter(ray_in, &hit_record(hit_point, hit_normal, &metal));
         // May or may not scatter depending on exact angle
         // Just checking it doesn't panic
         assert!(scatter.is_some() || scatter.is_none());
     }
 
+    #[test]
+    fn diffuse_light_emits() {
+        let light = DiffuseLight::new(Vec3::new(4.0, 4.0, 2.0));
+        let hit = hit_record(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), &light);
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(light.scatter(ray, &hit).is_none());
+        assert!(light.is_emissive());
+        let e = light.emitted(&hit);
+        assert_eq!((e.x, e.y, e.z), (4.0, 4.0, 2.0));
+        // other materials don't emit
+        let lambertian = Lambertian::new(Vec3::new(0.5, 0.5, 0.5));
+        let e = lambertian.emitted(&hit_record(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), &lambertian));
+        assert_eq!((e.x, e.y, e.z), (0.0, 0.0, 0.0));
+    }
+
     #[test]
     fn dielectric_creation() {
         let ref_idx = 1.5;
@@ -336,7 +1004,7 @@ mod tests {
         let dielectric = Dielectric::new(ref_idx, fuzz);
         
         assert_eq!(dielectric.refraction_index, 1.5);
-        assert_eq!(dielectric.fuzz, 0.0);
+        assert_eq!(dielectric.fuzz.value(0.0, 0.0, Vec3::new(0.0, 0.0, 0.0)).x, 0.0);
         assert_eq!(dielectric.attenuation.x, 1.0);
     }
 
@@ -348,7 +1016,7 @@ mod tests {
         let hit_point = Vec3::new(0.0, 0.0, 0.0);
         let hit_normal = Vec3::new(0.0, 1.0, 0.0);
         
-        let scatter = dielectric.scatter(ray_in, hit_point, hit_normal);
+        let scatter = dielectric.scatter(ray_in, &hit_record(hit_point, hit_normal, &dielectric));
         assert!(scatter.is_some());
         
         let s = scatter.unwrap();
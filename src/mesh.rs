@@ -0,0 +1,204 @@
+use std::sync::Arc;
+
+use crate::pt_math::{Vec3, Ray, Aabb, unit_vector, cross};
+use crate::objects::{Hitable, HitRecord, intersect_triangle};
+use crate::material::Material;
+use crate::bvh::Bvh;
+
+// vertex attributes of a mesh. The buffers are reference counted, so that several meshes
+// (e.g. the groups of a model using different materials) can share them
+pub struct MeshVertices {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,   // either empty or one per position
+    pub uvs: Vec<(f32, f32)>, // either empty or one per position
+    pub colors: Vec<Vec3>,    // either empty or one per position
+}
+
+impl MeshVertices {
+    pub fn new(positions: Vec<Vec3>, normals: Vec<Vec3>, uvs: Vec<(f32, f32)>) -> MeshVertices {
+        assert!(normals.is_empty() || normals.len() == positions.len(), "mesh needs one normal per vertex");
+        assert!(uvs.is_empty() || uvs.len() == positions.len(), "mesh needs one uv per vertex");
+        MeshVertices { positions, normals, uvs, colors: Vec::new() }
+    }
+
+    pub fn with_colors(mut self, colors: Vec<Vec3>) -> MeshVertices {
+        assert!(colors.is_empty() || colors.len() == self.positions.len(), "mesh needs one color per vertex");
+        self.colors = colors;
+        self
+    }
+}
+
+// a triangle of a mesh, referencing the shared vertex buffers
+struct MeshTriangle {
+    vertices: Arc<MeshVertices>,
+    material: Arc<dyn Material>,
+    indices: [u32; 3],
+}
+
+impl MeshTriangle {
+    fn positions(&self) -> [Vec3; 3] {
+        let p = &self.vertices.positions;
+        [p[self.indices[0] as usize], p[self.indices[1] as usize], p[self.indices[2] as usize]]
+    }
+}
+
+impl Hitable for MeshTriangle {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let [p0, p1, p2] = self.positions();
+        let (t, b1, b2) = intersect_triangle(ray, p0, p1, p2, t_min, t_max)?;
+        let b0 = 1.0 - b1 - b2;
+        let [i0, i1, i2] = [self.indices[0] as usize, self.indices[1] as usize, self.indices[2] as usize];
+        // smooth shading if the mesh has per-vertex normals
+        let normal = if self.vertices.normals.is_empty() {
+            unit_vector(cross(p1 - p0, p2 - p0))
+        } else {
+            let n = &self.vertices.normals;
+            unit_vector(b0*n[i0] + b1*n[i1] + b2*n[i2])
+        };
+        let (u, v) = if self.vertices.uvs.is_empty() {
+            (b1, b2)
+        } else {
+            let uv = &self.vertices.uvs;
+            (b0*uv[i0].0 + b1*uv[i1].0 + b2*uv[i2].0,
+             b0*uv[i0].1 + b1*uv[i1].1 + b2*uv[i2].1)
+        };
+        let vertex_color = if self.vertices.colors.is_empty() {
+            None
+        } else {
+            let c = &self.vertices.colors;
+            Some(b0*c[i0] + b1*c[i1] + b2*c[i2])
+        };
+        let point = ray.point_at_parameter(t);
+        Some(HitRecord{t, point, normal, u, v, vertex_color, object_id: 0, material: &*self.material})
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let [p0, p1, p2] = self.positions();
+        Some(Aabb::new(p0, p0).grow(p1).grow(p2))
+    }
+}
+
+// indexed triangle mesh. Triangles are stored in their own bvh
+pub struct TriangleMesh {
+    triangles: Bvh<MeshTriangle>,
+}
+
+impl TriangleMesh {
+    pub fn new(vertices: Arc<MeshVertices>, indices: Vec<[u32; 3]>, material: Box<dyn Material>) -> TriangleMesh {
+        let material: Arc<dyn Material> = Arc::from(material);
+        let triangles = indices.into_iter().map(|indices| {
+            assert!(indices.iter().all(|&i| (i as usize) < vertices.positions.len()), "mesh index out of range");
+            MeshTriangle { vertices: vertices.clone(), material: material.clone(), indices }
+        }).collect();
+        TriangleMesh { triangles: Bvh::new(triangles) }
+    }
+}
+
+impl Hitable for TriangleMesh {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.triangles.hit(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.triangles.bounding_box()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+
+    fn approx_eq(a: f32, b: f32, epsilon: f32) -> bool {
+        (a - b).abs() < epsilon
+    }
+
+    // unit quad in the z=-2 plane made of two triangles
+    fn quad_vertices(normals: Vec<Vec3>, uvs: Vec<(f32, f32)>) -> Arc<MeshVertices> {
+        Arc::new(MeshVertices::new(vec![Vec3::new(-1.0, -1.0, -2.0), Vec3::new(1.0, -1.0, -2.0),
+                                        Vec3::new(1.0, 1.0, -2.0), Vec3::new(-1.0, 1.0, -2.0)],
+                                   normals, uvs))
+    }
+
+    fn quad(vertices: Arc<MeshVertices>) -> TriangleMesh {
+        TriangleMesh::new(vertices, vec![[0, 1, 2], [0, 2, 3]], Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))))
+    }
+
+    #[test]
+    fn mesh_creation() {
+        let mesh = quad(quad_vertices(Vec::new(), Vec::new()));
+        assert_eq!(mesh.triangles.len(), 2);
+        let bbox = mesh.bounding_box().unwrap();
+        assert_eq!((bbox.min.x, bbox.min.y, bbox.min.z), (-1.0, -1.0, -2.0));
+        assert_eq!((bbox.max.x, bbox.max.y, bbox.max.z), (1.0, 1.0, -2.0));
+    }
+
+    #[test]
+    fn mesh_hit_flat() {
+        let mesh = quad(quad_vertices(Vec::new(), Vec::new()));
+        for &(x, y) in &[(-0.5, 0.5), (0.5, -0.5), (0.9, 0.9)] {
+            let ray = Ray::new(Vec3::new(x, y, 0.0), Vec3::new(0.0, 0.0, -1.0));
+            let hit = mesh.hit(&ray, 0.0, 100.0).unwrap();
+            assert!(approx_eq(hit.t, 2.0, 1e-5));
+            assert!(approx_eq(hit.normal.z, 1.0, 1e-5));
+        }
+        let ray = Ray::new(Vec3::new(1.5, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(mesh.hit(&ray, 0.0, 100.0).is_none());
+    }
+
+    #[test]
+    fn mesh_smooth_normals() {
+        // normals tilted towards +x on the right side of the quad
+        let n_left = Vec3::new(0.0, 0.0, 1.0);
+        let n_right = unit_vector(Vec3::new(1.0, 0.0, 1.0));
+        let mesh = quad(quad_vertices(vec![n_left, n_right, n_right, n_left], Vec::new()));
+        let ray = Ray::new(Vec3::new(-1.0+1e-4, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = mesh.hit(&ray, 0.0, 100.0).unwrap();
+        assert!(approx_eq(hit.normal.x, 0.0, 1e-3));
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = mesh.hit(&ray, 0.0, 100.0).unwrap();
+        assert!(hit.normal.x > 0.1 && hit.normal.x < n_right.x);
+        assert!(approx_eq(hit.normal.length(), 1.0, 1e-5));
+    }
+
+    #[test]
+    fn mesh_uv_interpolation() {
+        let uvs = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+        let mesh = quad(quad_vertices(Vec::new(), uvs));
+        let ray = Ray::new(Vec3::new(0.5, -0.5, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = mesh.hit(&ray, 0.0, 100.0).unwrap();
+        assert!(approx_eq(hit.u, 0.75, 1e-5));
+        assert!(approx_eq(hit.v, 0.25, 1e-5));
+    }
+
+    #[test]
+    fn mesh_vertex_colors() {
+        let colors = vec![Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0)];
+        let vertices = Arc::into_inner(quad_vertices(Vec::new(), Vec::new())).unwrap().with_colors(colors);
+        let mesh = quad(Arc::new(vertices));
+        let ray = Ray::new(Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let c = mesh.hit(&ray, 0.0, 100.0).unwrap().vertex_color.unwrap();
+        assert!(approx_eq(c.x, 0.5, 1e-5));
+        assert!(approx_eq(c.y, 0.5, 1e-5));
+        assert!(approx_eq(c.z, 0.0, 1e-5));
+        // meshes without colors don't report them
+        let mesh = quad(quad_vertices(Vec::new(), Vec::new()));
+        assert!(mesh.hit(&ray, 0.0, 100.0).unwrap().vertex_color.is_none());
+    }
+
+    #[test]
+    fn mesh_shared_vertices() {
+        let vertices = quad_vertices(Vec::new(), Vec::new());
+        let a = TriangleMesh::new(vertices.clone(), vec![[0, 1, 2]], Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))));
+        let b = TriangleMesh::new(vertices.clone(), vec![[0, 2, 3]], Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))));
+        assert_eq!(Arc::strong_count(&vertices), 3);
+        assert_eq!(a.triangles.len() + b.triangles.len(), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn mesh_index_out_of_range() {
+        TriangleMesh::new(quad_vertices(Vec::new(), Vec::new()), vec![[0, 1, 4]],
+                          Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::pt_math::{Vec3, dot, cross, unit_vector, orthonormal_basis};
+use std::f32::consts::PI;
+
+// local shading frame, with the normal along z
+#[derive(Clone,Copy,Debug)]
+pub struct Frame {
+    t: Vec3,
+    b: Vec3,
+    n: Vec3,
+}
+
+impl Frame {
+    pub fn new(n: Vec3) -> Frame {
+        let (t, b) = orthonormal_basis(n);
+        Frame { t, b, n }
+    }
+
+    pub fn to_local(self, v: Vec3) -> Vec3 {
+        Vec3::new(dot(v, self.t), dot(v, self.b), dot(v, self.n))
+    }
+
+    pub fn to_world(self, v: Vec3) -> Vec3 {
+        self.t*v.x + self.b*v.y + self.n*v.z
+    }
+}
+
+// isotropic Trowbridge-Reitz (GGX) distribution of microfacet normals, with Smith shadowing.
+// All the directions are in the local frame, and point away from the surface
+#[derive(Clone,Copy,Debug)]
+pub struct Ggx {
+    alpha: f32,
+}
+
+impl Ggx {
+    // the perceptual roughness in [0,1] is squared, as in the Disney and glTF models. Perfectly smooth surfaces
+    // are approximated with a very narrow lobe
+    pub fn from_roughness(roughness: f32) -> Ggx {
+        Ggx { alpha: (roughness*roughness).clamp(1e-3, 1.0) }
+    }
+
+    // density of microfacets with normal m
+    pub fn d(&self, m: Vec3) -> f32 {
+        if m.z <= 0.0 {
+            return 0.0;
+        }
+        let a2 = self.alpha*self.alpha;
+        let k = m.z*m.z*(a2 - 1.0) + 1.0;
+        a2 / (PI*k*k)
+    }
+
+    fn lambda(&self, v: Vec3) -> f32 {
+        let cos2 = v.z*v.z;
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        0.5*((1.0 + self.alpha*self.alpha*tan2).sqrt() - 1.0)
+    }
+
+    // fraction of the microfacets visible from v
+    pub fn g1(&self, v: Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(v))
+    }
+
+    // fraction of the microfacets visible from both directions (height correlated)
+    pub fn g2(&self, wo: Vec3, wi: Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // sample a microfacet normal among the ones visible from wo (Heitz 2018), given two uniform numbers in [0,1)
+    pub fn sample_visible(&self, wo: Vec3, u1: f32, u2: f32) -> Vec3 {
+        // stretch the view direction to the configuration with unit roughness
+        let vh = unit_vector(Vec3::new(self.alpha*wo.x, self.alpha*wo.y, wo.z));
+        let len2 = vh.x*vh.x + vh.y*vh.y;
+        let t1 = if len2 > 0.0 { Vec3::new(-vh.y, vh.x, 0.0) / len2.sqrt() } else { Vec3::new(1.0,0.0,0.0) };
+        let t2 = cross(vh, t1);
+        // uniform point on the disk, warped onto the visible half of the projected hemisphere
+        let r = u1.sqrt();
+        let phi = 2.0*PI*u2;
+        let p1 = r*phi.cos();
+        let s = 0.5*(1.0 + vh.z);
+        let p2 = (1.0 - s)*(1.0 - p1*p1).max(0.0).sqrt() + s*r*phi.sin();
+        let nh = t1*p1 + t2*p2 + vh*(1.0 - p1*p1 - p2*p2).max(0.0).sqrt();
+        // unstretch
+        unit_vector(Vec3::new(self.alpha*nh.x, self.alpha*nh.y, nh.z.max(1e-6)))
+    }
+
+    // pdf of `sample_visible` returning m
+    pub fn pdf_visible(&self, wo: Vec3, m: Vec3) -> f32 {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * dot(wo, m).max(0.0) * self.d(m) / wo.z
+    }
+}
+
+// fraction of light reflected by a smooth dielectric interface, for the given cosine of the incident angle.
+// `eta` is the ratio of the refraction indices on the transmitted and on the incident side
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i*cos_i) / (eta*eta);
+    if sin2_t >= 1.0 {
+        return 1.0; // total internal reflection
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (cos_i - eta*cos_t) / (cos_i + eta*cos_t);
+    let rp = (eta*cos_i - cos_t) / (eta*cos_i + cos_t);
+    0.5*(rs*rs + rp*rp)
+}
+
+// Schlick's approximation with a colored reflectance at normal incidence, used for conductors
+pub fn fresnel_schlick(f0: Vec3, cos_i: f32) -> Vec3 {
+    let k = (1.0 - cos_i.clamp(0.0, 1.0)).powi(5);
+    f0 + (Vec3::new(1.0,1.0,1.0) - f0)*k
+}
+
+// direction transmitted through a microfacet with normal m, or None for total internal reflection
+pub fn refract_microfacet(wo: Vec3, m: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_o = dot(wo, m);
+    let sin2_t = (1.0 - cos_o*cos_o).max(0.0) / (eta*eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo/eta + m*(cos_o/eta - cos_t))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approx_eq(a: f32, b: f32, epsilon: f32) -> bool {
+        (a - b).abs() < epsilon
+    }
+
+    // uniform directions over the sphere, on a regular grid of (cos theta, phi)
+    fn sphere_grid(n: usize) -> impl Iterator<Item = Vec3> {
+        (0..n*n).map(move |i| {
+            let z = 1.0 - 2.0*((i / n) as f32 + 0.5) / n as f32;
+            let phi = 2.0*PI*((i % n) as f32 + 0.5) / n as f32;
+            let r = (1.0 - z*z).sqrt();
+            Vec3::new(r*phi.cos(), r*phi.sin(), z)
+        })
+    }
+
+    #[test]
+    fn frame_roundtrip() {
+        let frame = Frame::new(unit_vector(Vec3::new(1.0, 2.0, -0.5)));
+        let v = Vec3::new(0.3, -0.7, 0.2);
+        let w = frame.to_world(frame.to_local(v));
+        assert!(approx_eq(v.x, w.x, 1e-5) && approx_eq(v.y, w.y, 1e-5) && approx_eq(v.z, w.z, 1e-5));
+        assert!(approx_eq(frame.to_local(unit_vector(Vec3::new(1.0, 2.0, -0.5))).z, 1.0, 1e-5));
+    }
+
+    #[test]
+    fn ggx_projected_area_is_one() {
+        // the projected area of the microfacets equals the macro surface
+        let n = 400;
+        for &roughness in &[0.5, 0.7, 1.0] {
+            let ggx = Ggx::from_roughness(roughness);
+            let sum: f32 = sphere_grid(n).map(|m| ggx.d(m)*m.z.max(0.0)).sum();
+            let integral = sum * 4.0*PI / (n*n) as f32;
+            assert!(approx_eq(integral, 1.0, 0.02), "{} {}", roughness, integral);
+        }
+    }
+
+    #[test]
+    fn ggx_visible_normals_pdf() {
+        // the density of the visible normals integrates to one, and the samples are distributed accordingly:
+        // averaging f(m)/pdf(m) over the samples gives the integral of f
+        let ggx = Ggx::from_roughness(0.7);
+        let wo = unit_vector(Vec3::new(0.6, 0.0, 0.5));
+        let n = 400;
+        let integral: f32 = sphere_grid(n).map(|m| ggx.pdf_visible(wo, m)).sum::<f32>() * 4.0*PI / (n*n) as f32;
+        assert!(approx_eq(integral, 1.0, 0.02), "{}", integral);
+        let f = |m: Vec3| m.z*m.z*ggx.pdf_visible(wo, m);
+        let expected: f32 = sphere_grid(n).map(f).sum::<f32>() * 4.0*PI / (n*n) as f32;
+        let samples = 256;
+        let mut estimate = 0.0;
+        for i in 0..samples*samples {
+            let (u1, u2) = (((i / samples) as f32 + 0.5) / samples as f32, ((i % samples) as f32 + 0.5) / samples as f32);
+            let m = ggx.sample_visible(wo, u1, u2);
+            assert!(m.z > 0.0 && dot(wo, m) >= -1e-4);
+            estimate += f(m) / ggx.pdf_visible(wo, m);
+        }
+        estimate /= (samples*samples) as f32;
+        assert!(approx_eq(estimate, expected, 0.02*expected), "{} {}", estimate, expected);
+    }
+
+    #[test]
+    fn ggx_shadowing() {
+        let ggx = Ggx::from_roughness(0.5);
+        // nothing is hidden looking straight down, and everything at grazing angles
+        assert!(approx_eq(ggx.g1(Vec3::new(0.0, 0.0, 1.0)), 1.0, 1e-6));
+        assert!(ggx.g1(Vec3::new(1.0, 0.0, 1e-4)) < 0.01);
+        let (wo, wi) = (unit_vector(Vec3::new(0.5, 0.0, 0.5)), unit_vector(Vec3::new(-0.2, 0.3, 0.7)));
+        assert!(ggx.g2(wo, wi) <= ggx.g1(wo).min(ggx.g1(wi)));
+    }
+
+    #[test]
+    fn fresnel() {
+        // 4% reflection for glass at normal incidence
+        assert!(approx_eq(fresnel_dielectric(1.0, 1.5), 0.04, 1e-4));
+        assert!(approx_eq(fresnel_dielectric(0.0, 1.5), 1.0, 1e-4));
+        // total internal reflection leaving glass at a shallow angle
+        assert_eq!(fresnel_dielectric(0.3, 1.0/1.5), 1.0);
+        let f = fresnel_schlick(Vec3::new(0.9, 0.5, 0.1), 1.0);
+        assert!(approx_eq(f.x, 0.9, 1e-6) && approx_eq(f.z, 0.1, 1e-6));
+        assert!(approx_eq(fresnel_schlick(Vec3::new(0.9, 0.5, 0.1), 0.0).z, 1.0, 1e-6));
+    }
+
+    #[test]
+    fn microfacet_refraction() {
+        // straight through along the normal, and Snell's law at an angle
+        let m = Vec3::new(0.0, 0.0, 1.0);
+        let t = refract_microfacet(Vec3::new(0.0, 0.0, 1.0), m, 1.5).unwrap();
+        assert!(approx_eq(t.z, -1.0, 1e-6));
+        let wo = unit_vector(Vec3::new(1.0, 0.0, 1.0));
+        let t = refract_microfacet(wo, m, 1.5).unwrap();
+        assert!(approx_eq(t.length(), 1.0, 1e-5));
+        assert!(approx_eq(wo.x, 1.5*(-t.x), 1e-5));
+        assert!(refract_microfacet(unit_vector(Vec3::new(1.0, 0.0, 0.3)), m, 1.0/1.5).is_none());
+    }
+}
@@ -0,0 +1,120 @@
+use crate::pt_math::{Vec3, dot, unit_vector};
+use rand::prelude::*;
+use rand_pcg::Pcg32;
+
+const POINT_COUNT: usize = 256;
+
+// Perlin gradient noise, as in "Ray Tracing: The Next Week". The lattice is generated from the seed, so the
+// same seed always gives the same pattern
+pub struct Perlin {
+    gradients: Vec<Vec3>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Perlin {
+        let mut rng = Pcg32::seed_from_u64(seed);
+        let gradients = (0..POINT_COUNT).map(|_| {
+            loop {
+                let v = Vec3::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0));
+                if v.squared_length() > 1e-4 {
+                    return unit_vector(v);
+                }
+            }
+        }).collect();
+        let mut permutation = || {
+            let mut p: Vec<usize> = (0..POINT_COUNT).collect();
+            p.shuffle(&mut rng);
+            p
+        };
+        let (perm_x, perm_y, perm_z) = (permutation(), permutation(), permutation());
+        Perlin { gradients, perm_x, perm_y, perm_z }
+    }
+
+    // smooth noise in [-1,1], zero at the lattice points
+    pub fn noise(&self, p: Vec3) -> f32 {
+        let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (u, v, w) = (p.x - fx, p.y - fy, p.z - fz);
+        let (i, j, k) = (fx as i64, fy as i64, fz as i64);
+        // hermite smoothing of the interpolation weights
+        let (uu, vv, ww) = (u*u*(3.0 - 2.0*u), v*v*(3.0 - 2.0*v), w*w*(3.0 - 2.0*w));
+        let mut accum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let index = self.perm_x[((i + di) & 255) as usize] ^ self.perm_y[((j + dj) & 255) as usize]
+                              ^ self.perm_z[((k + dk) & 255) as usize];
+                    let (a, b, c) = (di as f32, dj as f32, dk as f32);
+                    let weight = Vec3::new(u - a, v - b, w - c);
+                    accum += (a*uu + (1.0 - a)*(1.0 - uu)) * (b*vv + (1.0 - b)*(1.0 - vv)) * (c*ww + (1.0 - c)*(1.0 - ww))
+                           * dot(self.gradients[index], weight);
+                }
+            }
+        }
+        accum
+    }
+
+    // sum of the absolute value of octaves of noise, with doubling frequency and halving amplitude. Mostly in [0,1]
+    pub fn turbulence(&self, p: Vec3, octaves: u32) -> f32 {
+        let mut accum = 0.0;
+        let (mut p, mut weight) = (p, 1.0);
+        for _ in 0..octaves {
+            accum += weight*self.noise(p).abs();
+            weight *= 0.5;
+            p = p*2.0;
+        }
+        accum
+    }
+
+    // fractional brownian motion: like the turbulence, but keeping the sign of the octaves. Mostly in [-1,1]
+    pub fn fbm(&self, p: Vec3, octaves: u32) -> f32 {
+        let mut accum = 0.0;
+        let (mut p, mut weight) = (p, 1.0);
+        for _ in 0..octaves {
+            accum += weight*self.noise(p);
+            weight *= 0.5;
+            p = p*2.0;
+        }
+        accum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // points on a grid not aligned with the lattice
+    fn grid() -> impl Iterator<Item = Vec3> {
+        (0..1000).map(|i| Vec3::new((i % 10) as f32 * 0.37, ((i / 10) % 10) as f32 * 0.53, (i / 100) as f32 * 0.71))
+    }
+
+    #[test]
+    fn perlin_noise() {
+        let perlin = Perlin::new(7);
+        assert_eq!(perlin.noise(Vec3::new(3.0, -2.0, 5.0)), 0.0);
+        assert!(grid().all(|p| perlin.noise(p).abs() <= 1.0));
+        // continuous, and not constant
+        let p = Vec3::new(1.3, 2.7, -0.4);
+        assert!((perlin.noise(p) - perlin.noise(p + Vec3::new(1e-3, 0.0, 0.0))).abs() < 1e-2);
+        assert!(grid().any(|p| perlin.noise(p).abs() > 0.1));
+    }
+
+    #[test]
+    fn perlin_seed() {
+        let (a, b, c) = (Perlin::new(1), Perlin::new(1), Perlin::new(2));
+        assert!(grid().all(|p| a.noise(p) == b.noise(p)));
+        assert!(grid().any(|p| a.noise(p) != c.noise(p)));
+    }
+
+    #[test]
+    fn perlin_octaves() {
+        let perlin = Perlin::new(0);
+        let p = Vec3::new(0.3, 1.6, 2.2);
+        assert_eq!(perlin.turbulence(p, 1), perlin.noise(p).abs());
+        assert_eq!(perlin.fbm(p, 1), perlin.noise(p));
+        assert!(grid().all(|p| perlin.turbulence(p, 7) >= 0.0 && perlin.turbulence(p, 7) < 2.0));
+        assert!(grid().all(|p| perlin.fbm(p, 7).abs() < 2.0));
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::pt_math::Vec3;
+use crate::objects::HitableList;
+use crate::mesh::{MeshVertices, TriangleMesh};
+use crate::material::{Material, Lambertian, Metal, Dielectric, DiffuseLight};
+
+// Wavefront OBJ/MTL loader. Every group of faces sharing the same material becomes a TriangleMesh;
+// all the meshes of a file share the same vertex buffers.
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, line: usize, message: String },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ObjError::Parse { path, line, message } => write!(f, "{}:{}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+// material description as found in a .mtl file
+#[derive(Clone,Debug)]
+pub struct MtlMaterial {
+    pub name: String,
+    pub diffuse: Vec3,  // Kd
+    pub specular: Vec3, // Ks
+    pub emission: Vec3, // Ke
+    pub shininess: f32, // Ns
+    pub ior: f32,       // Ni
+    pub dissolve: f32,  // d (1 - Tr)
+    pub illum: u32,
+}
+
+impl MtlMaterial {
+    pub fn new(name: &str) -> MtlMaterial {
+        MtlMaterial { name: name.to_string(),
+                      diffuse: Vec3::new(0.8, 0.8, 0.8),
+                      specular: Vec3::new(0.0, 0.0, 0.0),
+                      emission: Vec3::new(0.0, 0.0, 0.0),
+                      shininess: 0.0,
+                      ior: 1.5,
+                      dissolve: 1.0,
+                      illum: 2,
+        }
+    }
+
+    // map the phong-style parameters onto the materials supported by the renderer:
+    // emissive materials become lights, transparent materials become dielectrics, materials with a specular component stronger
+    // than the diffuse one become metals (with a fuzz derived from the phong exponent),
+    // everything else is lambertian
+    pub fn to_material(&self) -> Box<dyn Material> {
+        let max = |v: Vec3| v.x.max(v.y).max(v.z);
+        if max(self.emission) > 0.0 {
+            Box::new(DiffuseLight::new(self.emission))
+        } else if self.dissolve < 1.0 || [4, 6, 7, 9].contains(&self.illum) {
+            Box::new(Dielectric::new(self.ior, 0.0))
+        } else if max(self.specular) > max(self.diffuse) {
+            // roughness of the blinn-phong lobe equivalent to the given exponent
+            let fuzz = (2.0 / (self.shininess.max(0.0) + 2.0)).sqrt();
+            Box::new(Metal::new(self.specular, fuzz))
+        } else {
+            Box::new(Lambertian::new(self.diffuse))
+        }
+    }
+}
+
+pub fn load_obj(path: &Path) -> Result<HitableList, ObjError> {
+    let file = std::fs::File::open(path).map_err(|error| ObjError::Io { path: path.to_path_buf(), error })?;
+    parse_obj(BufReader::new(file), path)
+}
+
+// load all the materials defined in a .mtl file
+pub fn load_mtl(path: &Path) -> Result<Vec<MtlMaterial>, ObjError> {
+    let file = std::fs::File::open(path).map_err(|error| ObjError::Io { path: path.to_path_buf(), error })?;
+    parse_mtl(BufReader::new(file), path)
+}
+
+// helper to parse the arguments of a statement, reporting errors at the right line
+struct LineParser<'a> {
+    path: &'a Path,
+    line: usize,
+}
+
+impl<'a> LineParser<'a> {
+    fn error(&self, message: String) -> ObjError {
+        ObjError::Parse { path: self.path.to_path_buf(), line: self.line, message }
+    }
+
+    fn floats(&self, keyword: &str, args: &[&str], min: usize, max: usize) -> Result<Vec<f32>, ObjError> {
+        if args.len() < min || args.len() > max {
+            let expected = if min == max { format!("{}", min) } else { format!("{} to {}", min, max) };
+            return Err(self.error(format!("'{}' expects {} values, found {}", keyword, expected, args.len())));
+        }
+        args.iter().map(|a| a.parse::<f32>()
+                              .map_err(|_| self.error(format!("invalid number '{}' in '{}'", a, keyword))))
+                   .collect()
+    }
+
+    fn vec3(&self, keyword: &str, args: &[&str]) -> Result<Vec3, ObjError> {
+        let v = self.floats(keyword, args, 3, 3)?;
+        Ok(Vec3::new(v[0], v[1], v[2]))
+    }
+
+    fn float(&self, keyword: &str, args: &[&str]) -> Result<f32, ObjError> {
+        Ok(self.floats(keyword, args, 1, 1)?[0])
+    }
+
+    // resolve a 1-based (or negative, relative to the end) obj index
+    fn index(&self, s: &str, count: usize, what: &str) -> Result<usize, ObjError> {
+        let i = s.parse::<i64>().map_err(|_| self.error(format!("invalid {} index '{}'", what, s)))?;
+        let idx = if i > 0 { i - 1 } else { count as i64 + i };
+        if i == 0 || idx < 0 || idx >= count as i64 {
+            return Err(self.error(format!("{} index {} out of range ({} defined)", what, i, count)));
+        }
+        Ok(idx as usize)
+    }
+}
+
+// strip comments and split a line into keyword and arguments
+fn tokenize(line: &str) -> Option<(&str, Vec<&str>)> {
+    let line = match line.find('#') {
+        Some(pos) => &line[..pos],
+        None => line,
+    };
+    let mut tokens = line.split_whitespace();
+    let keyword = tokens.next()?;
+    Some((keyword, tokens.collect()))
+}
+
+pub fn parse_mtl<R: BufRead>(reader: R, path: &Path) -> Result<Vec<MtlMaterial>, ObjError> {
+    let mut materials: Vec<MtlMaterial> = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|error| ObjError::Io { path: path.to_path_buf(), error })?;
+        let p = LineParser { path, line: i+1 };
+        let (keyword, args) = match tokenize(&line) {
+            Some(t) => t,
+            None => continue,
+        };
+        if keyword == "newmtl" {
+            if args.len() != 1 {
+                return Err(p.error("'newmtl' expects a material name".to_string()));
+            }
+            materials.push(MtlMaterial::new(args[0]));
+            continue;
+        }
+        let mat = match materials.last_mut() {
+            Some(m) => m,
+            None => return Err(p.error(format!("'{}' found before any 'newmtl'", keyword))),
+        };
+        match keyword {
+            "Kd" => mat.diffuse = p.vec3(keyword, &args)?,
+            "Ks" => mat.specular = p.vec3(keyword, &args)?,
+            "Ke" => mat.emission = p.vec3(keyword, &args)?,
+            "Ns" => mat.shininess = p.float(keyword, &args)?,
+            "Ni" => mat.ior = p.float(keyword, &args)?,
+            "d" => mat.dissolve = p.float(keyword, &args)?,
+            "Tr" => mat.dissolve = 1.0 - p.float(keyword, &args)?,
+            "illum" => mat.illum = p.float(keyword, &args)? as u32,
+            _ => {} // other statements (ambient, texture maps, ...) are not supported
+        }
+    }
+    Ok(materials)
+}
+
+// faces using the same material in the same group
+struct FaceGroup {
+    material: Option<String>,
+    triangles: Vec<[u32; 3]>,
+}
+
+pub fn parse_obj<R: BufRead>(reader: R, path: &Path) -> Result<HitableList, ObjError> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut positions: Vec<Vec3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut texcoords: Vec<(f32, f32)> = Vec::new();
+    let mut materials: HashMap<String, MtlMaterial> = HashMap::new();
+    // vertices are unique combinations of position/texcoord/normal indices
+    let mut vertex_map: HashMap<(usize, Option<usize>, Option<usize>), u32> = HashMap::new();
+    let mut vertices: Vec<(usize, Option<usize>, Option<usize>)> = Vec::new();
+    let mut groups = vec![FaceGroup { material: None, triangles: Vec::new() }];
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|error| ObjError::Io { path: path.to_path_buf(), error })?;
+        let p = LineParser { path, line: i+1 };
+        let (keyword, args) = match tokenize(&line) {
+            Some(t) => t,
+            None => continue,
+        };
+        match keyword {
+            "v" => {
+                let v = p.floats(keyword, &args, 3, 4)?;
+                positions.push(Vec3::new(v[0], v[1], v[2]));
+            }
+            "vn" => normals.push(p.vec3(keyword, &args)?),
+            "vt" => {
+                let v = p.floats(keyword, &args, 1, 3)?;
+                texcoords.push((v[0], if v.len() > 1 { v[1] } else { 0.0 }));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(p.error(format!("face needs at least 3 vertices, found {}", args.len())));
+                }
+                let mut face = Vec::with_capacity(args.len());
+                for arg in &args {
+                    let mut parts = arg.split('/');
+                    let v = p.index(parts.next().unwrap_or(""), positions.len(), "vertex")?;
+                    let vt = match parts.next() {
+                        Some(s) if !s.is_empty() => Some(p.index(s, texcoords.len(), "texture coordinate")?),
+                        _ => None,
+                    };
+                    let vn = match parts.next() {
+                        Some(s) if !s.is_empty() => Some(p.index(s, normals.len(), "normal")?),
+                        _ => None,
+                    };
+                    if parts.next().is_some() {
+                        return Err(p.error(format!("invalid face vertex '{}'", arg)));
+                    }
+                    let key = (v, vt, vn);
+                    let idx = *vertex_map.entry(key).or_insert_with(|| {
+                        vertices.push(key);
+                        (vertices.len() - 1) as u32
+                    });
+                    face.push(idx);
+                }
+                // n-gons are triangulated as a fan around the first vertex
+                let group = groups.last_mut().unwrap();
+                for k in 1..face.len()-1 {
+                    group.triangles.push([face[0], face[k], face[k+1]]);
+                }
+            }
+            "g" | "o" => {
+                let material = groups.last().unwrap().material.clone();
+                groups.push(FaceGroup { material, triangles: Vec::new() });
+            }
+            "usemtl" => {
+                if args.len() != 1 {
+                    return Err(p.error("'usemtl' expects a material name".to_string()));
+                }
+                if !materials.contains_key(args[0]) {
+                    return Err(p.error(format!("unknown material '{}'", args[0])));
+                }
+                groups.push(FaceGroup { material: Some(args[0].to_string()), triangles: Vec::new() });
+            }
+            "mtllib" => {
+                if args.is_empty() {
+                    return Err(p.error("'mtllib' expects a file name".to_string()));
+                }
+                for name in &args {
+                    for mat in load_mtl(&dir.join(name))? {
+                        materials.insert(mat.name.clone(), mat);
+                    }
+                }
+            }
+            _ => {} // smoothing groups, lines, points, ... are ignored
+        }
+    }
+
+    // build the shared vertex buffers. Attributes are used only if every vertex has them
+    let has_normals = !vertices.is_empty() && vertices.iter().all(|v| v.2.is_some());
+    let has_uvs = !vertices.is_empty() && vertices.iter().all(|v| v.1.is_some());
+    let mesh_vertices = Arc::new(MeshVertices::new(
+        vertices.iter().map(|v| positions[v.0]).collect(),
+        if has_normals { vertices.iter().map(|v| normals[v.2.unwrap()]).collect() } else { Vec::new() },
+        if has_uvs { vertices.iter().map(|v| texcoords[v.1.unwrap()]).collect() } else { Vec::new() },
+    ));
+
+    let mut world = HitableList::new();
+    for group in groups.into_iter().filter(|g| !g.triangles.is_empty()) {
+        let material = match group.material {
+            Some(name) => materials[&name].to_material(),
+            None => MtlMaterial::new("default").to_material(),
+        };
+        world.add(TriangleMesh::new(mesh_vertices.clone(), group.triangles, material));
+    }
+    Ok(world)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pt_math::Ray;
+    use crate::objects::Hitable;
+
+    fn parse(src: &str) -> Result<HitableList, ObjError> {
+        parse_obj(src.as_bytes(), Path::new("test.obj"))
+    }
+
+    fn parse_error_line(src: &str) -> usize {
+        match parse(src) {
+            Err(ObjError::Parse { line, .. }) => line,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("parsing should fail"),
+        }
+    }
+
+    const QUAD: &str = "# a quad
+v -1 -1 -2
+v 1 -1 -2
+v 1 1 -2
+v -1 1 -2
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+f 1/1/1 2/2/1 3/3/1 4/4/1
+";
+
+    #[test]
+    fn obj_quad() {
+        let world = parse(QUAD).unwrap();
+        assert_eq!(world.objects.len(), 1);
+        let ray = Ray::new(Vec3::new(0.5, -0.5, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = world.hit(&ray, 0.0, 100.0).unwrap();
+        assert!((hit.t - 2.0).abs() < 1e-5);
+        assert!((hit.u - 0.75).abs() < 1e-5);
+        assert!((hit.v - 0.25).abs() < 1e-5);
+    }
+
+    #[test]
+    fn obj_negative_indices_and_groups() {
+        let src = "v 0 0 0\nv 1 0 0\nv 0 1 0\ng a\nf -3 -2 -1\nv 0 0 1\ng b\nf 1 2 4\nf 1 2 9\n";
+        // last face references a vertex that doesn't exist
+        assert_eq!(parse_error_line(src), 9);
+        let src = "v 0 0 0\nv 1 0 0\nv 0 1 0\ng a\nf -3 -2 -1\nv 0 0 1\ng b\nf 1 2 4\n";
+        let world = parse(src).unwrap();
+        assert_eq!(world.objects.len(), 2);
+    }
+
+    #[test]
+    fn obj_errors() {
+        assert_eq!(parse_error_line("v 1 2\n"), 1);
+        assert_eq!(parse_error_line("v 0 0 0\nv 1 x 0\n"), 2);
+        assert_eq!(parse_error_line("v 0 0 0\nv 1 0 0\n\nf 1 2\n"), 4);
+        assert_eq!(parse_error_line("v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1 2 4\n"), 4);
+        assert_eq!(parse_error_line("v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1/1 2/1 3/1\n"), 4);
+        assert_eq!(parse_error_line("usemtl missing\n"), 1);
+    }
+
+    #[test]
+    fn obj_missing_mtllib() {
+        match parse("mtllib does_not_exist.mtl\n") {
+            Err(ObjError::Io { path, .. }) => assert!(path.ends_with("does_not_exist.mtl")),
+            _ => panic!("expected an io error"),
+        }
+    }
+
+    #[test]
+    fn mtl_parse() {
+        let src = "newmtl red\nKd 0.8 0.1 0.1\n\nnewmtl chrome # comment\nKd 0.1 0.1 0.1\nKs 0.9 0.9 0.9\nNs 200\n\
+                   newmtl glass\nNi 1.45\nd 0.2\nnewmtl lamp\nKe 5 5 4\n";
+        let mats = parse_mtl(src.as_bytes(), Path::new("test.mtl")).unwrap();
+        assert_eq!(mats.len(), 4);
+        assert_eq!(mats[0].name, "red");
+        assert_eq!(mats[0].diffuse.x, 0.8);
+        assert_eq!(mats[1].shininess, 200.0);
+        assert_eq!(mats[2].ior, 1.45);
+        assert_eq!(mats[2].dissolve, 0.2);
+        assert_eq!(mats[3].emission.z, 4.0);
+        assert_eq!(mats[0].emission.x, 0.0);
+    }
+
+    #[test]
+    fn mtl_errors() {
+        match parse_mtl("Kd 1 1 1\n".as_bytes(), Path::new("test.mtl")) {
+            Err(ObjError::Parse { line, .. }) => assert_eq!(line, 1),
+            _ => panic!("expected a parse error"),
+        }
+        match parse_mtl("newmtl a\nKd 1 1\n".as_bytes(), Path::new("test.mtl")) {
+            Err(ObjError::Parse { line, .. }) => assert_eq!(line, 2),
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn obj_with_mtl_file() {
+        let dir = std::env::temp_dir().join(format!("path_tracer_obj_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("quad.mtl"), "newmtl blue\nKd 0.1 0.1 0.9\nnewmtl mirror\nKs 1 1 1\n").unwrap();
+        let obj = format!("mtllib quad.mtl\n{}usemtl blue\nf 1 2 3\nusemtl mirror\nf 1 3 4\n", QUAD.replace("f 1/1/1 2/2/1 3/3/1 4/4/1\n", ""));
+        std::fs::write(dir.join("quad.obj"), obj).unwrap();
+        let world = load_obj(&dir.join("quad.obj")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(world.objects.len(), 2);
+    }
+
+    #[test]
+    fn obj_missing_file() {
+        match load_obj(Path::new("/does/not/exist.obj")) {
+            Err(ObjError::Io { .. }) => {}
+            _ => panic!("expected an io error"),
+        }
+    }
+}
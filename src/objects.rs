@@ -1,19 +1,46 @@
 use crate::pt_math::Vec3;
 use crate::pt_math::Ray;
+use crate::pt_math::Aabb;
 use crate::pt_math;
 use crate::material::Material;
+use crate::light::SphereLight;
 
 
 pub struct HitRecord<'a> {
     pub t: f32,
     pub point: Vec3,
     pub normal: Vec3,
+    pub u: f32, // surface coordinates
+    pub v: f32,
+    pub vertex_color: Option<Vec3>, // interpolated vertex color, for meshes that have them
+    pub object_id: usize, // index of the object in the outermost list (or bvh) containing it
     pub material: &'a dyn Material
 }
 
 // hitable trait
-pub trait Hitable {
+pub trait Hitable: Send + Sync {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>>;
+    // bounding box of the object, or None if the object is unbounded
+    fn bounding_box(&self) -> Option<Aabb>;
+    // emissive objects that can be sampled as light sources
+    fn lights(&self) -> Vec<SphereLight> {
+        Vec::new()
+    }
+}
+
+// allow boxed trait objects to be used wherever a Hitable is expected (e.g. in a Bvh)
+impl<T: Hitable + ?Sized> Hitable for Box<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        (**self).hit(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        (**self).bounding_box()
+    }
+
+    fn lights(&self) -> Vec<SphereLight> {
+        (**self).lights()
+    }
 }
 
 
@@ -30,6 +57,20 @@ impl Sphere {
     }
 }
 
+impl Sphere {
+    fn hit_record(&self, ray: &Ray, t: f32) -> HitRecord<'_> {
+        let point = ray.point_at_parameter(t);
+        let normal = (point-self.center) / self.radius;
+        // spherical coordinates of the point on the unit sphere: u runs around the y axis, v from bottom to top
+        let p = (point-self.center) / self.radius.abs();
+        let phi = p.z.atan2(p.x);
+        let theta = p.y.clamp(-1.0, 1.0).asin();
+        let u = 1.0 - (phi + std::f32::consts::PI) / (2.0*std::f32::consts::PI);
+        let v = (theta + std::f32::consts::FRAC_PI_2) / std::f32::consts::PI;
+        HitRecord{t, point, normal, u, v, vertex_color: None, object_id: 0, material: &*self.material}
+    }
+}
+
 impl Hitable for Sphere {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let oc = ray.origin - self.center;
@@ -38,28 +79,94 @@ impl Hitable for Sphere {
         let c = pt_math::dot(oc, oc) - self.radius*self.radius;
         let discriminant = b*b - a*c; // removed the 2.0 factor from b and the 4.0 here, since the result is the same 
         if discriminant > 0.0 {
+            // try the nearest intersection first, then the far one (ray starting inside the sphere)
             let t = (-b - discriminant.sqrt())/a;
             if t<t_max && t>t_min {
-                let point = ray.point_at_parameter(t);
-                let normal = (point-self.center) / self.radius;
-                return Some(HitRecord{t, point, normal, material: &*self.material});
+                return Some(self.hit_record(ray, t));
             }
-        } else {
             let t = (-b + discriminant.sqrt())/a;
             if t<t_max && t>t_min {
-                let point = ray.point_at_parameter(t);
-                let normal = (point-self.center) / self.radius;
-                return Some(HitRecord{t, point, normal, material: &*self.material});
+                return Some(self.hit_record(ray, t));
             }
         }
+        None
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // radius can be negative (hollow spheres)
+        let r = Vec3::new(self.radius.abs(), self.radius.abs(), self.radius.abs());
+        Some(Aabb::new(self.center - r, self.center + r))
+    }
+
+    fn lights(&self) -> Vec<SphereLight> {
+        if self.material.is_emissive() {
+            vec![SphereLight::new(self.center, self.radius)]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+// Möller–Trumbore ray/triangle intersection. Returns the ray parameter and the barycentric
+// coordinates of the hit point relative to p1 and p2
+pub fn intersect_triangle(ray: &Ray, p0: Vec3, p1: Vec3, p2: Vec3, t_min: f32, t_max: f32) -> Option<(f32, f32, f32)> {
+    let e1 = p1 - p0;
+    let e2 = p2 - p0;
+    let pvec = pt_math::cross(ray.direction, e2);
+    let det = pt_math::dot(e1, pvec);
+    if det.abs() < 1e-12 {
+        return None; // ray parallel to the triangle, or degenerate triangle
+    }
+    let inv_det = 1.0 / det;
+    let tvec = ray.origin - p0;
+    let b1 = pt_math::dot(tvec, pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let qvec = pt_math::cross(tvec, e1);
+    let b2 = pt_math::dot(ray.direction, qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
         return None;
     }
+    let t = pt_math::dot(e2, qvec) * inv_det;
+    if t<t_max && t>t_min {
+        return Some((t, b1, b2));
+    }
+    None
+}
+
+// a single triangle. The normal follows the winding order of the vertices (counter-clockwise is front)
+pub struct Triangle {
+    vertices: [Vec3; 3],
+    normal: Vec3,
+    material: Box<dyn Material>,
+}
+
+impl Triangle {
+    pub fn new(p0: Vec3, p1: Vec3, p2: Vec3, material: Box<dyn Material>) -> Triangle {
+        let normal = pt_math::unit_vector(pt_math::cross(p1 - p0, p2 - p0));
+        Triangle {vertices: [p0, p1, p2], normal, material}
+    }
+}
+
+impl Hitable for Triangle {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let [p0, p1, p2] = self.vertices;
+        let (t, u, v) = intersect_triangle(ray, p0, p1, p2, t_min, t_max)?;
+        let point = ray.point_at_parameter(t);
+        Some(HitRecord{t, point, normal: self.normal, u, v, vertex_color: None, object_id: 0, material: &*self.material})
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let [p0, p1, p2] = self.vertices;
+        Some(Aabb::new(p0, p0).grow(p1).grow(p2))
+    }
 }
 
 // list of objects that implement the Hitable trait
 
 pub struct HitableList {
-    pub objects: Vec<Sphere> // TODO: use a trait object to make it generic. for now only consider spheres
+    pub objects: Vec<Box<dyn Hitable>>
 }
 
 impl HitableList {
@@ -69,8 +176,8 @@ impl HitableList {
         }
     }
 
-    pub fn add(&mut self, obj: Sphere) {
-        self.objects.push(obj);
+    pub fn add<T: Hitable + 'static>(&mut self, obj: T) {
+        self.objects.push(Box::new(obj));
     }
 }
 
@@ -78,13 +185,29 @@ impl Hitable for HitableList {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let mut hit_record = None;
         let mut closest = t_max;
-        for obj in &self.objects {
-            if let Some(hr) = obj.hit(ray, t_min, closest) {
+        for (id, obj) in self.objects.iter().enumerate() {
+            if let Some(mut hr) = obj.hit(ray, t_min, closest) {
                 closest = hr.t;
+                hr.object_id = id;
                 hit_record = Some(hr);
             }
         }
-        return hit_record;
+        hit_record
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if self.objects.is_empty() {
+            return None;
+        }
+        let mut bbox = Aabb::empty();
+        for obj in &self.objects {
+            bbox = bbox.union(&obj.bounding_box()?);
+        }
+        Some(bbox)
+    }
+
+    fn lights(&self) -> Vec<SphereLight> {
+        self.objects.iter().flat_map(|obj| obj.lights()).collect()
     }
 }
 
@@ -170,6 +293,68 @@ mod tests {
         assert!(hit.is_none());
     }
 
+    #[test]
+    fn sphere_hit_from_inside() {
+        let sphere = Sphere::new(Vec3::new(0.0, 0.0, 0.0), 1.0, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))));
+        // ray starting at the center must hit the far side
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = sphere.hit(&ray, 0.001, 100.0).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-5);
+        assert!(hit.normal.z < -0.99);
+    }
+
+    #[test]
+    fn sphere_hit_uv() {
+        let sphere = Sphere::new(Vec3::new(0.0, 0.0, -2.0), 1.0, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))));
+        // top of the sphere has v=1
+        let ray = Ray::new(Vec3::new(0.0, 5.0, -2.0), Vec3::new(0.0, -1.0, 0.0));
+        let hit = sphere.hit(&ray, 0.0, 100.0).unwrap();
+        assert!((hit.v - 1.0).abs() < 1e-4);
+        // point facing the +z axis lies on the equator, at u=0.25
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = sphere.hit(&ray, 0.0, 100.0).unwrap();
+        assert!((hit.v - 0.5).abs() < 1e-4);
+        assert!((hit.u - 0.25).abs() < 1e-4);
+    }
+
+    #[test]
+    fn triangle_hit() {
+        let tri = Triangle::new(Vec3::new(-1.0, -1.0, -2.0), Vec3::new(1.0, -1.0, -2.0), Vec3::new(0.0, 1.0, -2.0),
+                                Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))));
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = tri.hit(&ray, 0.0, 100.0).unwrap();
+        assert!((hit.t - 2.0).abs() < 1e-5);
+        // counter-clockwise winding as seen from the ray origin: normal points back to the origin
+        assert!((hit.normal.z - 1.0).abs() < 1e-5);
+        // barycentric coordinates are used as surface coordinates
+        assert!((hit.u - 0.25).abs() < 1e-5);
+        assert!((hit.v - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn triangle_miss() {
+        let tri = Triangle::new(Vec3::new(-1.0, -1.0, -2.0), Vec3::new(1.0, -1.0, -2.0), Vec3::new(0.0, 1.0, -2.0),
+                                Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))));
+        // outside the edges
+        let ray = Ray::new(Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(tri.hit(&ray, 0.0, 100.0).is_none());
+        // parallel to the plane of the triangle
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -2.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(tri.hit(&ray, 0.0, 100.0).is_none());
+        // beyond t_max
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(tri.hit(&ray, 0.0, 1.0).is_none());
+    }
+
+    #[test]
+    fn triangle_bounding_box() {
+        let tri = Triangle::new(Vec3::new(-1.0, -1.0, -2.0), Vec3::new(1.0, -1.0, -3.0), Vec3::new(0.0, 1.0, -2.0),
+                                Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))));
+        let bbox = tri.bounding_box().unwrap();
+        assert_eq!((bbox.min.x, bbox.min.y, bbox.min.z), (-1.0, -1.0, -3.0));
+        assert_eq!((bbox.max.x, bbox.max.y, bbox.max.z), (1.0, 1.0, -2.0));
+    }
+
     #[test]
     fn hitable_list_creation() {
         let list = HitableList::new();
@@ -224,6 +409,18 @@ mod tests {
         assert!(hit_record.t < 1.0);
     }
 
+    #[test]
+    fn hitable_list_object_ids() {
+        let mut list = HitableList::new();
+        for i in 0..3 {
+            list.add(Sphere::new(Vec3::new(i as f32 * 2.0, 0.0, -1.0), 0.5, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        }
+        for i in 0..3 {
+            let ray = Ray::new(Vec3::new(i as f32 * 2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+            assert_eq!(list.hit(&ray, 0.0, 100.0).unwrap().object_id, i);
+        }
+    }
+
     #[test]
     fn hitable_list_no_hit() {
         let mut list = HitableList::new();
@@ -242,4 +439,57 @@ mod tests {
         
         assert!(hit.is_none());
     }
+
+    #[test]
+    fn sphere_bounding_box() {
+        let sphere = Sphere::new(Vec3::new(1.0, 2.0, 3.0), 0.5, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))));
+        let bbox = sphere.bounding_box().unwrap();
+        assert_eq!(bbox.min.x, 0.5);
+        assert_eq!(bbox.max.y, 2.5);
+        assert_eq!(bbox.max.z, 3.5);
+
+        // hollow spheres use a negative radius
+        let sphere = Sphere::new(Vec3::new(0.0, 0.0, 0.0), -0.5, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))));
+        let bbox = sphere.bounding_box().unwrap();
+        assert_eq!(bbox.min.x, -0.5);
+        assert_eq!(bbox.max.x, 0.5);
+    }
+
+    #[test]
+    fn hitable_list_bounding_box() {
+        let mut list = HitableList::new();
+        assert!(list.bounding_box().is_none());
+        list.add(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 1.0, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        list.add(Sphere::new(Vec3::new(4.0, 0.0, 0.0), 1.0, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        let bbox = list.bounding_box().unwrap();
+        assert_eq!(bbox.min.x, -1.0);
+        assert_eq!(bbox.max.x, 5.0);
+        assert_eq!(bbox.max.y, 1.0);
+    }
+
+    #[test]
+    fn hitable_list_nested() {
+        // lists can contain other lists, mixed with other primitives
+        let mut inner = HitableList::new();
+        inner.add(Sphere::new(Vec3::new(0.0, 0.0, -2.0), 0.3, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        let mut list = HitableList::new();
+        list.add(Sphere::new(Vec3::new(5.0, 0.0, -1.0), 0.3, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        list.add(inner);
+
+        assert_eq!(list.objects.len(), 2);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = list.hit(&ray, 0.0, 100.0).unwrap();
+        assert!((hit.t - 1.7).abs() < 1e-5);
+        let bbox = list.bounding_box().unwrap();
+        assert_eq!(bbox.max.x, 5.3);
+        assert_eq!(bbox.min.z, -2.3);
+    }
+
+    #[test]
+    fn boxed_hitable() {
+        let obj: Box<dyn Hitable> = Box::new(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))));
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(obj.hit(&ray, 0.0, 100.0).is_some());
+        assert!(obj.bounding_box().is_some());
+    }
 }
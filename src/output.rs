@@ -0,0 +1,455 @@
+use crate::pt_math::Vec3;
+use crate::color::ColorSpace;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// image file formats, chosen from the extension of the output file. Png is for display, the others store the
+// linear radiance, without clamping
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum OutputFormat {
+    Png(PngDepth),
+    Exr(ExrPrecision),
+    Pfm,
+    Hdr,
+}
+
+// bits per channel of the png files. 16 bits avoid the banding of smooth gradients
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum PngDepth {
+    Eight,
+    Sixteen,
+}
+
+impl PngDepth {
+    pub fn from_bits(bits: u32) -> Option<PngDepth> {
+        match bits {
+            8 => Some(PngDepth::Eight),
+            16 => Some(PngDepth::Sixteen),
+            _ => None,
+        }
+    }
+}
+
+// type of the channels of the exr files
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum ExrPrecision {
+    Half,
+    Float,
+}
+
+impl ExrPrecision {
+    pub fn from_name(name: &str) -> Option<ExrPrecision> {
+        match name {
+            "half" => Some(ExrPrecision::Half),
+            "float" => Some(ExrPrecision::Float),
+            _ => None,
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &Path, png_depth: PngDepth, exr_precision: ExrPrecision) -> Option<OutputFormat> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+        match extension.as_str() {
+            "png" => Some(OutputFormat::Png(png_depth)),
+            "exr" => Some(OutputFormat::Exr(exr_precision)),
+            "pfm" => Some(OutputFormat::Pfm),
+            "hdr" => Some(OutputFormat::Hdr),
+            _ => None,
+        }
+    }
+
+    // formats that can store an alpha channel
+    pub fn has_alpha(self) -> bool {
+        matches!(self, OutputFormat::Png(_) | OutputFormat::Exr(_))
+    }
+}
+
+#[derive(Debug)]
+pub enum OutputError {
+    Io { path: PathBuf, error: std::io::Error },
+    Encode { path: PathBuf, message: String },
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            OutputError::Encode { path, message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for OutputError {}
+
+fn create_file(path: &Path) -> Result<std::io::BufWriter<std::fs::File>, OutputError> {
+    let file = std::fs::File::create(path).map_err(|error| OutputError::Io { path: path.to_path_buf(), error })?;
+    Ok(std::io::BufWriter::new(file))
+}
+
+// all the writers take the pixels in rows from the top
+
+// rgb or rgba png, with the colors already encoded for display in the given color space (in [0,1]). The color
+// space is stored in the file: as a sRGB chunk for sRGB, and as an embedded ICC profile for the others. The
+// chromaticities are there for the older readers
+pub fn write_png(path: &Path, width: u32, height: u32, colors: &[Vec3], alpha: Option<&[f32]>, depth: PngDepth,
+                 color_space: ColorSpace) -> Result<(), OutputError> {
+    let encode_error = |e: png::EncodingError| OutputError::Encode { path: path.to_path_buf(), message: e.to_string() };
+    let mut encoder = png::Encoder::new(create_file(path)?, width, height);
+    encoder.set_color(if alpha.is_some() { png::ColorType::RGBA } else { png::ColorType::RGB });
+    encoder.set_depth(match depth {
+        PngDepth::Eight => png::BitDepth::Eight,
+        PngDepth::Sixteen => png::BitDepth::Sixteen,
+    });
+    let mut writer = encoder.write_header().map_err(encode_error)?;
+    writer.write_chunk(*b"cHRM", &color_space.png_chromaticities()).map_err(encode_error)?;
+    if color_space == ColorSpace::Srgb {
+        writer.write_chunk(*b"gAMA", &45455u32.to_be_bytes()).map_err(encode_error)?;
+        writer.write_chunk(*b"sRGB", &[0]).map_err(encode_error)?; // perceptual rendering intent
+    } else {
+        // profile name, compression method (zlib) and compressed profile
+        let mut iccp = b"ICC profile\0\0".to_vec();
+        iccp.extend_from_slice(&zlib_stored(&color_space.icc_profile()));
+        writer.write_chunk(*b"iCCP", &iccp).map_err(encode_error)?;
+    }
+    // samples interleaved by pixel, 16 bit ones in big endian order
+    let mut samples = Vec::with_capacity(colors.len()*4);
+    for (k, c) in colors.iter().enumerate() {
+        samples.extend_from_slice(&[c.x, c.y, c.z]);
+        if let Some(alpha) = alpha {
+            samples.push(alpha[k]);
+        }
+    }
+    let data: Vec<u8> = match depth {
+        PngDepth::Eight => samples.iter().map(|&v| (v.clamp(0.0, 1.0)*255.0).round() as u8).collect(),
+        PngDepth::Sixteen => samples.iter().flat_map(|&v| ((v.clamp(0.0, 1.0)*65535.0).round() as u16).to_be_bytes().to_vec()).collect(),
+    };
+    writer.write_image_data(&data).map_err(encode_error)
+}
+
+// zlib stream of uncompressed deflate blocks. The profiles are small, and this avoids a compression library
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = if data.is_empty() { vec![data] } else { data.chunks(0xffff).collect() };
+    for (i, block) in blocks.iter().enumerate() {
+        out.push((i + 1 == blocks.len()) as u8);
+        out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    // adler-32 checksum
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    out.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    out
+}
+
+// samples of a channel of the exr files
+pub enum ChannelData {
+    Float(Vec<f32>), // stored with the precision of the file
+    Uint(Vec<u32>),
+}
+
+// channels with the components of the vectors, named by prefix and component. Multi-layer files use the name of
+// the layer as prefix ("albedo.R"); the color of the image has none
+pub fn vector_channels(prefix: &str, names: &[&str], pixels: &[Vec3]) -> Vec<(String, ChannelData)> {
+    names.iter().enumerate()
+         .map(|(k, name)| (format!("{}{}", prefix, name), ChannelData::Float(pixels.iter().map(|p| p[k]).collect())))
+         .collect()
+}
+
+// OpenEXR with the given channels, usually R, G, B and optionally A, compressed with zip. As usual for exr, the
+// colors are premultiplied by the alpha
+pub fn write_exr(path: &Path, width: u32, height: u32, channels: Vec<(String, ChannelData)>,
+                 precision: ExrPrecision) -> Result<(), OutputError> {
+    use exr::prelude::*;
+    let channels: Vec<AnyChannel<FlatSamples>> = channels.into_iter().map(|(name, data)| {
+        let samples = match (data, precision) {
+            (ChannelData::Float(values), ExrPrecision::Half) => FlatSamples::F16(values.into_iter().map(f16::from_f32).collect()),
+            (ChannelData::Float(values), ExrPrecision::Float) => FlatSamples::F32(values),
+            (ChannelData::Uint(values), _) => FlatSamples::U32(values),
+        };
+        AnyChannel::new(name.as_str(), samples)
+    }).collect();
+    let layer = Layer::new((width as usize, height as usize), LayerAttributes::default(), Encoding::SMALL_LOSSLESS,
+                           AnyChannels::sort(channels.into()));
+    Image::from_layer(layer).write().to_file(path)
+        .map_err(|e| OutputError::Encode { path: path.to_path_buf(), message: e.to_string() })
+}
+
+// portable float map: 32 bit floats, little endian (negative scale), with the rows from the bottom
+pub fn write_pfm(path: &Path, width: u32, height: u32, pixels: &[Vec3]) -> Result<(), OutputError> {
+    let io_error = |error| OutputError::Io { path: path.to_path_buf(), error };
+    let mut w = create_file(path)?;
+    write!(w, "PF\n{} {}\n-1.0\n", width, height).map_err(io_error)?;
+    for row in pixels.chunks(width as usize).rev() {
+        for p in row {
+            for c in &[p.x, p.y, p.z] {
+                w.write_all(&c.to_le_bytes()).map_err(io_error)?;
+            }
+        }
+    }
+    w.flush().map_err(io_error)
+}
+
+// radiance rgbe, with run length encoded scanlines
+pub fn write_hdr(path: &Path, width: u32, height: u32, pixels: &[Vec3]) -> Result<(), OutputError> {
+    let io_error = |error| OutputError::Io { path: path.to_path_buf(), error };
+    let mut w = create_file(path)?;
+    write!(w, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width).map_err(io_error)?;
+    let mut buf = Vec::new();
+    for row in pixels.chunks(width as usize) {
+        let rgbe: Vec<[u8; 4]> = row.iter().map(|&p| rgb_to_rgbe(p)).collect();
+        buf.clear();
+        if (8..0x8000).contains(&width) {
+            buf.extend_from_slice(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8]);
+            for c in 0..4 {
+                let component: Vec<u8> = rgbe.iter().map(|p| p[c]).collect();
+                encode_runs(&component, &mut buf);
+            }
+        } else {
+            // too narrow or too wide to be run length encoded
+            rgbe.iter().for_each(|p| buf.extend_from_slice(p));
+        }
+        w.write_all(&buf).map_err(io_error)?;
+    }
+    w.flush().map_err(io_error)
+}
+
+// shared exponent encoding of the radiance format: the mantissas of the three channels share the exponent
+// of the largest one. Negative values are clamped to zero
+fn rgb_to_rgbe(p: Vec3) -> [u8; 4] {
+    let (r, g, b) = (p.x.max(0.0), p.y.max(0.0), p.z.max(0.0));
+    let v = r.max(g).max(b);
+    if v < 1e-32 || !v.is_finite() {
+        return [0, 0, 0, 0];
+    }
+    // v = m*2^e, with m in [0.5,1)
+    let mut e = v.log2().floor() as i32 + 1;
+    if v / 2.0f32.powi(e) >= 1.0 {
+        e += 1;
+    } else if v / 2.0f32.powi(e) < 0.5 {
+        e -= 1;
+    }
+    let scale = 256.0 / 2.0f32.powi(e);
+    [(r*scale) as u8, (g*scale) as u8, (b*scale) as u8, (e + 128) as u8]
+}
+
+// run length encoding of one component of a scanline: runs of at least 3 equal values are stored as a count
+// above 128 followed by the value, everything else as a count up to 128 followed by the literal values
+fn encode_runs(data: &[u8], out: &mut Vec<u8>) {
+    const MIN_RUN: usize = 3;
+    let mut i = 0;
+    while i < data.len() {
+        // find the next run
+        let mut run_start = i;
+        let mut run_length = 0;
+        while run_start < data.len() {
+            run_length = data[run_start..].iter().take(127).take_while(|&&b| b == data[run_start]).count();
+            if run_length >= MIN_RUN {
+                break;
+            }
+            run_start += run_length;
+        }
+        if run_length < MIN_RUN {
+            run_start = data.len();
+        }
+        // literal values before it
+        while i < run_start {
+            let count = (run_start - i).min(128);
+            out.push(count as u8);
+            out.extend_from_slice(&data[i..i + count]);
+            i += count;
+        }
+        if run_start < data.len() {
+            out.extend_from_slice(&[128 + run_length as u8, data[run_start]]);
+            i = run_start + run_length;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::{Texture, ImageTexture, WrapMode, Filter};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("path_tracer_output_test_{}_{}", std::process::id(), name))
+    }
+
+    // 10x3 image with values above 1 and runs of equal pixels
+    fn test_pixels() -> Vec<Vec3> {
+        (0..30).map(|i| if i % 10 < 6 { Vec3::new(0.25, 0.5, 1.0) } else { Vec3::new(i as f32, 0.0, 1000.0) }).collect()
+    }
+
+    #[test]
+    fn output_format() {
+        let format = |name: &str| OutputFormat::from_path(Path::new(name), PngDepth::Sixteen, ExrPrecision::Float);
+        assert_eq!(format("image.png"), Some(OutputFormat::Png(PngDepth::Sixteen)));
+        assert_eq!(format("image.EXR"), Some(OutputFormat::Exr(ExrPrecision::Float)));
+        assert_eq!(format("image.pfm"), Some(OutputFormat::Pfm));
+        assert_eq!(format("image.hdr"), Some(OutputFormat::Hdr));
+        assert_eq!(format("image.tga"), None);
+        assert_eq!(ExrPrecision::from_name("half"), Some(ExrPrecision::Half));
+        assert_eq!(ExrPrecision::from_name("double"), None);
+        assert_eq!(PngDepth::from_bits(16), Some(PngDepth::Sixteen));
+        assert_eq!(PngDepth::from_bits(12), None);
+        assert!(format("image.exr").unwrap().has_alpha() && !format("image.hdr").unwrap().has_alpha());
+    }
+
+    #[test]
+    fn rgbe_encoding() {
+        assert_eq!(rgb_to_rgbe(Vec3::new(1.0, 0.5, 0.0)), [128, 64, 0, 129]);
+        assert_eq!(rgb_to_rgbe(Vec3::new(0.0, 0.0, 0.0)), [0, 0, 0, 0]);
+        assert_eq!(rgb_to_rgbe(Vec3::new(-1.0, 0.0, 0.0)), [0, 0, 0, 0]);
+        let [r, g, b, e] = rgb_to_rgbe(Vec3::new(1000.0, 3.0, 0.001));
+        assert_eq!((r, e), (250, 138));
+        assert!(g == 0 && b == 0);
+    }
+
+    #[test]
+    fn run_length_encoding() {
+        let mut out = Vec::new();
+        encode_runs(&[1, 2, 3, 3, 3, 3, 4, 5], &mut out);
+        assert_eq!(out, vec![2, 1, 2, 128 + 4, 3, 2, 4, 5]);
+        let mut out = Vec::new();
+        encode_runs(&[7; 300], &mut out);
+        assert_eq!(out, vec![128 + 127, 7, 128 + 127, 7, 128 + 46, 7]);
+    }
+
+    // chunk types of a png file, in order
+    fn png_chunks(bytes: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut chunks = Vec::new();
+        let mut pos = 8;
+        while pos < bytes.len() {
+            let length = u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]) as usize;
+            let name = String::from_utf8_lossy(&bytes[pos + 4..pos + 8]).to_string();
+            chunks.push((name, bytes[pos + 8..pos + 8 + length].to_vec()));
+            pos += 12 + length;
+        }
+        chunks
+    }
+
+    #[test]
+    fn zlib_stream() {
+        // known adler-32 checksum, and blocks of at most 64k
+        let out = zlib_stored(b"Wikipedia");
+        assert_eq!(out[out.len() - 4..], 0x11e60398u32.to_be_bytes());
+        assert_eq!(out[2..7], [1, 9, 0, 0xf6, 0xff]);
+        let out = zlib_stored(&vec![0; 70000]);
+        assert_eq!(out.len(), 2 + 5 + 0xffff + 5 + (70000 - 0xffff) + 4);
+        assert_eq!(out[2], 0);
+    }
+
+    #[test]
+    fn write_png_color_space() {
+        let path = temp_path("image.png");
+        let colors = [Vec3::new(1.0, 0.0, 0.0), Vec3::new(10.0/255.0, 20.0/255.0, 30.0/255.0)];
+        let rgb = [255, 0, 0, 10, 20, 30];
+        for &space in &[ColorSpace::Srgb, ColorSpace::DisplayP3, ColorSpace::Rec2020] {
+            write_png(&path, 2, 1, &colors, None, PngDepth::Eight, space).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            let chunks = png_chunks(&bytes);
+            let names: Vec<&str> = chunks.iter().map(|(name, _)| name.as_str()).collect();
+            let idat = names.iter().position(|&n| n == "IDAT").unwrap();
+            assert!(names[..idat].contains(&"cHRM"));
+            if space == ColorSpace::Srgb {
+                assert!(names[..idat].contains(&"sRGB") && !names.contains(&"iCCP"));
+            } else {
+                // the embedded profile, in a single uncompressed block
+                let (_, iccp) = chunks.iter().find(|(name, _)| name == "iCCP").unwrap();
+                let start = iccp.iter().position(|&b| b == 0).unwrap() + 2;
+                let profile = space.icc_profile();
+                assert_eq!(iccp[start..start + 3], [0x78, 0x01, 1]);
+                assert_eq!(iccp[start + 7..iccp.len() - 4], profile[..]);
+                assert!(!names.contains(&"sRGB"));
+            }
+            // and the pixels are unchanged
+            let decoder = png::Decoder::new(std::fs::File::open(&path).unwrap());
+            let (info, mut reader) = decoder.read_info().unwrap();
+            let mut buf = vec![0; info.buffer_size()];
+            reader.next_frame(&mut buf).unwrap();
+            assert_eq!(buf, rgb.to_vec());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_png_16_bit_alpha() {
+        let path = temp_path("image16.png");
+        let colors = [Vec3::new(1.0, 0.5, 0.0), Vec3::new(2.0, -1.0, 0.25)];
+        write_png(&path, 2, 1, &colors, Some(&[1.0, 0.0]), PngDepth::Sixteen, ColorSpace::Srgb).unwrap();
+        let mut decoder = png::Decoder::new(std::fs::File::open(&path).unwrap());
+        // keep the 16 bit samples, which are stripped by default
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut buf = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((info.color_type, info.bit_depth), (png::ColorType::RGBA, png::BitDepth::Sixteen));
+        let samples: Vec<u16> = buf.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect();
+        // rounded to the nearest value, and clamped
+        assert_eq!(samples, vec![65535, 32768, 0, 65535, 65535, 0, 16384, 0]);
+    }
+
+    #[test]
+    fn write_hdr_file() {
+        // read back with the texture loader
+        let path = temp_path("image.hdr");
+        let pixels = test_pixels();
+        write_hdr(&path, 10, 3, &pixels).unwrap();
+        let image = ImageTexture::load(&path, false).unwrap().with_sampling(WrapMode::Clamp, Filter::Nearest);
+        std::fs::remove_file(&path).unwrap();
+        for (k, p) in pixels.iter().enumerate() {
+            let (i, j) = (k % 10, k / 10);
+            let v = image.value((i as f32 + 0.5) / 10.0, 1.0 - (j as f32 + 0.5) / 3.0, Vec3::new(0.0, 0.0, 0.0));
+            assert!((v.x - p.x).abs() <= p.z / 128.0 && (v.z - p.z).abs() <= p.z / 128.0, "{} {:?} {:?}", k, v, p);
+        }
+    }
+
+    #[test]
+    fn write_pfm_file() {
+        let path = temp_path("image.pfm");
+        let pixels = test_pixels();
+        write_pfm(&path, 10, 3, &pixels).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let header = b"PF\n10 3\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(bytes.len(), header.len() + 30*12);
+        // the first pixel in the file is the bottom left one
+        let first = f32::from_le_bytes([bytes[header.len()], bytes[header.len() + 1], bytes[header.len() + 2], bytes[header.len() + 3]]);
+        assert_eq!(first, pixels[20].x);
+    }
+
+    #[test]
+    fn write_exr_file() {
+        let pixels = test_pixels();
+        for &precision in &[ExrPrecision::Half, ExrPrecision::Float] {
+            let path = temp_path("image.exr");
+            let mut channels = vector_channels("", &["R", "G", "B"], &pixels);
+            channels.push((String::from("A"), ChannelData::Float((0..30).map(|i| i as f32 / 32.0).collect())));
+            channels.push((String::from("layer.id"), ChannelData::Uint((0..30).collect())));
+            write_exr(&path, 10, 3, channels, precision).unwrap();
+            let image = exr::prelude::read_first_flat_layer_from_file(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let channels = &image.layer_data.channel_data.list;
+            assert_eq!(channels.iter().map(|c| c.name.to_string()).collect::<Vec<_>>(), vec!["A", "B", "G", "R", "layer.id"]);
+            let red: Vec<f32> = channels[3].sample_data.values_as_f32().collect();
+            let blue: Vec<f32> = channels[1].sample_data.values_as_f32().collect();
+            let alpha: Vec<f32> = channels[0].sample_data.values_as_f32().collect();
+            assert_eq!(alpha[8], 0.25);
+            assert_eq!(red[9], 9.0);
+            assert_eq!(blue[29], 1000.0);
+            assert_eq!(red[0], 0.25);
+            // integer channels are kept as they are
+            match &channels[4].sample_data {
+                exr::prelude::FlatSamples::U32(ids) => assert_eq!(ids[17], 17),
+                _ => panic!("the id channel should have integer samples"),
+            }
+        }
+    }
+}
@@ -1,16 +1,31 @@
-use crate::pt_math::{Vec3, Ray, mul_component, unit_vector};
+use crate::pt_math::{Vec3, seed_rng, random_f32};
 use crate::objects::{Hitable, Sphere, HitableList};
 use crate::camera::Camera;
 use crate::material::{Lambertian, Metal, Dielectric};
+use crate::light::SphereLight;
+use crate::integrator::{Integrator, PathTracer, DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH};
+use crate::output::{self, OutputFormat, OutputError, ExrPrecision};
+use crate::aov::{Aov, AovSample};
+use crate::tonemap::ToneMapping;
+use crate::color::ColorSpace;
 
 use rand::Rng;
 use rand::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::path::Path;
 
 pub struct Image {
     pub width: u32,
     pub height: u32,
     pub data: Vec<Vec3>,
     pub samples: u32,
+    pub tone_mapping: ToneMapping, // used for display: preview and png
+    pub color_space: ColorSpace,   // of the displayed colors
+    // number of samples whose camera ray hit the scene, for the alpha channel. The background is then
+    // transparent: the camera rays missing the scene don't add the sky to the image, only to the passes
+    pub coverage: Option<Vec<f32>>,
+    pub aovs: Vec<(Aov, Vec<Vec3>)>, // render passes, accumulated like the image data
 }
 
 impl Image {
@@ -18,74 +33,104 @@ impl Image {
         let mut data: Vec<Vec3> = Vec::new();
         data.resize((width*height) as usize, Vec3::new(0.0,0.0,0.0));
         Image {
-            width: width,
-            height: height,
-            data: data,
-            samples: 0
+            width,
+            height,
+            data,
+            samples: 0,
+            tone_mapping: ToneMapping::default(),
+            color_space: ColorSpace::Srgb,
+            coverage: None,
+            aovs: Vec::new(),
         }
     }
 
-    pub fn save(&self, filename: &str) {
-        let file = std::fs::File::create(filename).unwrap();
-        let ref mut w = std::io::BufWriter::new(file);
-
-        let mut encoder = png::Encoder::new(w, self.width, self.height);
-        encoder.set_color(png::ColorType::RGB);
-        encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-        // image data rows start from top. need to swap lines
-        let mut raw_data: Vec<u8> = vec![0; (self.width*self.height*3) as usize];
-        for i in 0..self.data.len() {
-            let row = i as u32 / self.width;
-            let col = i as u32 % self.width;
-            let idx = (self.height-row-1)*self.width + col;
-            let idx = idx as usize;
-            let v = self.val(i);
-            raw_data[3*idx+0] = (v.x*255.99) as u8;
-            raw_data[3*idx+1] = (v.y*255.99) as u8;
-            raw_data[3*idx+2] = (v.z*255.99) as u8;
+    pub fn with_alpha(self) -> Image {
+        let coverage = Some(vec![0.0; self.data.len()]);
+        Image { coverage, ..self }
+    }
+
+    pub fn with_aovs(self, aovs: &[Aov]) -> Image {
+        let aovs = aovs.iter().map(|&aov| (aov, vec![aov.empty(); self.data.len()])).collect();
+        Image { aovs, ..self }
+    }
+
+    pub fn save(&self, path: &Path, format: OutputFormat) -> Result<(), OutputError> {
+        match format {
+            OutputFormat::Png(depth) => {
+                // image data rows start from top. need to swap lines
+                let colors: Vec<Vec3> = (0..self.height).rev()
+                                        .flat_map(|j| (0..self.width).map(move |i| self.val((j*self.width + i) as usize)))
+                                        .collect();
+                output::write_png(path, self.width, self.height, &colors, self.alpha().as_deref(), depth, self.color_space)
+            }
+            OutputFormat::Exr(precision) => {
+                // the render passes are layers of the same file
+                let mut channels = output::vector_channels("", &["R", "G", "B"], &self.radiance());
+                if let Some(alpha) = self.alpha() {
+                    channels.push((String::from("A"), output::ChannelData::Float(alpha)));
+                }
+                for (k, (aov, _)) in self.aovs.iter().enumerate() {
+                    channels.extend(aov.exr_channels(&format!("{}.", aov.name()), &self.aov_pixels(k)));
+                }
+                output::write_exr(path, self.width, self.height, channels, precision)
+            }
+            OutputFormat::Pfm => output::write_pfm(path, self.width, self.height, &self.radiance()),
+            OutputFormat::Hdr => output::write_hdr(path, self.width, self.height, &self.radiance()),
         }
-        writer.write_image_data(&raw_data).unwrap(); // Save
     }
-    
+
+    // average radiance of the pixels, in rows from the top
+    pub fn radiance(&self) -> Vec<Vec3> {
+        let samples = self.samples.max(1) as f32;
+        self.data.chunks(self.width as usize).rev().flatten().map(|&c| c/samples).collect()
+    }
+
+    // render passes as separate exr files next to the image, named after it: image.albedo.exr, ...
+    pub fn save_aovs(&self, path: &Path, precision: ExrPrecision) -> Result<(), OutputError> {
+        for (k, (aov, _)) in self.aovs.iter().enumerate() {
+            let channels = aov.exr_channels("", &self.aov_pixels(k));
+            output::write_exr(&path.with_extension(format!("{}.exr", aov.name())), self.width, self.height, channels, precision)?;
+        }
+        Ok(())
+    }
+
+    // values of the k-th render pass, averaged over the samples if the pass is, in rows from the top
+    pub fn aov_pixels(&self, k: usize) -> Vec<Vec3> {
+        let (aov, data) = &self.aovs[k];
+        let samples = if aov.is_averaged() { self.samples.max(1) as f32 } else { 1.0 };
+        data.chunks(self.width as usize).rev().flatten().map(|&v| v/samples).collect()
+    }
+
+    // fraction of the samples of each pixel that hit the scene, in rows from the top
+    pub fn alpha(&self) -> Option<Vec<f32>> {
+        let samples = self.samples.max(1) as f32;
+        self.coverage.as_ref().map(|coverage| coverage.chunks(self.width as usize).rev().flatten().map(|&n| n/samples).collect())
+    }
+
     pub fn val(&self, i: usize) -> Vec3 {
-        let col = self.data[i]/(self.samples as f32);
-        // gamma correct using "gamma 2"
-        Vec3::new(col.x.sqrt(), col.y.sqrt(), col.z.sqrt())
+        // colors are not premultiplied: with an alpha channel, average only the samples that hit the scene
+        let samples = match &self.coverage {
+            Some(coverage) if coverage[i] > 0.0 => coverage[i],
+            _ => self.samples.max(1) as f32,
+        };
+        let col = self.tone_mapping.apply(self.data[i]/samples);
+        // encode for display with the transfer function of the output color space
+        self.color_space.encode_color(col)
     }
 
+    #[cfg_attr(not(feature = "preview"), allow(dead_code))] // used by the preview window
     pub fn val_rgb(&self, i: u32, j: u32) -> (u8, u8, u8) {
         let idx = ((self.height-j-1)*self.width + i) as usize;
         let v = self.val(idx);
-        let r = (v.x*255.99) as u8;
-        let g = (v.y*255.99) as u8;
-        let b = (v.z*255.99) as u8;
+        let r = (v.x*255.0).round() as u8;
+        let g = (v.y*255.0).round() as u8;
+        let b = (v.z*255.0).round() as u8;
         (r,g,b)
         // let rgba: u32 = 0xff << 24 | r << 16 | g << 8 | b;
         // return rgba;
     }
 }
 
-fn color<T: Hitable>(ray: Ray, world: &T, depth: i32) -> Vec3 {
-    const MIN_DIST : f32 = 0.0001; //10.0 * std::f32::MIN_POSITIVE;
-    const MAX_ITX: i32 = 50;
-    let max_dist = 1000000.0;
-    if let Some(hitrecord) = world.hit(&ray, MIN_DIST, max_dist) {
-        if depth >= MAX_ITX {
-            return Vec3::new(0.0,0.0,0.0);
-        }
-        if let Some(scatter) = hitrecord.material.scatter(ray, hitrecord.point, hitrecord.normal) {
-            return mul_component(scatter.color, color(scatter.ray, world, depth+1));
-        } else {
-            return Vec3::new(0.0,0.0,0.0);
-        }
-    } else {
-        let unit_dir = unit_vector(ray.direction);
-        let t = 0.5 * (unit_dir.y + 1.0);
-        Vec3::new(1.0,1.0,1.0)*(1.0-t) + Vec3::new(0.5,0.7,1.0)*t
-    }
-}
-
 #[allow(dead_code)]
 pub fn create_test_scene() -> HitableList {
     let mut world = HitableList::new();
@@ -134,34 +179,165 @@ pub fn create_book_scene() -> HitableList {
     world
 }
 
-#[allow(dead_code)]
-pub fn render(world: &HitableList, camera: &Camera, image: &mut Image, samples: u32) {
-    // fill image
-    for _s in 0..samples {
-        render_step(world, camera, image);
+// side of the square tiles the image is split into for rendering
+pub const TILE_SIZE: u32 = 32;
+
+// a rectangular region of the image with its own pixel data, rendered by a single thread
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<Vec3>,
+    pub coverage: Vec<f32>,
+    pub aovs: Vec<Vec<Vec3>>,
+}
+
+impl Tile {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Tile {
+        Tile { x, y, width, height, data: vec![Vec3::new(0.0,0.0,0.0); (width*height) as usize], coverage: vec![0.0; (width*height) as usize],
+               aovs: Vec::new() }
     }
 }
 
-pub fn render_step(world: &HitableList, camera: &Camera, image: &mut Image) {
-    // fill image
-    let mut rng = rand::thread_rng();
-    for j in 0..image.height {
-        for i in 0..image.width {
-            let u = (i as f32 + rng.gen::<f32>()) / (image.width as f32);
-            let v = (j as f32 + rng.gen::<f32>()) / (image.height as f32);
-            let ray = camera.get_ray(u,v);
-            let mut col = Vec3::new(0.0,0.0,0.0);
-            col = col + color(ray, world, 0);
-            // accumulate color in the image data
-            image.data[(j*image.width+i) as usize] = image.data[(j*image.width+i) as usize] + col;
+// split the image in tiles, starting from the top rows (image data starts from the bottom)
+fn image_tiles(width: u32, height: u32) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for ty in (0..height.div_ceil(TILE_SIZE)).rev() {
+        for tx in 0..width.div_ceil(TILE_SIZE) {
+            let x = tx*TILE_SIZE;
+            let y = ty*TILE_SIZE;
+            tiles.push(Tile::new(x, y, TILE_SIZE.min(width-x), TILE_SIZE.min(height-y)));
         }
     }
-    image.samples += 1;
+    tiles
+}
+
+// everything the worker threads need to render a tile
+struct TileRenderer<'a> {
+    world: &'a dyn Hitable,
+    lights: Vec<SphereLight>,
+    integrator: &'a dyn Integrator,
+    camera: &'a Camera,
+    image_size: (u32, u32),
+    samples: std::ops::Range<u32>,
+    seed: u64,
+    alpha: bool,
+    aovs: Vec<Aov>,
+}
+
+impl TileRenderer<'_> {
+    // accumulate the samples in the given range for every pixel of the tile. Each sample uses its own random
+    // stream, so the result doesn't depend on which thread renders the tile
+    fn render_tile(&self, tile: &mut Tile) {
+        let (image_width, image_height) = self.image_size;
+        tile.aovs = self.aovs.iter().map(|aov| vec![aov.empty(); tile.data.len()]).collect();
+        for j in 0..tile.height {
+            for i in 0..tile.width {
+                let pixel = ((tile.y+j)*image_width + tile.x+i) as u64;
+                let mut col = Vec3::new(0.0,0.0,0.0);
+                let mut covered = 0.0;
+                let mut passes: Vec<Vec3> = self.aovs.iter().map(|aov| aov.empty()).collect();
+                for s in self.samples.clone() {
+                    seed_rng(self.seed, pixel, s as u64);
+                    let u = ((tile.x+i) as f32 + random_f32()) / (image_width as f32);
+                    let v = ((tile.y+j) as f32 + random_f32()) / (image_height as f32);
+                    let ray = self.camera.get_ray(u,v);
+                    if !self.alpha && self.aovs.is_empty() {
+                        col = col + self.integrator.radiance(ray, self.world, &self.lights);
+                        continue;
+                    }
+                    // the first hit recorded by the integrator tells if the sample covers the pixel
+                    let mut sample = AovSample::default();
+                    let radiance = self.integrator.radiance_aovs(ray, self.world, &self.lights, &mut sample);
+                    if !self.alpha || sample.depth.is_finite() {
+                        col = col + radiance;
+                        covered += 1.0;
+                    }
+                    // the passes keep the missed samples: with an alpha channel the light passes add up to the
+                    // image composited over the background, that stays in the emission pass
+                    for (pass, aov) in passes.iter_mut().zip(&self.aovs) {
+                        *pass = aov.accumulate(*pass, aov.value(&sample));
+                    }
+                }
+                tile.data[(j*tile.width+i) as usize] = col;
+                tile.coverage[(j*tile.width+i) as usize] = covered;
+                for (values, pass) in tile.aovs.iter_mut().zip(passes) {
+                    values[(j*tile.width+i) as usize] = pass;
+                }
+            }
+        }
+    }
+}
+
+// render the given number of samples per pixel, using `threads` worker threads pulling tiles from a shared queue.
+// Finished tiles are merged into the image on the calling thread, that then invokes `on_tile`; rendering stops
+// early (leaving the remaining tiles untouched) if it returns false.
+// Returns false if rendering was stopped: the samples are still counted, so the finished tiles are right and the
+// remaining ones are darker. The same seed always gives the same image, independently of the number of threads
+#[allow(clippy::too_many_arguments)]
+pub fn render<F>(world: &dyn Hitable, integrator: &dyn Integrator, camera: &Camera, image: &mut Image,
+                 samples: u32, seed: u64, threads: usize, mut on_tile: F) -> bool
+where F: FnMut(&Image, &Tile) -> bool {
+    let tiles = std::sync::Mutex::new(image_tiles(image.width, image.height).into_iter());
+    let cancelled = AtomicBool::new(false);
+    let width = image.width;
+    // keep counting samples from the previous calls, to get new random streams
+    let renderer = TileRenderer { world, lights: world.lights(), integrator, camera, image_size: (image.width, image.height),
+                                  samples: image.samples..image.samples+samples, seed, alpha: image.coverage.is_some(),
+                                  aovs: image.aovs.iter().map(|(aov, _)| *aov).collect() };
+    // count the new samples before merging the tiles, so `on_tile` sees the finished ones with the right average
+    image.samples += samples;
+    std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::sync_channel::<Tile>(threads);
+        for _ in 0..threads.max(1) {
+            let sender = sender.clone();
+            let (tiles, cancelled, renderer) = (&tiles, &cancelled, &renderer);
+            scope.spawn(move || {
+                while !cancelled.load(Ordering::Relaxed) {
+                    let next = tiles.lock().unwrap().next();
+                    let Some(mut tile) = next else { break };
+                    renderer.render_tile(&mut tile);
+                    if sender.send(tile).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+        // merge the tiles as they come, until all the workers are done
+        for tile in receiver {
+            for j in 0..tile.height {
+                for i in 0..tile.width {
+                    let idx = ((tile.y+j)*width + tile.x+i) as usize;
+                    image.data[idx] = image.data[idx] + tile.data[(j*tile.width+i) as usize];
+                    if let Some(coverage) = image.coverage.as_mut() {
+                        coverage[idx] += tile.coverage[(j*tile.width+i) as usize];
+                    }
+                    for ((aov, data), values) in image.aovs.iter_mut().zip(&tile.aovs) {
+                        data[idx] = aov.accumulate(data[idx], values[(j*tile.width+i) as usize]);
+                    }
+                }
+            }
+            if !cancelled.load(Ordering::Relaxed) && !on_tile(image, &tile) {
+                cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+    !cancelled.load(Ordering::Relaxed)
+}
+
+// render one more sample for every pixel on the calling thread, with the default path tracer
+#[allow(dead_code)]
+pub fn render_step(world: &dyn Hitable, camera: &Camera, image: &mut Image) {
+    let integrator = PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH);
+    render(world, &integrator, camera, image, 1, 0, 1, |_, _| true);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::material::DiffuseLight;
 
     fn approx_eq(a: f32, b: f32, epsilon: f32) -> bool {
         (a - b).abs() < epsilon
@@ -191,15 +367,34 @@ mod tests {
         let mut image = Image::new(10, 10);
         
         // Set a pixel value
-        image.data[0] = Vec3::new(4.0, 9.0, 16.0);
+        image.data[0] = Vec3::new(0.25, 1.0, 16.0);
         image.samples = 1;
         
         let val = image.val(0);
         
-        // Should apply gamma correction (sqrt)
-        assert_eq!(val.x, 2.0);
-        assert_eq!(val.y, 3.0);
-        assert_eq!(val.z, 4.0);
+        // Should apply the sRGB transfer function, after clamping with the default tone mapping
+        assert!(approx_eq(val.x, 0.5371, 1e-4));
+        assert!(approx_eq(val.y, 1.0, 1e-6));
+        assert!(approx_eq(val.z, 1.0, 1e-6));
+
+        // exposure in stops
+        image.tone_mapping.exposure = -2.0;
+        let val = image.val(0);
+        assert!(approx_eq(val.y, 0.5371, 1e-4));
+        assert!(approx_eq(val.z, 1.0, 1e-6));
+    }
+
+    #[test]
+    fn image_radiance() {
+        // averaged over the samples, without gamma or clamping, and flipped to rows from the top
+        let mut image = Image::new(2, 2);
+        image.data[0] = Vec3::new(8.0, 4.0, 2.0);
+        image.data[3] = Vec3::new(0.0, 1.0, 0.0);
+        image.samples = 2;
+        let radiance = image.radiance();
+        assert_eq!((radiance[2].x, radiance[2].y, radiance[2].z), (4.0, 2.0, 1.0));
+        assert_eq!(radiance[1].y, 0.5);
+        assert_eq!(radiance[0].y, 0.0);
     }
 
     #[test]
@@ -212,11 +407,11 @@ mod tests {
         
         let val = image.val(0);
         
-        // Should divide by samples, then sqrt
-        // 4.0/4.0 = 1.0, sqrt(1.0) = 1.0
-        assert_eq!(val.x, 1.0);
-        assert_eq!(val.y, 1.0);
-        assert_eq!(val.z, 1.0);
+        // Should divide by samples, then encode
+        // 4.0/4.0 = 1.0, encoded as 1.0
+        assert!(approx_eq(val.x, 1.0, 1e-6));
+        assert!(approx_eq(val.y, 1.0, 1e-6));
+        assert!(approx_eq(val.z, 1.0, 1e-6));
     }
 
     #[test]
@@ -226,87 +421,102 @@ mod tests {
         // Set a pixel at data index 0 (bottom-left in storage)
         // val_rgb(i, j) accesses data at index (height-j-1)*width + i
         // So val_rgb(5, 9) accesses (10-9-1)*10 + 5 = 0*10 + 5 = 5
-        image.data[5] = Vec3::new(1.0, 0.25, 0.0625); // After gamma: sqrt gives 1.0, 0.5, 0.25
+        image.data[5] = Vec3::new(1.0, 0.2140, 0.0508); // sRGB encoded: 1.0, 0.5, 0.25
         image.samples = 1;
         
         let (r, g, b) = image.val_rgb(5, 9);
         
-        // After gamma correction and conversion to 0-255
+        // After encoding and conversion to 0-255
         assert_eq!(r, 255);
-        assert!(g > 125 && g < 130); // sqrt(0.25) ≈ 0.5 → ~127-128
-        assert!(b > 60 && b < 70); // sqrt(0.0625) = 0.25 → ~63-64
+        assert!(g > 125 && g < 130); // 0.5 → ~127-128
+        assert!(b > 60 && b < 70); // 0.25 → ~63-64
     }
 
     #[test]
-    fn color_miss_returns_sky() {
-        let world = HitableList::new(); // Empty world
-        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
-        
-        let col = color(ray, &world, 0);
-        
-        // Should return sky gradient (blue-white)
-        assert!(col.x >= 0.5 && col.x <= 1.0);
-        assert!(col.y >= 0.7 && col.y <= 1.0);
-        assert!(col.z >= 1.0);
-    }
-
-    #[test]
-    fn color_sky_gradient() {
-        let world = HitableList::new();
-        
-        // Ray pointing straight up should give bluer color
-        let ray_up = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
-        let col_up = color(ray_up, &world, 0);
-        
-        // Ray pointing down should give whiter color
-        let ray_down = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
-        let col_down = color(ray_down, &world, 0);
-        
-        // The gradient blends between white (1,1,1) at bottom and blue (0.5,0.7,1.0) at top
-        // So rays pointing down should have higher overall brightness
-        let brightness_up = col_up.x + col_up.y + col_up.z;
-        let brightness_down = col_down.x + col_down.y + col_down.z;
-        
-        // Down ray should be brighter (more white)
-        assert!(brightness_down > brightness_up);
+    fn render_with_light_is_finite() {
+        let mut world = HitableList::new();
+        world.add(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Box::new(Lambertian::new(Vec3::new(0.6, 0.6, 0.6)))));
+        world.add(Sphere::new(Vec3::new(0.0, 0.5, 0.0), 0.5, Box::new(Lambertian::new(Vec3::new(0.6, 0.6, 0.6)))));
+        world.add(Sphere::new(Vec3::new(1.0, 1.5, 1.0), 0.1, Box::new(DiffuseLight::new(Vec3::new(50.0, 40.0, 30.0)))));
+        let camera = Camera::new(Vec3::new(0.0, 1.0, 4.0), Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, 1.0, 0.0),
+                                 40.0, 2.0, 0.0, 4.0);
+        let mut image = Image::new(60, 30);
+        render(&world, &PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH), &camera, &mut image, 8, 0, 1, |_, _| true);
+        // light samples at the edge of the cone used to give a zero pdf, and NaN pixels
+        assert!(image.data.iter().all(|p| p.x.is_finite() && p.y.is_finite() && p.z.is_finite()));
     }
 
     #[test]
-    fn color_hit_returns_material_color() {
+    fn render_alpha_coverage() {
+        // a single sphere in the middle of the image
         let mut world = HitableList::new();
-        world.add(Sphere::new(
-            Vec3::new(0.0, 0.0, -1.0),
-            0.5,
-            Box::new(Lambertian::new(Vec3::new(0.8, 0.3, 0.3)))
-        ));
-        
-        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
-        let col = color(ray, &world, 0);
-        
-        // Should return some color (not black, not pure sky)
-        assert!(col.x > 0.0 || col.y > 0.0 || col.z > 0.0);
+        world.add(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 0.5, Box::new(Lambertian::new(Vec3::new(0.6, 0.6, 0.6)))));
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 4.0), Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0),
+                                 40.0, 1.0, 0.0, 4.0);
+        let light_passes = [Aov::Emission, Aov::DiffuseDirect, Aov::DiffuseIndirect, Aov::Specular];
+        let mut image = Image::new(20, 20).with_alpha().with_aovs(&light_passes);
+        render(&world, &PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH), &camera, &mut image, 4, 0, 2, |_, _| true);
+        let alpha = image.alpha().unwrap();
+        assert_eq!(alpha[0], 0.0);
+        assert_eq!(alpha[10*20 + 10], 1.0);
+        // the background is left black and transparent, but the emission pass still sees it: the light passes
+        // add up to the covered pixels, and to the background elsewhere
+        let passes = |idx: usize| image.aovs.iter().fold(Vec3::new(0.0,0.0,0.0), |sum, (_, data)| sum + data[idx]);
+        assert_eq!(image.data[0].length(), 0.0);
+        assert!(passes(0).length() > 0.0);
+        assert_eq!(passes(0).length(), image.aovs[0].1[0].length());
+        let center = 10*20 + 10;
+        assert!((passes(center) - image.data[center]).length() < 1e-4*image.data[center].length());
+        assert!(Image::new(20, 20).alpha().is_none());
     }
 
     #[test]
-    fn color_max_depth() {
+    fn render_aovs() {
         let mut world = HitableList::new();
-        world.add(Sphere::new(
-            Vec3::new(0.0, 0.0, -1.0),
-            0.5,
-            Box::new(Lambertian::new(Vec3::new(1.0, 1.0, 1.0)))
-        ));
-        
-        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
-        
-        // At max depth, should return black
-        let col = color(ray, &world, 50);
-        
-        assert_eq!(col.x, 0.0);
-        assert_eq!(col.y, 0.0);
-        assert_eq!(col.z, 0.0);
+        world.add(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 0.5, Box::new(Lambertian::new(Vec3::new(0.6, 0.4, 0.2)))));
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 4.0), Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0),
+                                 40.0, 1.0, 0.0, 4.0);
+        let mut image = Image::new(20, 20).with_aovs(&Aov::ALL);
+        // two calls, that keep accumulating the passes
+        for _ in 0..2 {
+            render(&world, &PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH), &camera, &mut image, 2, 0, 3, |_, _| true);
+        }
+        let pass = |aov: Aov| image.aov_pixels(Aov::ALL.iter().position(|&a| a == aov).unwrap());
+        let (center, corner) = (10*20 + 10, 0);
+        assert!(approx_eq(pass(Aov::Albedo)[center].x, 0.6, 1e-5));
+        assert!(approx_eq(pass(Aov::Normal)[center].z, 1.0, 0.05));
+        assert!(approx_eq(pass(Aov::Depth)[center].x, 3.5, 0.05));
+        assert_eq!((pass(Aov::ObjectId)[center].x, pass(Aov::ObjectId)[corner].x), (1.0, 0.0));
+        assert_eq!(pass(Aov::Depth)[corner].x, f32::INFINITY);
+        // the light passes add up to the image
+        let radiance = image.radiance();
+        for idx in &[center, corner] {
+            let sum: f32 = Aov::ALL.iter().filter(|aov| aov.is_light()).map(|&aov| pass(aov)[*idx].y).sum();
+            assert!(approx_eq(sum, radiance[*idx].y, 1e-4), "{} {}", sum, radiance[*idx].y);
+        }
+        assert_eq!(pass(Aov::Emission)[center].y, 0.0);
+
+        // saved as layers of the exr output, or as separate files
+        let path = std::env::temp_dir().join(format!("path_tracer_aov_test_{}.exr", std::process::id()));
+        image.save(&path, OutputFormat::Exr(ExrPrecision::Float)).unwrap();
+        let layers = exr::prelude::read_first_flat_layer_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let names: Vec<String> = layers.layer_data.channel_data.list.iter().map(|c| c.name.to_string()).collect();
+        assert_eq!(names.len(), 3 + 4*3 + 2*3 + 2);
+        assert!(names.contains(&String::from("R")) && names.contains(&String::from("diffuse_direct.G")));
+        assert!(names.contains(&String::from("normal.X")) && names.contains(&String::from("depth.Z")) && names.contains(&String::from("id.id")));
+        image.save_aovs(&path.with_extension("png"), ExrPrecision::Half).unwrap();
+        let albedo_path = path.with_extension("albedo.exr");
+        let albedo = exr::prelude::read_first_flat_layer_from_file(&albedo_path).unwrap();
+        for aov in &Aov::ALL {
+            std::fs::remove_file(path.with_extension(format!("{}.exr", aov.name()))).unwrap();
+        }
+        let names: Vec<String> = albedo.layer_data.channel_data.list.iter().map(|c| c.name.to_string()).collect();
+        assert_eq!(names, vec!["B", "G", "R"]);
     }
 
     #[test]
+    #[allow(clippy::len_zero)]
     fn create_test_scene_works() {
         let world = create_test_scene();
         
@@ -375,19 +585,139 @@ mod tests {
         assert!(has_color);
     }
 
+    #[test]
+    fn render_step_accepts_trait_object() {
+        let scene = create_test_scene();
+        let world: &dyn Hitable = &scene;
+        let camera = Camera::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            90.0,
+            2.0,
+            0.0,
+            1.0
+        );
+        let mut image = Image::new(10, 10);
+
+        render_step(world, &camera, &mut image);
+
+        assert_eq!(image.samples, 1);
+    }
+
+    #[test]
+    fn image_tiles_cover_image() {
+        let tiles = image_tiles(70, 40);
+        assert_eq!(tiles.len(), 6);
+        // the first tiles are the top ones, partial tiles at the borders
+        assert_eq!((tiles[0].x, tiles[0].y, tiles[0].width, tiles[0].height), (0, 32, 32, 8));
+        assert_eq!((tiles[5].x, tiles[5].y, tiles[5].width, tiles[5].height), (64, 0, 6, 32));
+        let mut covered = vec![0; 70*40];
+        for tile in &tiles {
+            assert_eq!(tile.data.len(), (tile.width*tile.height) as usize);
+            for j in tile.y..tile.y+tile.height {
+                for i in tile.x..tile.x+tile.width {
+                    covered[(j*70+i) as usize] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn render_multithreaded_fills_image() {
+        let world = HitableList::new();
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0),
+                                 90.0, 2.0, 0.0, 1.0);
+        let mut image = Image::new(100, 50);
+        let mut tiles = 0;
+        render(&world, &PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH), &camera, &mut image, 2, 0, 4, |_, _| { tiles += 1; true });
+        assert_eq!(tiles, 8);
+        assert_eq!(image.samples, 2);
+        // every pixel gets the sky color, accumulated twice
+        assert!(image.data.iter().all(|p| p.z > 1.99 && p.z < 2.01 && p.x > 0.99));
+    }
+
+    #[test]
+    fn render_cancel_stops_early() {
+        let world = HitableList::new();
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0),
+                                 90.0, 2.0, 0.0, 1.0);
+        let mut image = Image::new(320, 320);
+        let mut tiles = 0;
+        let done = render(&world, &PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH), &camera, &mut image, 1, 0, 1, |_, _| { tiles += 1; false });
+        assert_eq!(tiles, 1);
+        // the samples are counted anyway, the pixels of the other tiles are left black
+        assert!(!done);
+        assert_eq!(image.samples, 1);
+        let rendered = image.data.iter().filter(|p| p.z > 0.0).count();
+        assert!((32*32..320*320).contains(&rendered));
+    }
+
+    #[test]
+    fn render_preview_sees_averages() {
+        let world = create_test_scene();
+        let camera = Camera::new(Vec3::new(0.0, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0),
+                                 60.0, 2.0, 0.0, 2.0);
+        let mut image = Image::new(80, 40);
+        let mut shown = vec![Vec3::new(0.0,0.0,0.0); 80*40];
+        render(&world, &PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH), &camera, &mut image, 2, 0, 2, |image, tile| {
+            for j in tile.y..tile.y+tile.height {
+                for i in tile.x..tile.x+tile.width {
+                    let idx = (j*image.width + i) as usize;
+                    shown[idx] = image.val(idx);
+                }
+            }
+            true
+        });
+        // the finished tiles already have all their samples
+        for (idx, v) in shown.iter().enumerate() {
+            let last = image.val(idx);
+            assert!(v.x.is_finite() && v.y.is_finite() && v.z.is_finite());
+            assert_eq!((v.x, v.y, v.z), (last.x, last.y, last.z));
+        }
+    }
+
+    #[test]
+    fn render_seed_independent_of_threads() {
+        let world = create_test_scene();
+        let camera = Camera::new(Vec3::new(0.0, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0),
+                                 60.0, 2.0, 0.1, 2.0);
+        let render_with = |seed, threads, passes| {
+            let mut image = Image::new(80, 40);
+            for _ in 0..passes {
+                render(&world, &PathTracer::new(DEFAULT_MAX_DEPTH, DEFAULT_RR_DEPTH), &camera, &mut image, 4/passes, seed, threads, |_, _| true);
+            }
+            image.data
+        };
+        let reference = render_with(42, 1, 1);
+        let same = |a: &Vec<Vec3>, b: &Vec<Vec3>| a.iter().zip(b).all(|(p, q)| p.x == q.x && p.y == q.y && p.z == q.z);
+        assert!(same(&reference, &render_with(42, 4, 1)));
+        assert!(same(&reference, &render_with(42, 3, 1)));
+        // the samples are the same when rendered in several passes, but summed in a different order
+        assert!(render_with(42, 2, 2).iter().zip(&reference).all(|(p, q)| approx_eq(p.x, q.x, 1e-4)));
+        assert!(!same(&reference, &render_with(43, 1, 1)));
+    }
+
     #[test]
     fn image_gamma_correction() {
         let mut image = Image::new(1, 1);
         
         // Test gamma correction with known values
-        image.data[0] = Vec3::new(0.25, 0.5, 1.0);
+        image.data[0] = Vec3::new(0.002, 0.5, 1.0);
         image.samples = 1;
         
         let val = image.val(0);
         
-        // sqrt(0.25) = 0.5, sqrt(0.5) ≈ 0.707, sqrt(1.0) = 1.0
-        assert!(approx_eq(val.x, 0.5, 0.001));
-        assert!(approx_eq(val.y, 0.707, 0.001));
+        // linear segment near black, then the 2.4 power curve
+        assert!(approx_eq(val.x, 0.02584, 0.001));
+        assert!(approx_eq(val.y, 0.7354, 0.001));
         assert!(approx_eq(val.z, 1.0, 0.001));
+
+        // wider gamuts: the same color has smaller values
+        image.color_space = ColorSpace::DisplayP3;
+        image.data[0] = Vec3::new(1.0, 0.0, 0.0);
+        let val = image.val(0);
+        assert!(val.x < 1.0 && val.y > 0.0 && val.z > 0.0);
     }
 }
@@ -0,0 +1,452 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::color;
+use crate::pt_math::Vec3;
+use crate::mesh::{MeshVertices, TriangleMesh};
+use crate::material::Material;
+
+// Stanford PLY loader, for ascii and binary (little and big endian) files.
+// Vertex positions, normals, colors and texture coordinates are read from the "vertex" element,
+// polygons from the "face" element; other elements are skipped.
+
+#[derive(Debug)]
+pub enum PlyError {
+    Io { path: PathBuf, error: std::io::Error },
+    Header { path: PathBuf, line: usize, message: String },
+    Data { path: PathBuf, message: String },
+}
+
+impl fmt::Display for PlyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlyError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            PlyError::Header { path, line, message } => write!(f, "{}:{}: {}", path.display(), line, message),
+            PlyError::Data { path, message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for PlyError {}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum ScalarType {
+    Int8, UInt8, Int16, UInt16, Int32, UInt32, Float32, Float64,
+}
+
+impl ScalarType {
+    fn parse(name: &str) -> Option<ScalarType> {
+        match name {
+            "char" | "int8" => Some(ScalarType::Int8),
+            "uchar" | "uint8" => Some(ScalarType::UInt8),
+            "short" | "int16" => Some(ScalarType::Int16),
+            "ushort" | "uint16" => Some(ScalarType::UInt16),
+            "int" | "int32" => Some(ScalarType::Int32),
+            "uint" | "uint32" => Some(ScalarType::UInt32),
+            "float" | "float32" => Some(ScalarType::Float32),
+            "double" | "float64" => Some(ScalarType::Float64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ScalarType::Int8 | ScalarType::UInt8 => 1,
+            ScalarType::Int16 | ScalarType::UInt16 => 2,
+            ScalarType::Int32 | ScalarType::UInt32 | ScalarType::Float32 => 4,
+            ScalarType::Float64 => 8,
+        }
+    }
+
+    // scale factor to bring integer colors to [0,1]
+    fn color_scale(self) -> f64 {
+        match self {
+            ScalarType::UInt8 => 1.0/255.0,
+            ScalarType::UInt16 => 1.0/65535.0,
+            _ => 1.0,
+        }
+    }
+}
+
+#[derive(Clone,Debug)]
+enum PropertyType {
+    Scalar(ScalarType),
+    List(ScalarType, ScalarType), // count type, item type
+}
+
+#[derive(Clone,Debug)]
+struct Property {
+    name: String,
+    ty: PropertyType,
+}
+
+#[derive(Clone,Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn property(&self, names: &[&str]) -> Option<usize> {
+        self.properties.iter().position(|p| names.contains(&p.name.as_str()))
+    }
+}
+
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+}
+
+// read the mesh from a ply file. If vertex_colors is set, the colors stored in the file (if any)
+// are kept in the mesh and modulate the albedo of lambertian materials
+pub fn load_ply(path: &Path, material: Box<dyn Material>, vertex_colors: bool) -> Result<TriangleMesh, PlyError> {
+    let data = std::fs::read(path).map_err(|error| PlyError::Io { path: path.to_path_buf(), error })?;
+    parse_ply(&data, path, material, vertex_colors)
+}
+
+// split the header from the body; returns the header lines and the offset of the body
+fn split_header(data: &[u8]) -> Option<(Vec<&str>, usize)> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = start + data[start..].iter().position(|&c| c == b'\n')?;
+        let line = std::str::from_utf8(&data[start..end]).ok()?.trim_end_matches('\r');
+        lines.push(line);
+        start = end + 1;
+        if line.trim() == "end_header" {
+            return Some((lines, start));
+        }
+    }
+    None
+}
+
+fn parse_header(lines: &[&str], path: &Path) -> Result<Header, PlyError> {
+    let error = |line: usize, message: String| PlyError::Header { path: path.to_path_buf(), line: line+1, message };
+    if lines.first().map(|l| l.trim()) != Some("ply") {
+        return Err(error(0, "not a ply file (missing 'ply' magic)".to_string()));
+    }
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for (i, line) in lines.iter().enumerate().skip(1) {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.first().copied() {
+            Some("format") => {
+                if tokens.len() != 3 {
+                    return Err(error(i, "'format' expects a format and a version".to_string()));
+                }
+                format = Some(match tokens[1] {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    f => return Err(error(i, format!("unknown format '{}'", f))),
+                });
+            }
+            Some("element") => {
+                if tokens.len() != 3 {
+                    return Err(error(i, "'element' expects a name and a count".to_string()));
+                }
+                let count = tokens[2].parse::<usize>().map_err(|_| error(i, format!("invalid element count '{}'", tokens[2])))?;
+                elements.push(Element { name: tokens[1].to_string(), count, properties: Vec::new() });
+            }
+            Some("property") => {
+                let scalar = |name: &str| ScalarType::parse(name).ok_or_else(|| error(i, format!("unknown property type '{}'", name)));
+                let property = match tokens.len() {
+                    3 => Property { name: tokens[2].to_string(), ty: PropertyType::Scalar(scalar(tokens[1])?) },
+                    5 if tokens[1] == "list" => Property { name: tokens[4].to_string(),
+                                                           ty: PropertyType::List(scalar(tokens[2])?, scalar(tokens[3])?) },
+                    _ => return Err(error(i, "malformed property".to_string())),
+                };
+                match elements.last_mut() {
+                    Some(e) => e.properties.push(property),
+                    None => return Err(error(i, "property defined before any element".to_string())),
+                }
+            }
+            Some("comment") | Some("obj_info") | Some("end_header") | None => {}
+            Some(keyword) => return Err(error(i, format!("unknown header keyword '{}'", keyword))),
+        }
+    }
+    let format = format.ok_or_else(|| error(0, "missing 'format' line".to_string()))?;
+    Ok(Header { format, elements })
+}
+
+// sequential reader for the values of the body
+enum BodyReader<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary { data: &'a [u8], pos: usize, big_endian: bool },
+}
+
+impl<'a> BodyReader<'a> {
+    fn read(&mut self, ty: ScalarType) -> Result<f64, String> {
+        match self {
+            BodyReader::Ascii(tokens) => {
+                let token = tokens.next().ok_or_else(|| "unexpected end of file".to_string())?;
+                token.parse::<f64>().map_err(|_| format!("invalid number '{}'", token))
+            }
+            BodyReader::Binary { data, pos, big_endian } => {
+                let size = ty.size();
+                if *pos + size > data.len() {
+                    return Err("unexpected end of file".to_string());
+                }
+                let mut bytes = [0u8; 8];
+                bytes[..size].copy_from_slice(&data[*pos..*pos+size]);
+                if *big_endian {
+                    bytes[..size].reverse();
+                }
+                *pos += size;
+                let b2 = [bytes[0], bytes[1]];
+                let b4 = [bytes[0], bytes[1], bytes[2], bytes[3]];
+                Ok(match ty {
+                    ScalarType::Int8 => bytes[0] as i8 as f64,
+                    ScalarType::UInt8 => bytes[0] as f64,
+                    ScalarType::Int16 => i16::from_le_bytes(b2) as f64,
+                    ScalarType::UInt16 => u16::from_le_bytes(b2) as f64,
+                    ScalarType::Int32 => i32::from_le_bytes(b4) as f64,
+                    ScalarType::UInt32 => u32::from_le_bytes(b4) as f64,
+                    ScalarType::Float32 => f32::from_le_bytes(b4) as f64,
+                    ScalarType::Float64 => f64::from_le_bytes(bytes),
+                })
+            }
+        }
+    }
+}
+
+pub fn parse_ply(data: &[u8], path: &Path, material: Box<dyn Material>, vertex_colors: bool) -> Result<TriangleMesh, PlyError> {
+    let (lines, body_start) = split_header(data).ok_or_else(|| PlyError::Header { path: path.to_path_buf(), line: 1,
+                                                                                  message: "missing 'end_header'".to_string() })?;
+    let header = parse_header(&lines, path)?;
+    let data_error = |message: String| PlyError::Data { path: path.to_path_buf(), message };
+    let body = &data[body_start..];
+    let mut reader = match header.format {
+        Format::Ascii => BodyReader::Ascii(std::str::from_utf8(body).map_err(|_| data_error("invalid ascii data".to_string()))?
+                                           .split_ascii_whitespace()),
+        Format::BinaryLittleEndian => BodyReader::Binary { data: body, pos: 0, big_endian: false },
+        Format::BinaryBigEndian => BodyReader::Binary { data: body, pos: 0, big_endian: true },
+    };
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut colors = Vec::new();
+    let mut uvs = Vec::new();
+    let mut triangles: Vec<[u32; 3]> = Vec::new();
+    let mut values: Vec<Vec<f64>> = Vec::new(); // values of the properties of the current element instance
+    for element in &header.elements {
+        let position_idx = [element.property(&["x"]), element.property(&["y"]), element.property(&["z"])];
+        let normal_idx = [element.property(&["nx"]), element.property(&["ny"]), element.property(&["nz"])];
+        let color_idx = [element.property(&["red", "r"]), element.property(&["green", "g"]), element.property(&["blue", "b"])];
+        let uv_idx = [element.property(&["u", "s", "texture_u", "texture_s"]), element.property(&["v", "t", "texture_v", "texture_t"])];
+        let face_idx = element.property(&["vertex_indices", "vertex_index"]);
+        values.resize(element.properties.len(), Vec::new());
+        for n in 0..element.count {
+            for (property, value) in element.properties.iter().zip(values.iter_mut()) {
+                value.clear();
+                let context = |e: String| data_error(format!("{} {} property '{}': {}", element.name, n, property.name, e));
+                match property.ty {
+                    PropertyType::Scalar(ty) => value.push(reader.read(ty).map_err(context)?),
+                    PropertyType::List(count_ty, item_ty) => {
+                        let count = reader.read(count_ty).map_err(context)? as usize;
+                        for _ in 0..count {
+                            value.push(reader.read(item_ty).map_err(context)?);
+                        }
+                    }
+                }
+            }
+            let get = |idx: Option<usize>| idx.and_then(|i| values[i].first().copied());
+            if element.name == "vertex" {
+                match (get(position_idx[0]), get(position_idx[1]), get(position_idx[2])) {
+                    (Some(x), Some(y), Some(z)) => positions.push(Vec3::new(x as f32, y as f32, z as f32)),
+                    _ => return Err(data_error("vertex element without x/y/z properties".to_string())),
+                }
+                if let (Some(x), Some(y), Some(z)) = (get(normal_idx[0]), get(normal_idx[1]), get(normal_idx[2])) {
+                    normals.push(Vec3::new(x as f32, y as f32, z as f32));
+                }
+                if let (Some(r), Some(g), Some(b)) = (get(color_idx[0]), get(color_idx[1]), get(color_idx[2])) {
+                    let scale = match element.properties[color_idx[0].unwrap()].ty {
+                        PropertyType::Scalar(ty) => ty.color_scale(),
+                        PropertyType::List(_, ty) => ty.color_scale(),
+                    };
+                    // colors are stored sRGB encoded: decode them to linear like the image textures
+                    let decode = |c: f64| color::srgb_to_linear((c*scale) as f32);
+                    colors.push(Vec3::new(decode(r), decode(g), decode(b)));
+                }
+                if let (Some(u), Some(v)) = (get(uv_idx[0]), get(uv_idx[1])) {
+                    uvs.push((u as f32, v as f32));
+                }
+            } else if element.name == "face" {
+                let indices = match face_idx {
+                    Some(i) => &values[i],
+                    None => return Err(data_error("face element without vertex_indices property".to_string())),
+                };
+                if indices.len() < 3 {
+                    return Err(data_error(format!("face {} has only {} vertices", n, indices.len())));
+                }
+                if let Some(&i) = indices.iter().find(|&&i| i < 0.0) {
+                    return Err(data_error(format!("face {} references the negative vertex index {}", n, i)));
+                }
+                // n-gons are triangulated as a fan around the first vertex
+                for k in 1..indices.len()-1 {
+                    triangles.push([indices[0] as u32, indices[k] as u32, indices[k+1] as u32]);
+                }
+            }
+        }
+    }
+
+    if let Some(&i) = triangles.iter().flatten().find(|&&i| i as usize >= positions.len()) {
+        return Err(data_error(format!("face references vertex {} but only {} vertices are defined", i, positions.len())));
+    }
+    if !vertex_colors {
+        colors.clear();
+    }
+    let vertices = MeshVertices::new(positions, normals, uvs).with_colors(colors);
+    Ok(TriangleMesh::new(Arc::new(vertices), triangles, material))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pt_math::Ray;
+    use crate::objects::Hitable;
+    use crate::material::Lambertian;
+
+    fn material() -> Box<dyn Material> {
+        Box::new(Lambertian::new(Vec3::new(1.0, 1.0, 1.0)))
+    }
+
+    fn parse(data: &[u8]) -> Result<TriangleMesh, PlyError> {
+        parse_ply(data, Path::new("test.ply"), material(), true)
+    }
+
+    const HEADER: &str = "ply
+format {} 1.0
+comment a unit quad in the z=-2 plane
+element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+";
+
+    const POSITIONS: [[f32; 3]; 4] = [[-1.0, -1.0, -2.0], [1.0, -1.0, -2.0], [1.0, 1.0, -2.0], [-1.0, 1.0, -2.0]];
+
+    fn check_quad(mesh: &TriangleMesh) {
+        let ray = Ray::new(Vec3::new(0.5, -0.5, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = mesh.hit(&ray, 0.0, 100.0).unwrap();
+        assert!((hit.t - 2.0).abs() < 1e-5);
+        // all the vertices are red
+        let c = hit.vertex_color.unwrap();
+        assert!((c.x - 1.0).abs() < 1e-5);
+        assert_eq!(c.y, 0.0);
+        let bbox = mesh.bounding_box().unwrap();
+        assert_eq!((bbox.min.x, bbox.max.y), (-1.0, 1.0));
+    }
+
+    fn binary(big_endian: bool) -> Vec<u8> {
+        let format = if big_endian { "binary_big_endian" } else { "binary_little_endian" };
+        let mut data = HEADER.replace("{}", format).into_bytes();
+        for p in &POSITIONS {
+            for v in p {
+                data.extend_from_slice(&if big_endian { v.to_be_bytes() } else { v.to_le_bytes() });
+            }
+            data.extend_from_slice(&[255, 0, 0]);
+        }
+        data.push(4);
+        for i in 0..4i32 {
+            data.extend_from_slice(&if big_endian { i.to_be_bytes() } else { i.to_le_bytes() });
+        }
+        data
+    }
+
+    #[test]
+    fn ply_ascii() {
+        let src = HEADER.replace("{}", "ascii") + "-1 -1 -2 255 0 0\n1 -1 -2 255 0 0\n1 1 -2 255 0 0\n-1 1 -2 255 0 0\n4 0 1 2 3\n";
+        check_quad(&parse(src.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn ply_colors_are_srgb() {
+        let src = HEADER.replace("{}", "ascii") + "-1 -1 -2 128 0 0\n1 -1 -2 128 0 0\n1 1 -2 128 0 0\n-1 1 -2 128 0 0\n4 0 1 2 3\n";
+        let mesh = parse(src.as_bytes()).unwrap();
+        let ray = Ray::new(Vec3::new(0.5, -0.5, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let c = mesh.hit(&ray, 0.0, 100.0).unwrap().vertex_color.unwrap();
+        assert!((c.x - color::srgb_to_linear(128.0/255.0)).abs() < 1e-5);
+        assert!((c.x - 0.2158).abs() < 1e-3);
+    }
+
+    #[test]
+    fn ply_binary_little_endian() {
+        check_quad(&parse(&binary(false)).unwrap());
+    }
+
+    #[test]
+    fn ply_binary_big_endian() {
+        check_quad(&parse(&binary(true)).unwrap());
+    }
+
+    #[test]
+    fn ply_without_vertex_colors() {
+        let mesh = parse_ply(&binary(false), Path::new("test.ply"), material(), false).unwrap();
+        let ray = Ray::new(Vec3::new(0.5, -0.5, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(mesh.hit(&ray, 0.0, 100.0).unwrap().vertex_color.is_none());
+    }
+
+    #[test]
+    fn ply_normals_and_extra_elements() {
+        let src = "ply\nformat ascii 1.0\nelement vertex 3\nproperty double x\nproperty double y\nproperty double z\n\
+                   property float nx\nproperty float ny\nproperty float nz\nproperty float confidence\n\
+                   element face 1\nproperty list uchar uint vertex_index\nelement edge 1\nproperty int vertex1\nproperty int vertex2\n\
+                   end_header\n0 0 -1 0 1 0 0.5\n1 0 -1 0 1 0 0.5\n0 1 -1 0 1 0 0.5\n3 0 1 2\n0 1\n";
+        let mesh = parse(src.as_bytes()).unwrap();
+        let ray = Ray::new(Vec3::new(0.2, 0.2, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = mesh.hit(&ray, 0.0, 100.0).unwrap();
+        // normal comes from the vertices, not from the geometry
+        assert!((hit.normal.y - 1.0).abs() < 1e-5);
+        assert!(hit.vertex_color.is_none());
+    }
+
+    #[test]
+    fn ply_header_errors() {
+        let line = |src: &str| match parse(src.as_bytes()) {
+            Err(PlyError::Header { line, .. }) => line,
+            _ => panic!("expected a header error"),
+        };
+        assert_eq!(line("obj\nend_header\n"), 1);
+        assert_eq!(line("ply\nformat ascii 1.0\nelement vertex\nend_header\n"), 3);
+        assert_eq!(line("ply\nformat ascii 1.0\nelement vertex 1\nproperty float3 x\nend_header\n"), 4);
+        assert_eq!(line("ply\nformat ascii 1.0\nproperty float x\nend_header\n"), 3);
+        assert_eq!(line("ply\nformat ascii 1.0\n"), 1);
+    }
+
+    #[test]
+    fn ply_data_errors() {
+        // truncated binary data
+        let data = binary(false);
+        assert!(matches!(parse(&data[..data.len()-2]), Err(PlyError::Data { .. })));
+        // invalid ascii value
+        let src = HEADER.replace("{}", "ascii") + "-1 -1 -2 255 0 0\n1 -1 x 255 0 0\n";
+        assert!(matches!(parse(src.as_bytes()), Err(PlyError::Data { .. })));
+        // face referencing a missing vertex
+        let src = HEADER.replace("{}", "ascii") + "-1 -1 -2 255 0 0\n1 -1 -2 255 0 0\n1 1 -2 255 0 0\n-1 1 -2 255 0 0\n3 0 1 7\n";
+        assert!(matches!(parse(src.as_bytes()), Err(PlyError::Data { .. })));
+        // negative indices are not wrapped to a valid vertex
+        let src = HEADER.replace("{}", "ascii") + "-1 -1 -2 255 0 0\n1 -1 -2 255 0 0\n1 1 -2 255 0 0\n-1 1 -2 255 0 0\n3 0 1 -1\n";
+        assert!(matches!(parse(src.as_bytes()), Err(PlyError::Data { .. })));
+    }
+
+    #[test]
+    fn ply_missing_file() {
+        assert!(matches!(load_ply(Path::new("/does/not/exist.ply"), material(), true), Err(PlyError::Io { .. })));
+    }
+}
@@ -0,0 +1,30 @@
+use crate::path_tracer::{Image, Tile};
+
+use minifb::{Key, WindowOptions, Window};
+
+// window showing the image while it is rendered
+pub struct Preview {
+    window: Window,
+    buffer: Vec<u32>,
+}
+
+impl Preview {
+    pub fn new(width: u32, height: u32) -> Result<Preview, minifb::Error> {
+        let window = Window::new("Test - ESC to exit", width as usize, height as usize, WindowOptions::default())?;
+        Ok(Preview { window, buffer: vec![0; (width * height) as usize] })
+    }
+
+    // copy a finished tile to the framebuffer and refresh the window.
+    // Returns false when the window has been closed, to stop rendering
+    pub fn update(&mut self, image: &Image, tile: &Tile) -> bool {
+        for j in tile.y..tile.y+tile.height {
+            for i in tile.x..tile.x+tile.width {
+                let (r,g,b) = image.val_rgb(i,image.height-j-1);
+                let rgb: u32 = 0xff << 24 | (r as u32) << 16 | (g as u32) << 8 | b as u32;
+                self.buffer[(i+(image.height-j-1)*image.width) as usize] = rgb;
+            }
+        }
+        self.window.update_with_buffer(&self.buffer).unwrap();
+        self.window.is_open() && !self.window.is_key_down(Key::Escape)
+    }
+}
@@ -1,4 +1,7 @@
-use std::ops::{Add, Sub, Mul, Div, Neg};
+use std::ops::{Add, Sub, Mul, Div, Neg, Index};
+use std::cell::RefCell;
+use rand::prelude::*;
+use rand_pcg::Pcg32;
 
 #[derive(Clone,Copy,Debug)]
 pub struct Vec3 {
@@ -9,7 +12,7 @@ pub struct Vec3 {
 
 impl Vec3 {
     pub fn new(x: f32, y: f32, z: f32) -> Vec3 {
-        Vec3 {x: x, y: y, z: z}
+        Vec3 {x, y, z}
     }
 
     pub fn squared_length(&self) -> f32 {
@@ -96,6 +99,19 @@ impl Neg for Vec3 {
     }
 }
 
+impl Index<usize> for Vec3 {
+    type Output = f32;
+
+    fn index(&self, axis: usize) -> &f32 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 index out of range: {}", axis),
+        }
+    }
+}
+
 // finctions working on Vec3 
 
 pub fn unit_vector(v: Vec3) -> Vec3 {
@@ -124,6 +140,31 @@ pub fn mul_component(v1: Vec3, v2: Vec3) -> Vec3 {
     }
 }
 
+// component-wise minimum of two vectors
+pub fn min_component(v1: Vec3, v2: Vec3) -> Vec3 {
+    Vec3 { x: v1.x.min(v2.x),
+           y: v1.y.min(v2.y),
+           z: v1.z.min(v2.z),
+    }
+}
+
+// component-wise maximum of two vectors
+pub fn max_component(v1: Vec3, v2: Vec3) -> Vec3 {
+    Vec3 { x: v1.x.max(v2.x),
+           y: v1.y.max(v2.y),
+           z: v1.z.max(v2.z),
+    }
+}
+
+// two unit vectors that form an orthonormal basis with the unit vector n (Duff et al. 2017)
+pub fn orthonormal_basis(n: Vec3) -> (Vec3, Vec3) {
+    let sign = 1.0f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+     Vec3::new(b, sign + n.y * n.y * a, -n.y))
+}
+
 // Ray in 3D space
 #[derive(Clone,Copy,Debug)]
 pub struct Ray {
@@ -141,6 +182,107 @@ impl Ray {
     }
 }
 
+// axis aligned bounding box
+#[derive(Clone,Copy,Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        Aabb {min, max}
+    }
+
+    // an inverted box that acts as the identity for union()
+    pub fn empty() -> Aabb {
+        Aabb { min: Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+               max: Vec3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(min_component(self.min, other.min), max_component(self.max, other.max))
+    }
+
+    pub fn grow(&self, p: Vec3) -> Aabb {
+        Aabb::new(min_component(self.min, p), max_component(self.max, p))
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.extent();
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (d.x*d.y + d.y*d.z + d.z*d.x)
+    }
+
+    // slab test. inv_dir is the component-wise inverse of the ray direction, precomputed
+    // by the caller since the same ray is tested against many boxes
+    pub fn hit(&self, ray: &Ray, inv_dir: Vec3, t_min: f32, t_max: f32) -> bool {
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+        for axis in 0..3 {
+            let mut t_near = (self.min[axis] - ray.origin[axis]) * inv_dir[axis];
+            let mut t_far = (self.max[axis] - ray.origin[axis]) * inv_dir[axis];
+            if inv_dir[axis] < 0.0 {
+                std::mem::swap(&mut t_near, &mut t_far);
+            }
+            // max/min written this way so that NaNs (0*inf) don't discard the slab
+            t0 = if t_near > t0 { t_near } else { t0 };
+            t1 = if t_far < t1 { t_far } else { t1 };
+            if t1 < t0 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// random numbers used while rendering. Every thread has its own generator, that the renderer reseeds for
+// each pixel sample so that images only depend on the seed, and not on how pixels are split among threads.
+// Pcg32 is a fixed algorithm (SmallRng isn't), so the images are also the same across platforms and rand versions
+thread_local! {
+    static RNG: RefCell<Pcg32> = RefCell::new(Pcg32::from_entropy());
+}
+
+// reseed the generator of the current thread with a stream derived from the seed, pixel and sample indices
+pub fn seed_rng(seed: u64, pixel: u64, sample: u64) {
+    let stream = mix_bits(mix_bits(mix_bits(seed) ^ pixel) ^ sample);
+    // the state is set directly: seed_from_u64 expands the seed in ways that changed across rand versions
+    RNG.with(|rng| *rng.borrow_mut() = Pcg32::new(stream, 0xa02bdbf7bb3c0a7));
+}
+
+// uniform random number in [0,1)
+pub fn random_f32() -> f32 {
+    // the top 24 bits, as many as the mantissa holds
+    RNG.with(|rng| (rng.borrow_mut().next_u32() >> 8) as f32 / (1u32 << 24) as f32)
+}
+
+// uniformly distributed on the surface of the unit sphere
+pub fn random_unit_vector() -> Vec3 {
+    let z = 1.0 - 2.0*random_f32();
+    let r = (1.0 - z*z).max(0.0).sqrt();
+    let phi = 2.0*std::f32::consts::PI*random_f32();
+    Vec3::new(r*phi.cos(), r*phi.sin(), z)
+}
+
+// splitmix64 finalizer, to decorrelate the streams of neighbouring pixels and samples
+fn mix_bits(v: u64) -> u64 {
+    let mut v = v.wrapping_add(0x9e3779b97f4a7c15);
+    v = (v ^ (v >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    v = (v ^ (v >> 27)).wrapping_mul(0x94d049bb133111eb);
+    v ^ (v >> 31)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -223,6 +365,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::excessive_precision)]
     fn vec3_length() {
         let v1 = Vec3::new(3.0, 4.0, 0.0);
         assert_eq!(v1.length(), 5.0);
@@ -268,6 +411,17 @@ mod tests {
         assert_eq!(dot(v3, v4), 0.0);
     }
 
+    #[test]
+    fn orthonormal_basis_test() {
+        for n in [Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), unit_vector(Vec3::new(1.0, -2.0, 0.5))] {
+            let (u, v) = orthonormal_basis(n);
+            assert!((u.length() - 1.0).abs() < 1e-5 && (v.length() - 1.0).abs() < 1e-5);
+            assert!(dot(u, v).abs() < 1e-5 && dot(u, n).abs() < 1e-5 && dot(v, n).abs() < 1e-5);
+            // right handed
+            assert!(dot(cross(u, v), n) > 0.99);
+        }
+    }
+
     #[test]
     fn cross_product() {
         let v1 = Vec3::new(1.0, 0.0, 0.0);
@@ -367,5 +521,84 @@ mod tests {
         assert_eq!(v3.y, 3.0);
         assert_eq!(v3.z, 3.0);
     }
-}
 
+    #[test]
+    fn vec3_index() {
+        let v = Vec3::new(1.0, 2.0, 3.0);
+        assert_eq!(v[0], 1.0);
+        assert_eq!(v[1], 2.0);
+        assert_eq!(v[2], 3.0);
+    }
+
+    #[test]
+    fn min_max_component() {
+        let v1 = Vec3::new(1.0, 5.0, -3.0);
+        let v2 = Vec3::new(2.0, -1.0, -4.0);
+        let vmin = min_component(v1, v2);
+        let vmax = max_component(v1, v2);
+        assert_eq!((vmin.x, vmin.y, vmin.z), (1.0, -1.0, -4.0));
+        assert_eq!((vmax.x, vmax.y, vmax.z), (2.0, 5.0, -3.0));
+    }
+
+    #[test]
+    fn aabb_union_and_area() {
+        let a = Aabb::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 1.0));
+        let b = Aabb::new(Vec3::new(1.0, 0.0, 0.0), Vec3::new(2.0, 1.0, 1.0));
+        let u = a.union(&b);
+        assert_eq!(u.min.x, 0.0);
+        assert_eq!(u.max.x, 2.0);
+        assert_eq!(a.surface_area(), 6.0);
+        assert_eq!(u.surface_area(), 10.0);
+        // the empty box is the identity for union and has no area
+        let e = Aabb::empty();
+        assert_eq!(e.surface_area(), 0.0);
+        assert_eq!(e.union(&a).max.y, 1.0);
+    }
+
+    #[test]
+    fn aabb_hit() {
+        let b = Aabb::new(Vec3::new(-1.0, -1.0, -3.0), Vec3::new(1.0, 1.0, -2.0));
+        let inv = |d: Vec3| Vec3::new(1.0/d.x, 1.0/d.y, 1.0/d.z);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(b.hit(&ray, inv(ray.direction), 0.0, 100.0));
+        // box is beyond t_max
+        assert!(!b.hit(&ray, inv(ray.direction), 0.0, 1.0));
+        // ray pointing away
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!(!b.hit(&ray, inv(ray.direction), 0.0, 100.0));
+        // ray passing beside the box
+        let ray = Ray::new(Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(!b.hit(&ray, inv(ray.direction), 0.0, 100.0));
+    }
+
+    #[test]
+    fn random_unit_vector_test() {
+        for _ in 0..100 {
+            assert!((random_unit_vector().length() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn seed_rng_is_deterministic() {
+        seed_rng(7, 100, 3);
+        let a: Vec<f32> = (0..8).map(|_| random_f32()).collect();
+        seed_rng(7, 100, 3);
+        let b: Vec<f32> = (0..8).map(|_| random_f32()).collect();
+        assert_eq!(a, b);
+        assert!(a.iter().all(|&x| (0.0..1.0).contains(&x)));
+        // a different seed, pixel or sample gives a different stream
+        for (seed, pixel, sample) in [(8, 100, 3), (7, 101, 3), (7, 100, 4)] {
+            seed_rng(seed, pixel, sample);
+            let c: Vec<f32> = (0..8).map(|_| random_f32()).collect();
+            assert_ne!(a, c);
+        }
+    }
+
+    #[test]
+    fn seed_rng_stream_is_fixed() {
+        // the values don't depend on the platform or the rand version: regression images stay bit identical
+        seed_rng(7, 100, 3);
+        let values: Vec<u32> = (0..4).map(|_| (random_f32() * (1u32 << 24) as f32) as u32).collect();
+        assert_eq!(values, [1379337, 12482958, 4998694, 16287453]);
+    }
+}
@@ -0,0 +1,705 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::pt_math::Vec3;
+use crate::objects::{HitableList, Sphere, Triangle};
+use crate::material::{Material, Lambertian, Metal, Dielectric, DiffuseLight, RoughConductor, RoughDielectric, Principled, PrincipledParams};
+use crate::camera::CameraParams;
+use crate::integrator::Background;
+use crate::texture::{Texture, Checker, ImageTexture, WrapMode, Filter, NoiseTexture, NoisePattern, solid, constant};
+use crate::{obj_loader, ply_loader, gltf_loader};
+
+// scene loading. A scene can be a single model (.obj, .ply, .gltf/.glb) or a toml scene description
+// like the following, where every section is optional except for the objects:
+//
+//   [image]
+//   width = 640
+//   height = 360
+//   samples = 10
+//   background = [0.0, 0.0, 0.0]  # color of the directions missing the scene, or "sky" (default)
+//
+//   [camera]
+//   from = [10.0, 1.8, 2.4]
+//   to = [0.0, 0.0, 0.5]
+//   up = [0.0, 1.0, 0.0]      # default is +y
+//   vfov = 30.0               # vertical field of view, in degrees
+//   aperture = 0.05           # default is 0 (no depth of field)
+//   focus_distance = 10.0     # default is the distance between from and to
+//
+//   [textures.checks]
+//   type = "checker"          # even, odd (colors), size (default 1)
+//   even = [0.9, 0.9, 0.9]
+//   odd = [0.2, 0.3, 0.1]
+//   size = 0.5
+//   [textures.wood]
+//   type = "image"            # file (png, jpeg or hdr, relative to the scene file), srgb (default true: 8/16 bit
+//   file = "wood.jpg"         # colors are sRGB encoded), wrap (repeat, clamp, mirror), filter (nearest, bilinear)
+//   wrap = "mirror"
+//   [textures.stone]
+//   type = "marble"           # noise, turbulence, fbm, marble or wood (Perlin noise): scale (frequency, default 1),
+//   scale = 4.0               # seed (default 0), dark and light (colors blended by the pattern, default black
+//   light = [0.9, 0.9, 0.85]  # and white)
+//
+//   # material colors are either [r, g, b] or the name of a texture, and numbers
+//   # (fuzz, roughness, ...) are either a number or a texture name, using its red channel
+//   [materials.red]
+//   type = "lambertian"       # albedo
+//   albedo = [0.8, 0.3, 0.3]
+//   [materials.floor]
+//   type = "lambertian"
+//   albedo = "checks"
+//   [materials.gold]
+//   type = "metal"            # albedo, fuzz
+//   albedo = [0.8, 0.6, 0.2]
+//   fuzz = 0.1
+//   [materials.glass]
+//   type = "dielectric"       # ior, fuzz (optional)
+//   ior = 1.5
+//   [materials.copper]
+//   type = "conductor"        # color (reflectance at normal incidence), roughness (GGX microfacets)
+//   color = [0.95, 0.64, 0.54]
+//   roughness = 0.3
+//   [materials.frosted]
+//   type = "rough_dielectric" # ior, roughness (GGX microfacets)
+//   ior = 1.5
+//   roughness = 0.2
+//   [materials.plastic]
+//   type = "principled"       # base_color, metallic, roughness, specular, sheen, clearcoat, clearcoat_gloss,
+//   base_color = [0.2, 0.4, 0.8]  # transmission, ior; all optional
+//   roughness = 0.4
+//   clearcoat = 1.0
+//   [materials.lamp]
+//   type = "diffuse_light"    # emit (emitted radiance)
+//   emit = [4.0, 4.0, 4.0]
+//
+//   [[objects]]
+//   type = "sphere"           # center, radius, material
+//   center = [0.0, 0.0, -1.0]
+//   radius = 0.5
+//   material = "red"
+//   [[objects]]
+//   type = "triangle"         # vertices, material
+//   vertices = [[-1.0, 0.0, -2.0], [1.0, 0.0, -2.0], [0.0, 1.0, -2.0]]
+//   material = "gold"
+//   [[objects]]
+//   type = "mesh"             # file (.obj, .ply, .gltf or .glb, relative to the scene file), material (.ply only)
+//   file = "bunny.ply"
+//   material = "red"
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io { path: PathBuf, error: std::io::Error },
+    Toml { path: PathBuf, error: toml::de::Error },
+    Invalid { path: PathBuf, key: String, message: String },
+    Model { path: PathBuf, key: String, error: Box<dyn std::error::Error> },
+    UnsupportedFormat { path: PathBuf },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            SceneError::Toml { path, error } => write!(f, "{}: {}", path.display(), error),
+            SceneError::Invalid { path, key, message } => write!(f, "{}: '{}': {}", path.display(), key, message),
+            SceneError::Model { path, key, error } => write!(f, "{}: '{}': {}", path.display(), key, error),
+            SceneError::UnsupportedFormat { path } => write!(f, "{}: unsupported scene file format", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+// image settings from the scene file; missing values are left to the command line defaults
+#[derive(Clone,Copy,Debug,Default)]
+pub struct ImageSettings {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub samples: Option<u32>,
+    pub background: Option<Background>,
+}
+
+pub struct Scene {
+    pub world: HitableList,
+    pub camera: Option<CameraParams>,
+    pub image: ImageSettings,
+}
+
+// load a scene, choosing the loader from the file extension
+pub fn load_scene(path: &Path) -> Result<Scene, SceneError> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    let model_error = |error: Box<dyn std::error::Error>| SceneError::Model { path: path.to_path_buf(), key: String::new(), error };
+    match extension.as_str() {
+        "toml" => load_scene_file(path),
+        "obj" => {
+            let world = obj_loader::load_obj(path).map_err(|e| model_error(e.into()))?;
+            Ok(Scene { world, camera: None, image: ImageSettings::default() })
+        }
+        "ply" => {
+            // ply files have no materials: use a diffuse one, tinted by the vertex colors if present
+            let mut world = HitableList::new();
+            world.add(ply_loader::load_ply(path, Box::new(Lambertian::new(Vec3::new(0.8,0.8,0.8))), true)
+                      .map_err(|e| model_error(e.into()))?);
+            Ok(Scene { world, camera: None, image: ImageSettings::default() })
+        }
+        "gltf" | "glb" => {
+            let scene = gltf_loader::load_gltf(path).map_err(|e| model_error(e.into()))?;
+            Ok(Scene { world: scene.world, camera: scene.camera, image: ImageSettings::default() })
+        }
+        _ => Err(SceneError::UnsupportedFormat { path: path.to_path_buf() }),
+    }
+}
+
+pub fn load_scene_file(path: &Path) -> Result<Scene, SceneError> {
+    let src = std::fs::read_to_string(path).map_err(|error| SceneError::Io { path: path.to_path_buf(), error })?;
+    parse_scene(&src, path)
+}
+
+// a toml table together with its position in the document, to report errors precisely
+struct Table<'a> {
+    path: &'a Path,
+    key: String,
+    table: &'a toml::value::Table,
+}
+
+impl<'a> Table<'a> {
+    fn new(path: &'a Path, key: String, value: &'a toml::Value) -> Result<Table<'a>, SceneError> {
+        match value.as_table() {
+            Some(table) => Ok(Table { path, key, table }),
+            None => Err(SceneError::Invalid { path: path.to_path_buf(), key, message: "expected a table".to_string() }),
+        }
+    }
+
+    fn key(&self, name: &str) -> String {
+        if self.key.is_empty() { name.to_string() } else { format!("{}.{}", self.key, name) }
+    }
+
+    fn error(&self, name: &str, message: &str) -> SceneError {
+        SceneError::Invalid { path: self.path.to_path_buf(), key: self.key(name), message: message.to_string() }
+    }
+
+    // fail on keys that are not used, typically typos
+    fn check_keys(&self, allowed: &[&str]) -> Result<(), SceneError> {
+        match self.table.keys().find(|k| !allowed.contains(&k.as_str())) {
+            Some(k) => Err(self.error(k, "unknown key")),
+            None => Ok(()),
+        }
+    }
+
+    fn table(&self, name: &str) -> Result<Option<Table<'a>>, SceneError> {
+        self.table.get(name).map(|v| Table::new(self.path, self.key(name), v)).transpose()
+    }
+
+    fn opt_f32(&self, name: &str) -> Result<Option<f32>, SceneError> {
+        match self.table.get(name) {
+            None => Ok(None),
+            Some(v) => to_f32(v).map(Some).ok_or_else(|| self.error(name, "expected a number")),
+        }
+    }
+
+    fn f32(&self, name: &str) -> Result<f32, SceneError> {
+        self.opt_f32(name)?.ok_or_else(|| self.error(name, "missing value"))
+    }
+
+    fn opt_u32(&self, name: &str) -> Result<Option<u32>, SceneError> {
+        match self.table.get(name) {
+            None => Ok(None),
+            Some(v) => match v.as_integer() {
+                Some(i) if i > 0 && i <= u32::MAX as i64 => Ok(Some(i as u32)),
+                _ => Err(self.error(name, "expected a positive integer")),
+            },
+        }
+    }
+
+    fn opt_vec3(&self, name: &str) -> Result<Option<Vec3>, SceneError> {
+        match self.table.get(name) {
+            None => Ok(None),
+            Some(v) => to_vec3(v).map(Some).ok_or_else(|| self.error(name, "expected an array of 3 numbers")),
+        }
+    }
+
+    fn vec3(&self, name: &str) -> Result<Vec3, SceneError> {
+        self.opt_vec3(name)?.ok_or_else(|| self.error(name, "missing value"))
+    }
+
+    fn opt_str(&self, name: &str) -> Result<Option<&'a str>, SceneError> {
+        match self.table.get(name) {
+            None => Ok(None),
+            Some(v) => v.as_str().map(Some).ok_or_else(|| self.error(name, "expected a string")),
+        }
+    }
+
+    fn str(&self, name: &str) -> Result<&'a str, SceneError> {
+        self.opt_str(name)?.ok_or_else(|| self.error(name, "missing value"))
+    }
+
+    // "sky", or a color
+    fn opt_background(&self, name: &str) -> Result<Option<Background>, SceneError> {
+        match self.table.get(name) {
+            None => Ok(None),
+            Some(toml::Value::String(s)) if s == "sky" => Ok(Some(Background::Sky)),
+            Some(v) => match to_vec3(v) {
+                Some(c) if c.x >= 0.0 && c.y >= 0.0 && c.z >= 0.0 => Ok(Some(Background::Color(c))),
+                _ => Err(self.error(name, "expected \"sky\" or a color")),
+            },
+        }
+    }
+
+    fn opt_bool(&self, name: &str) -> Result<Option<bool>, SceneError> {
+        match self.table.get(name) {
+            None => Ok(None),
+            Some(v) => v.as_bool().map(Some).ok_or_else(|| self.error(name, "expected true or false")),
+        }
+    }
+
+    fn named_texture(&self, name: &str, texture: &str, textures: &Textures) -> Result<Arc<dyn Texture>, SceneError> {
+        textures.get(texture).cloned().ok_or_else(|| self.error(name, &format!("unknown texture '{}'", texture)))
+    }
+
+    // color parameter: [r, g, b] or the name of a texture
+    fn opt_color(&self, name: &str, textures: &Textures) -> Result<Option<Arc<dyn Texture>>, SceneError> {
+        match self.table.get(name) {
+            None => Ok(None),
+            Some(toml::Value::String(texture)) => self.named_texture(name, texture, textures).map(Some),
+            Some(v) => to_vec3(v).map(|c| Some(solid(c))).ok_or_else(|| self.error(name, "expected an array of 3 numbers or a texture name")),
+        }
+    }
+
+    fn color(&self, name: &str, textures: &Textures) -> Result<Arc<dyn Texture>, SceneError> {
+        self.opt_color(name, textures)?.ok_or_else(|| self.error(name, "missing value"))
+    }
+
+    // scalar parameter: a number or the name of a texture
+    fn opt_scalar(&self, name: &str, textures: &Textures) -> Result<Option<Arc<dyn Texture>>, SceneError> {
+        match self.table.get(name) {
+            None => Ok(None),
+            Some(toml::Value::String(texture)) => self.named_texture(name, texture, textures).map(Some),
+            Some(v) => to_f32(v).map(|x| Some(constant(x))).ok_or_else(|| self.error(name, "expected a number or a texture name")),
+        }
+    }
+
+    // same as opt_scalar, for parameters that are fractions: the numbers must be in [0,1]
+    fn opt_fraction(&self, name: &str, textures: &Textures) -> Result<Option<Arc<dyn Texture>>, SceneError> {
+        match self.table.get(name).and_then(to_f32) {
+            Some(x) if !(0.0..=1.0).contains(&x) => Err(self.error(name, "expected a number in [0,1] or a texture name")),
+            _ => self.opt_scalar(name, textures),
+        }
+    }
+
+    fn scalar(&self, name: &str, textures: &Textures) -> Result<Arc<dyn Texture>, SceneError> {
+        self.opt_scalar(name, textures)?.ok_or_else(|| self.error(name, "missing value"))
+    }
+}
+
+// textures by name; they are shared by all the materials using them
+type Textures<'a> = HashMap<&'a str, Arc<dyn Texture>>;
+
+fn to_f32(v: &toml::Value) -> Option<f32> {
+    match v {
+        toml::Value::Float(f) => Some(*f as f32),
+        toml::Value::Integer(i) => Some(*i as f32),
+        _ => None,
+    }
+}
+
+fn to_vec3(v: &toml::Value) -> Option<Vec3> {
+    match v.as_array()?.as_slice() {
+        [x, y, z] => Some(Vec3::new(to_f32(x)?, to_f32(y)?, to_f32(z)?)),
+        _ => None,
+    }
+}
+
+fn parse_texture(t: &Table, dir: &Path) -> Result<Arc<dyn Texture>, SceneError> {
+    match t.str("type")? {
+        "checker" => {
+            t.check_keys(&["type", "even", "odd", "size"])?;
+            let size = t.opt_f32("size")?.unwrap_or(1.0);
+            if size <= 0.0 {
+                return Err(t.error("size", "must be positive"));
+            }
+            Ok(Arc::new(Checker::new(solid(t.vec3("even")?), solid(t.vec3("odd")?), size)))
+        }
+        "image" => {
+            t.check_keys(&["type", "file", "srgb", "wrap", "filter"])?;
+            let wrap = match t.opt_str("wrap")? {
+                None => WrapMode::Repeat,
+                Some(name) => WrapMode::from_name(name).ok_or_else(|| t.error("wrap", "unknown wrap mode (expected repeat, clamp or mirror)"))?,
+            };
+            let filter = match t.opt_str("filter")? {
+                None => Filter::Bilinear,
+                Some(name) => Filter::from_name(name).ok_or_else(|| t.error("filter", "unknown filter (expected nearest or bilinear)"))?,
+            };
+            let image = ImageTexture::load(&dir.join(t.str("file")?), t.opt_bool("srgb")?.unwrap_or(true))
+                .map_err(|e| SceneError::Model { path: t.path.to_path_buf(), key: t.key("file"), error: e.into() })?;
+            Ok(Arc::new(image.with_sampling(wrap, filter)))
+        }
+        name => {
+            let pattern = NoisePattern::from_name(name)
+                .ok_or_else(|| t.error("type", "unknown texture type (expected checker, image, noise, turbulence, fbm, marble or wood)"))?;
+            t.check_keys(&["type", "scale", "seed", "dark", "light"])?;
+            let scale = t.opt_f32("scale")?.unwrap_or(1.0);
+            if scale <= 0.0 {
+                return Err(t.error("scale", "must be positive"));
+            }
+            let dark = solid(t.opt_vec3("dark")?.unwrap_or_else(|| Vec3::new(0.0, 0.0, 0.0)));
+            let light = solid(t.opt_vec3("light")?.unwrap_or_else(|| Vec3::new(1.0, 1.0, 1.0)));
+            Ok(Arc::new(NoiseTexture::new(pattern, scale, t.opt_u32("seed")?.unwrap_or(0) as u64, dark, light)))
+        }
+    }
+}
+
+// materials are described in the file and instantiated for every object using them
+fn parse_material(t: &Table, textures: &Textures) -> Result<Box<dyn Material>, SceneError> {
+    let material: Box<dyn Material> = match t.str("type")? {
+        "lambertian" => {
+            t.check_keys(&["type", "albedo"])?;
+            Box::new(Lambertian::textured(t.color("albedo", textures)?))
+        }
+        "metal" => {
+            t.check_keys(&["type", "albedo", "fuzz"])?;
+            Box::new(Metal::textured(t.color("albedo", textures)?, t.opt_scalar("fuzz", textures)?.unwrap_or_else(|| constant(0.0))))
+        }
+        "dielectric" => {
+            t.check_keys(&["type", "ior", "fuzz"])?;
+            Box::new(Dielectric::textured(t.f32("ior")?, t.opt_scalar("fuzz", textures)?.unwrap_or_else(|| constant(0.0))))
+        }
+        "conductor" => {
+            t.check_keys(&["type", "color", "roughness"])?;
+            Box::new(RoughConductor::new(t.color("color", textures)?, t.scalar("roughness", textures)?))
+        }
+        "rough_dielectric" => {
+            t.check_keys(&["type", "ior", "roughness"])?;
+            Box::new(RoughDielectric::new(t.f32("ior")?, t.scalar("roughness", textures)?))
+        }
+        "principled" => {
+            t.check_keys(&["type", "base_color", "metallic", "roughness", "specular", "sheen", "clearcoat", "clearcoat_gloss",
+                           "transmission", "ior"])?;
+            let d = PrincipledParams::default();
+            Box::new(Principled::new(PrincipledParams {
+                base_color: t.opt_color("base_color", textures)?.unwrap_or(d.base_color),
+                metallic: t.opt_fraction("metallic", textures)?.unwrap_or(d.metallic),
+                roughness: t.opt_fraction("roughness", textures)?.unwrap_or(d.roughness),
+                specular: t.opt_fraction("specular", textures)?.unwrap_or(d.specular),
+                sheen: t.opt_fraction("sheen", textures)?.unwrap_or(d.sheen),
+                clearcoat: t.opt_fraction("clearcoat", textures)?.unwrap_or(d.clearcoat),
+                clearcoat_gloss: t.opt_fraction("clearcoat_gloss", textures)?.unwrap_or(d.clearcoat_gloss),
+                transmission: t.opt_fraction("transmission", textures)?.unwrap_or(d.transmission),
+                ior: t.opt_f32("ior")?.unwrap_or(d.ior),
+            }))
+        }
+        "diffuse_light" => {
+            t.check_keys(&["type", "emit"])?;
+            Box::new(DiffuseLight::textured(t.color("emit", textures)?))
+        }
+        _ => return Err(t.error("type", "unknown material type (expected lambertian, metal, dielectric, conductor, rough_dielectric, principled or diffuse_light)")),
+    };
+    Ok(material)
+}
+
+pub fn parse_scene(src: &str, path: &Path) -> Result<Scene, SceneError> {
+    let doc: toml::Value = src.parse().map_err(|error| SceneError::Toml { path: path.to_path_buf(), error })?;
+    let root = Table::new(path, String::new(), &doc)?;
+    root.check_keys(&["image", "camera", "textures", "materials", "objects"])?;
+
+    let mut image = ImageSettings::default();
+    if let Some(t) = root.table("image")? {
+        t.check_keys(&["width", "height", "samples", "background"])?;
+        image = ImageSettings { width: t.opt_u32("width")?, height: t.opt_u32("height")?, samples: t.opt_u32("samples")?,
+                                background: t.opt_background("background")? };
+    }
+
+    let mut camera = None;
+    if let Some(t) = root.table("camera")? {
+        t.check_keys(&["from", "to", "up", "vfov", "aperture", "focus_distance"])?;
+        let from = t.vec3("from")?;
+        let to = t.vec3("to")?;
+        if (to - from).length() == 0.0 {
+            return Err(t.error("to", "must be different from 'from'"));
+        }
+        camera = Some(CameraParams { from, to,
+                                     up: t.opt_vec3("up")?.unwrap_or_else(|| Vec3::new(0.0, 1.0, 0.0)),
+                                     vfov_deg: t.f32("vfov")?,
+                                     aperture: t.opt_f32("aperture")?.unwrap_or(0.0),
+                                     dist_to_focus: t.opt_f32("focus_distance")?.unwrap_or_else(|| (to - from).length()) });
+    }
+
+    // textures are loaded once, and shared by the materials using them
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut textures: Textures = HashMap::new();
+    if let Some(t) = root.table("textures")? {
+        for (name, value) in t.table {
+            textures.insert(name, parse_texture(&Table::new(path, t.key(name), value)?, dir)?);
+        }
+    }
+
+    // check all the materials upfront, so that errors are reported even for unused ones
+    let mut materials: HashMap<&str, Table> = HashMap::new();
+    if let Some(t) = root.table("materials")? {
+        for (name, value) in t.table {
+            let mat = Table::new(path, t.key(name), value)?;
+            parse_material(&mat, &textures)?;
+            materials.insert(name, mat);
+        }
+    }
+    let material = |t: &Table| -> Result<Box<dyn Material>, SceneError> {
+        let name = t.str("material")?;
+        match materials.get(name) {
+            Some(m) => parse_material(m, &textures),
+            None => Err(t.error("material", &format!("unknown material '{}'", name))),
+        }
+    };
+
+    let objects = match doc.get("objects") {
+        Some(toml::Value::Array(objects)) => objects,
+        Some(_) => return Err(root.error("objects", "expected an array of tables ([[objects]])")),
+        None => return Err(root.error("objects", "the scene has no objects")),
+    };
+    let mut world = HitableList::new();
+    for (i, value) in objects.iter().enumerate() {
+        let t = Table::new(path, format!("objects[{}]", i), value)?;
+        match t.str("type")? {
+            "sphere" => {
+                t.check_keys(&["type", "center", "radius", "material"])?;
+                world.add(Sphere::new(t.vec3("center")?, t.f32("radius")?, material(&t)?));
+            }
+            "triangle" => {
+                t.check_keys(&["type", "vertices", "material"])?;
+                let vertices = match t.table.get("vertices").and_then(|v| v.as_array()) {
+                    Some(v) if v.len() == 3 => v.iter().map(to_vec3).collect::<Option<Vec<Vec3>>>(),
+                    _ => None,
+                };
+                let v = vertices.ok_or_else(|| t.error("vertices", "expected an array of 3 points"))?;
+                world.add(Triangle::new(v[0], v[1], v[2], material(&t)?));
+            }
+            "mesh" => {
+                t.check_keys(&["type", "file", "material"])?;
+                let file = dir.join(t.str("file")?);
+                let model_error = |error: Box<dyn std::error::Error>| SceneError::Model { path: path.to_path_buf(), key: t.key("file"), error };
+                let extension = file.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+                match extension.as_str() {
+                    "ply" => {
+                        let mat = match t.opt_str("material")? {
+                            Some(_) => material(&t)?,
+                            None => Box::new(Lambertian::new(Vec3::new(0.8,0.8,0.8))),
+                        };
+                        world.add(ply_loader::load_ply(&file, mat, true).map_err(|e| model_error(e.into()))?);
+                    }
+                    // only models: a nested toml scene could include itself, and its camera and image would be lost
+                    "obj" | "gltf" | "glb" => {
+                        if t.table.contains_key("material") {
+                            return Err(t.error("material", "only .ply meshes take a material, other formats define their own"));
+                        }
+                        match load_scene(&file) {
+                            Ok(scene) => world.add(scene.world),
+                            Err(SceneError::Model { error, .. }) => return Err(model_error(error)),
+                            Err(e) => return Err(model_error(e.into())),
+                        }
+                    }
+                    _ => return Err(t.error("file", "unsupported mesh format")),
+                }
+            }
+            _ => return Err(t.error("type", "unknown object type (expected sphere, triangle or mesh)")),
+        }
+    }
+    Ok(Scene { world, camera, image })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pt_math::Ray;
+    use crate::objects::Hitable;
+
+    const SCENE: &str = r#"
+[image]
+width = 320
+samples = 4
+
+[camera]
+from = [0, 1, 5]
+to = [0, 0, 0]
+vfov = 40.0
+
+[materials.red]
+type = "lambertian"
+albedo = [0.8, 0.1, 0.1]
+
+[materials.mirror]
+type = "metal"
+albedo = [0.9, 0.9, 0.9]
+
+[materials.lamp]
+type = "diffuse_light"
+emit = [4, 4, 4]
+
+[[objects]]
+type = "sphere"
+center = [0, 0, -1]
+radius = 0.5
+material = "red"
+
+[[objects]]
+type = "sphere"
+center = [0, 10, 0]
+radius = 1
+material = "lamp"
+
+[[objects]]
+type = "triangle"
+vertices = [[-1, -1, -3], [1, -1, -3], [0, 1, -3]]
+material = "mirror"
+"#;
+
+    fn parse(src: &str) -> Result<Scene, SceneError> {
+        parse_scene(src, Path::new("scene.toml"))
+    }
+
+    // the key reported by a validation error
+    fn error_key(src: &str) -> String {
+        match parse(src) {
+            Err(SceneError::Invalid { key, .. }) => key,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("parsing should fail"),
+        }
+    }
+
+    #[test]
+    fn scene_parse() {
+        let scene = parse(SCENE).unwrap();
+        assert_eq!(scene.world.objects.len(), 3);
+        assert_eq!(scene.image.width, Some(320));
+        assert_eq!(scene.image.height, None);
+        assert_eq!(scene.image.samples, Some(4));
+        assert!(scene.image.background.is_none());
+        let camera = scene.camera.unwrap();
+        assert_eq!(camera.from.z, 5.0);
+        assert_eq!(camera.up.y, 1.0);
+        assert_eq!(camera.vfov_deg, 40.0);
+        assert_eq!(camera.aperture, 0.0);
+        assert!((camera.dist_to_focus - 26.0f32.sqrt()).abs() < 1e-5);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = scene.world.hit(&ray, 0.0, 100.0).unwrap();
+        assert!((hit.t - 0.5).abs() < 1e-5);
+        // the light sphere
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let hit = scene.world.hit(&ray, 0.0, 100.0).unwrap();
+        assert_eq!(hit.material.emitted(&hit).y, 4.0);
+    }
+
+    #[test]
+    fn scene_background() {
+        let scene = parse(&SCENE.replace("samples = 4", "background = [0, 0, 0.1]")).unwrap();
+        match scene.image.background {
+            Some(Background::Color(c)) => assert_eq!((c.x, c.y, c.z), (0.0, 0.0, 0.1)),
+            _ => panic!("expected a background color"),
+        }
+        let scene = parse(&SCENE.replace("samples = 4", "background = \"sky\"")).unwrap();
+        assert!(matches!(scene.image.background, Some(Background::Sky)));
+        assert_eq!(error_key(&SCENE.replace("samples = 4", "background = \"night\"")), "image.background");
+        assert_eq!(error_key(&SCENE.replace("samples = 4", "background = [0, -1, 0]")), "image.background");
+    }
+
+    #[test]
+    fn scene_errors() {
+        let principled = SCENE.to_string() + "[materials.plastic]\ntype = \"principled\"\nmetallic = 1.5\n";
+        assert_eq!(error_key(&principled), "materials.plastic.metallic");
+        assert_eq!(error_key(&principled.replace("metallic = 1.5", "transmission = -0.1")), "materials.plastic.transmission");
+        assert!(parse(&principled.replace("metallic = 1.5", "metallic = 1.0\nclearcoat = 0.0")).is_ok());
+        assert_eq!(error_key(&SCENE.replace("radius = 0.5", "radius = \"big\"")), "objects[0].radius");
+        assert_eq!(error_key(&SCENE.replace("radius = 0.5", "radus = 0.5")), "objects[0].radus");
+        assert_eq!(error_key(&SCENE.replace("material = \"red\"", "material = \"blue\"")), "objects[0].material");
+        assert_eq!(error_key(&SCENE.replace("type = \"sphere\"", "type = \"cube\"")), "objects[0].type");
+        assert_eq!(error_key(&SCENE.replace("[[-1, -1, -3], [1, -1, -3], [0, 1, -3]]", "[[-1, -1, -3], [1, -1, -3]]")), "objects[2].vertices");
+        assert_eq!(error_key(&SCENE.replace("albedo = [0.8, 0.1, 0.1]", "albedo = [0.8, 0.1]")), "materials.red.albedo");
+        assert_eq!(error_key(&SCENE.replace("type = \"metal\"", "type = \"plastic\"")), "materials.mirror.type");
+        assert_eq!(error_key(&SCENE.replace("emit = [4, 4, 4]", "emit = 4")), "materials.lamp.emit");
+        assert_eq!(error_key(&SCENE.replace("type = \"metal\"", "type = \"conductor\"")), "materials.mirror.albedo");
+        assert_eq!(error_key(&SCENE.replace("vfov = 40.0\n", "")), "camera.vfov");
+        assert_eq!(error_key(&SCENE.replace("width = 320", "width = -3")), "image.width");
+        assert_eq!(error_key(&SCENE.replace("[image]", "[images]")), "images");
+        assert_eq!(error_key("[image]\nwidth = 10\n"), "objects");
+    }
+
+    #[test]
+    fn scene_microfacet_materials() {
+        let scene = SCENE.replace("type = \"metal\"\nalbedo = [0.9, 0.9, 0.9]", "type = \"conductor\"\ncolor = [0.9, 0.9, 0.9]\nroughness = 0.2")
+                         .replace("type = \"lambertian\"\nalbedo = [0.8, 0.1, 0.1]", "type = \"rough_dielectric\"\nior = 1.5\nroughness = 0.1");
+        let scene = parse(&scene).unwrap();
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = scene.world.hit(&ray, 0.0, 100.0).unwrap();
+        assert!(match hit.material.scatter(ray, &hit) { Some(s) => s.pdf.is_some(), None => true });
+        assert_eq!(error_key(&SCENE.replace("type = \"metal\"", "type = \"rough_dielectric\"\nior = 1.5")), "materials.mirror.albedo");
+    }
+
+    #[test]
+    fn scene_principled_material() {
+        let scene = SCENE.replace("type = \"lambertian\"\nalbedo = [0.8, 0.1, 0.1]", "type = \"principled\"\nbase_color = [0.8, 0.1, 0.1]\nclearcoat = 1");
+        let scene = parse(&scene).unwrap();
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = scene.world.hit(&ray, 0.0, 100.0).unwrap();
+        assert_eq!(hit.material.albedo(&hit).x, 0.8);
+        assert_eq!(error_key(&SCENE.replace("type = \"metal\"\nalbedo = [0.9, 0.9, 0.9]", "type = \"principled\"\nmetallic = \"yes\"")),
+                   "materials.mirror.metallic");
+    }
+
+    #[test]
+    fn scene_textures() {
+        let checks = "\n[textures.checks]\ntype = \"checker\"\neven = [1, 1, 1]\nodd = [0.2, 0.3, 0.4]\n";
+        let scene = SCENE.replace("albedo = [0.8, 0.1, 0.1]", "albedo = \"checks\"") + checks;
+        let scene = parse(&scene).unwrap();
+        // the front sphere is hit at z=-0.5, in an odd cell
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = scene.world.hit(&ray, 0.0, 100.0).unwrap();
+        assert_eq!(hit.material.albedo(&hit).z, 0.4);
+        assert_eq!(error_key(&SCENE.replace("albedo = [0.8, 0.1, 0.1]", "albedo = \"stripes\"")), "materials.red.albedo");
+        assert_eq!(error_key(&(SCENE.to_string() + checks + "size = -1\n")), "textures.checks.size");
+        let missing = SCENE.to_string() + "\n[textures.wood]\ntype = \"image\"\nfile = \"missing.png\"\n";
+        assert!(matches!(parse(&missing), Err(SceneError::Model { .. })));
+        assert_eq!(error_key(&(missing + "wrap = \"tile\"\n")), "textures.wood.wrap");
+        let marble = "\n[textures.stone]\ntype = \"marble\"\nscale = 4\nseed = 2\nlight = [0.9, 0.9, 0.85]\n";
+        let scene = SCENE.replace("albedo = [0.8, 0.1, 0.1]", "albedo = \"stone\"") + marble;
+        let scene = parse(&scene).unwrap();
+        let hit = scene.world.hit(&ray, 0.0, 100.0).unwrap();
+        assert!(hit.material.albedo(&hit).x <= 0.9);
+        assert_eq!(error_key(&(SCENE.to_string() + &marble.replace("marble", "granite"))), "textures.stone.type");
+        assert_eq!(error_key(&(SCENE.to_string() + &marble.replace("scale = 4", "scale = 0"))), "textures.stone.scale");
+    }
+
+    #[test]
+    fn scene_toml_syntax_error() {
+        assert!(matches!(parse("[camera\nfrom = 1"), Err(SceneError::Toml { .. })));
+    }
+
+    #[test]
+    fn scene_mesh_objects() {
+        let dir = std::env::temp_dir().join(format!("path_tracer_scene_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("tri.obj"), "v 0 0 -2\nv 1 0 -2\nv 0 1 -2\nf 1 2 3\n").unwrap();
+        let scene = SCENE.to_string() + "[[objects]]\ntype = \"mesh\"\nfile = \"tri.obj\"\n";
+        std::fs::write(dir.join("scene.toml"), &scene).unwrap();
+        let bad_scene = SCENE.to_string() + "[[objects]]\ntype = \"mesh\"\nfile = \"missing.obj\"\n";
+        std::fs::write(dir.join("bad_scene.toml"), &bad_scene).unwrap();
+
+        let loaded = load_scene(&dir.join("scene.toml"));
+        let bad = load_scene(&dir.join("bad_scene.toml"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.unwrap().world.objects.len(), 4);
+        match bad {
+            Err(SceneError::Model { key, .. }) => assert_eq!(key, "objects[3].file"),
+            _ => panic!("expected a model error"),
+        }
+        // toml scenes are not meshes, and can't include themselves
+        let recursive = SCENE.to_string() + "[[objects]]\ntype = \"mesh\"\nfile = \"scene.toml\"\n";
+        match parse(&recursive) {
+            Err(SceneError::Invalid { key, .. }) => assert_eq!(key, "objects[3].file"),
+            _ => panic!("expected an invalid mesh file"),
+        }
+    }
+
+    #[test]
+    fn scene_unsupported_format() {
+        assert!(matches!(load_scene(Path::new("scene.fbx")), Err(SceneError::UnsupportedFormat { .. })));
+    }
+}
@@ -0,0 +1,559 @@
+use crate::pt_math::Vec3;
+use crate::noise::Perlin;
+use crate::color::srgb_to_linear;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// spatially varying value of a material parameter. Color parameters use the three channels, scalar ones
+// (roughness, metallic, ...) the first one
+pub trait Texture: Send + Sync {
+    // value at the surface coordinates (u,v) of the hit point p
+    fn value(&self, u: f32, v: f32, p: Vec3) -> Vec3;
+}
+
+// textures are shared between the materials (and objects) using them
+pub fn solid(color: Vec3) -> Arc<dyn Texture> {
+    Arc::new(SolidColor::new(color))
+}
+
+pub fn constant(value: f32) -> Arc<dyn Texture> {
+    solid(Vec3::new(value, value, value))
+}
+
+// /////////// //
+// Solid color //
+// /////////// //
+pub struct SolidColor {
+    color: Vec3,
+}
+
+impl SolidColor {
+    pub fn new(color: Vec3) -> SolidColor {
+        SolidColor { color }
+    }
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _u: f32, _v: f32, _p: Vec3) -> Vec3 {
+        self.color
+    }
+}
+
+// //////////// //
+// Checkerboard //
+// //////////// //
+// 3d checkerboard of cubes with the given size, alternating two textures. Being defined in space, it also
+// works on objects without surface coordinates
+pub struct Checker {
+    even: Arc<dyn Texture>,
+    odd: Arc<dyn Texture>,
+    size: f32,
+}
+
+impl Checker {
+    pub fn new(even: Arc<dyn Texture>, odd: Arc<dyn Texture>, size: f32) -> Checker {
+        Checker { even, odd, size }
+    }
+}
+
+impl Texture for Checker {
+    fn value(&self, u: f32, v: f32, p: Vec3) -> Vec3 {
+        let cell = (p.x/self.size).floor() as i64 + (p.y/self.size).floor() as i64 + (p.z/self.size).floor() as i64;
+        if cell % 2 == 0 { self.even.value(u, v, p) } else { self.odd.value(u, v, p) }
+    }
+}
+
+// //////////////// //
+// Procedural noise //
+// //////////////// //
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum NoisePattern {
+    Noise,
+    Turbulence,
+    Fbm,
+    Marble,
+    Wood,
+}
+
+impl NoisePattern {
+    pub fn from_name(name: &str) -> Option<NoisePattern> {
+        match name {
+            "noise" => Some(NoisePattern::Noise),
+            "turbulence" => Some(NoisePattern::Turbulence),
+            "fbm" => Some(NoisePattern::Fbm),
+            "marble" => Some(NoisePattern::Marble),
+            "wood" => Some(NoisePattern::Wood),
+            _ => None,
+        }
+    }
+}
+
+const NOISE_OCTAVES: u32 = 7;
+
+// pattern of Perlin noise in space, blending two textures. The scale is the frequency of the pattern (the
+// inverse of the feature size), and the seed selects one of the possible patterns
+pub struct NoiseTexture {
+    perlin: Perlin,
+    pattern: NoisePattern,
+    scale: f32,
+    dark: Arc<dyn Texture>,
+    light: Arc<dyn Texture>,
+}
+
+impl NoiseTexture {
+    pub fn new(pattern: NoisePattern, scale: f32, seed: u64, dark: Arc<dyn Texture>, light: Arc<dyn Texture>) -> NoiseTexture {
+        NoiseTexture { perlin: Perlin::new(seed), pattern, scale, dark, light }
+    }
+
+    // blending factor between the two textures, in [0,1]
+    fn blend(&self, p: Vec3) -> f32 {
+        let sp = p*self.scale;
+        let t = match self.pattern {
+            NoisePattern::Noise => 0.5*(1.0 + self.perlin.noise(sp)),
+            NoisePattern::Turbulence => self.perlin.turbulence(sp, NOISE_OCTAVES),
+            NoisePattern::Fbm => 0.5*(1.0 + self.perlin.fbm(sp, NOISE_OCTAVES)),
+            // stripes along z, with the frequency of the scale, perturbed by large scale turbulence
+            NoisePattern::Marble => 0.5*(1.0 + (sp.z + 10.0*self.perlin.turbulence(p, NOISE_OCTAVES)).sin()),
+            // distorted rings around the y axis
+            NoisePattern::Wood => {
+                let r = (sp.x*sp.x + sp.z*sp.z).sqrt() + 0.5*self.perlin.fbm(sp, NOISE_OCTAVES);
+                r - r.floor()
+            }
+        };
+        t.clamp(0.0, 1.0)
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, u: f32, v: f32, p: Vec3) -> Vec3 {
+        let t = self.blend(p);
+        self.dark.value(u, v, p)*(1.0 - t) + self.light.value(u, v, p)*t
+    }
+}
+
+// ///////////// //
+// Image texture //
+// ///////////// //
+#[derive(Debug)]
+pub enum TextureError {
+    Io { path: PathBuf, error: std::io::Error },
+    Decode { path: PathBuf, message: String },
+    UnsupportedFormat { path: PathBuf },
+}
+
+impl fmt::Display for TextureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TextureError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            TextureError::Decode { path, message } => write!(f, "{}: {}", path.display(), message),
+            TextureError::UnsupportedFormat { path } => write!(f, "{}: unsupported image format", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for TextureError {}
+
+// how the texture coordinates outside of [0,1] are mapped on the image
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl WrapMode {
+    pub fn from_name(name: &str) -> Option<WrapMode> {
+        match name {
+            "repeat" => Some(WrapMode::Repeat),
+            "clamp" => Some(WrapMode::Clamp),
+            "mirror" => Some(WrapMode::Mirror),
+            _ => None,
+        }
+    }
+
+    // index of the pixel used for the (possibly out of range) index i, in an image row or column of size n
+    fn apply(self, i: i64, n: usize) -> usize {
+        let n = n as i64;
+        let i = match self {
+            WrapMode::Repeat => i.rem_euclid(n),
+            WrapMode::Clamp => i.clamp(0, n - 1),
+            WrapMode::Mirror => {
+                let i = i.rem_euclid(2*n);
+                if i < n { i } else { 2*n - 1 - i }
+            }
+        };
+        i as usize
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+}
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "nearest" => Some(Filter::Nearest),
+            "bilinear" => Some(Filter::Bilinear),
+            _ => None,
+        }
+    }
+}
+
+// image mapped on the surface coordinates, with (0,0) at the bottom left corner. The data is linear: 8 bit
+// images are usually sRGB encoded, and are converted when loaded
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    data: Vec<Vec3>, // rows from the top
+    wrap: WrapMode,
+    filter: Filter,
+}
+
+impl ImageTexture {
+    pub fn new(width: usize, height: usize, data: Vec<Vec3>) -> ImageTexture {
+        assert_eq!(data.len(), width*height, "image size doesn't match the data");
+        ImageTexture { width, height, data, wrap: WrapMode::Repeat, filter: Filter::Bilinear }
+    }
+
+    pub fn with_sampling(self, wrap: WrapMode, filter: Filter) -> ImageTexture {
+        ImageTexture { wrap, filter, ..self }
+    }
+
+    // png, jpeg and radiance hdr images. `srgb` tells if the 8 and 16 bit formats store sRGB encoded colors
+    // (albedo maps) or linear values (roughness maps and other data); hdr images are always linear
+    pub fn load(path: &Path, srgb: bool) -> Result<ImageTexture, TextureError> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+        let image = match extension.as_str() {
+            "png" => load_png(path)?,
+            "jpg" | "jpeg" => load_jpeg(path)?,
+            "hdr" => return load_hdr(path),
+            _ => return Err(TextureError::UnsupportedFormat { path: path.to_path_buf() }),
+        };
+        if !srgb {
+            return Ok(image);
+        }
+        let decode = |c: Vec3| Vec3::new(srgb_to_linear(c.x), srgb_to_linear(c.y), srgb_to_linear(c.z));
+        Ok(ImageTexture { data: image.data.into_iter().map(decode).collect(), ..image })
+    }
+
+    fn pixel(&self, i: i64, j: i64) -> Vec3 {
+        self.data[self.wrap.apply(j, self.height)*self.width + self.wrap.apply(i, self.width)]
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f32, v: f32, _p: Vec3) -> Vec3 {
+        // continuous image coordinates, with the pixel centers at half integers
+        let x = u*self.width as f32;
+        let y = (1.0 - v)*self.height as f32;
+        match self.filter {
+            Filter::Nearest => self.pixel(x.floor() as i64, y.floor() as i64),
+            Filter::Bilinear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (i, j) = (x.floor() as i64, y.floor() as i64);
+                let (fx, fy) = (x - x.floor(), y - y.floor());
+                let top = self.pixel(i, j)*(1.0 - fx) + self.pixel(i + 1, j)*fx;
+                let bottom = self.pixel(i, j + 1)*(1.0 - fx) + self.pixel(i + 1, j + 1)*fx;
+                top*(1.0 - fy) + bottom*fy
+            }
+        }
+    }
+}
+
+// 8 and 16 bit png images, in any color type
+fn load_png(path: &Path) -> Result<ImageTexture, TextureError> {
+    let file = std::fs::File::open(path).map_err(|error| TextureError::Io { path: path.to_path_buf(), error })?;
+    let decode_error = |e: png::DecodingError| TextureError::Decode { path: path.to_path_buf(), message: e.to_string() };
+    let mut decoder = png::Decoder::new(file);
+    // palettes are expanded to rgb, and low bit depths to 8 bits
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let (info, mut reader) = decoder.read_info().map_err(decode_error)?;
+    let mut buf = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buf).map_err(decode_error)?;
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::RGB | png::ColorType::Indexed => 3,
+        png::ColorType::RGBA => 4,
+    };
+    let samples: Vec<f32> = match info.bit_depth {
+        png::BitDepth::Sixteen => buf.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as f32 / 65535.0).collect(),
+        _ => buf.iter().map(|&b| b as f32 / 255.0).collect(),
+    };
+    let (width, height) = (info.width as usize, info.height as usize);
+    if width == 0 || height == 0 {
+        return Err(TextureError::Decode { path: path.to_path_buf(), message: "empty image".to_string() });
+    }
+    let data = samples.chunks_exact(channels).take(width*height).map(|p| match channels {
+        1 | 2 => Vec3::new(p[0], p[0], p[0]),
+        _ => Vec3::new(p[0], p[1], p[2]),
+    }).collect::<Vec<Vec3>>();
+    if data.len() != width*height {
+        return Err(TextureError::Decode { path: path.to_path_buf(), message: "truncated image data".to_string() });
+    }
+    Ok(ImageTexture::new(width, height, data))
+}
+
+// baseline and progressive jpeg images, grayscale or rgb
+fn load_jpeg(path: &Path) -> Result<ImageTexture, TextureError> {
+    let file = std::fs::File::open(path).map_err(|error| TextureError::Io { path: path.to_path_buf(), error })?;
+    let decode_error = |message: String| TextureError::Decode { path: path.to_path_buf(), message };
+    let mut decoder = jpeg_decoder::Decoder::new(std::io::BufReader::new(file));
+    let pixels = decoder.decode().map_err(|e| decode_error(e.to_string()))?;
+    let info = decoder.info().ok_or_else(|| decode_error("missing image header".to_string()))?;
+    let data: Vec<Vec3> = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => pixels.iter().map(|&l| Vec3::new(l as f32, l as f32, l as f32) / 255.0).collect(),
+        jpeg_decoder::PixelFormat::L16 => pixels.chunks_exact(2).map(|b| {
+            let l = u16::from_be_bytes([b[0], b[1]]) as f32 / 65535.0;
+            Vec3::new(l, l, l)
+        }).collect(),
+        jpeg_decoder::PixelFormat::RGB24 => pixels.chunks_exact(3).map(|p| Vec3::new(p[0] as f32, p[1] as f32, p[2] as f32) / 255.0).collect(),
+        jpeg_decoder::PixelFormat::CMYK32 => return Err(decode_error("cmyk images are not supported".to_string())),
+    };
+    let (width, height) = (info.width as usize, info.height as usize);
+    if width == 0 || height == 0 {
+        return Err(decode_error("empty image".to_string()));
+    }
+    if data.len() != width*height {
+        return Err(decode_error("truncated image data".to_string()));
+    }
+    Ok(ImageTexture::new(width, height, data))
+}
+
+// radiance rgbe images (.hdr), flat or run length encoded, in the standard top to bottom orientation
+fn load_hdr(path: &Path) -> Result<ImageTexture, TextureError> {
+    let bytes = std::fs::read(path).map_err(|error| TextureError::Io { path: path.to_path_buf(), error })?;
+    let decode_error = |message: &str| TextureError::Decode { path: path.to_path_buf(), message: message.to_string() };
+    // text header, terminated by an empty line and followed by the resolution line
+    let mut lines: Vec<String> = Vec::new();
+    let mut pos = 0;
+    while lines.len() < 2 || !lines[lines.len() - 2].is_empty() {
+        let end = bytes[pos..].iter().position(|&b| b == b'\n').ok_or_else(|| decode_error("truncated header"))?;
+        lines.push(String::from_utf8_lossy(&bytes[pos..pos + end]).trim().to_string());
+        pos += end + 1;
+    }
+    if !lines[0].starts_with("#?") {
+        return Err(decode_error("not a radiance image"));
+    }
+    if lines.iter().any(|l| l.starts_with("FORMAT=") && l != "FORMAT=32-bit_rle_rgbe") {
+        return Err(decode_error("unsupported pixel format"));
+    }
+    let resolution: Vec<&str> = lines[lines.len() - 1].split_whitespace().collect();
+    let (height, width) = match resolution.as_slice() {
+        ["-Y", h, "+X", w] => (h.parse::<usize>().ok(), w.parse::<usize>().ok()),
+        _ => return Err(decode_error("unsupported image orientation")),
+    };
+    let (width, height) = width.zip(height).ok_or_else(|| decode_error("invalid image size"))?;
+    if width == 0 || height == 0 {
+        return Err(decode_error("empty image"));
+    }
+
+    let mut data = Vec::with_capacity(width*height);
+    let mut scanline = vec![0u8; 4*width];
+    for _ in 0..height {
+        let rest = &bytes[pos..];
+        if (8..0x8000).contains(&width) && rest.len() >= 4 && rest[0] == 2 && rest[1] == 2 && rest[2] & 0x80 == 0 {
+            // run length encoded scanline: the four components are stored one after the other
+            if ((rest[2] as usize) << 8 | rest[3] as usize) != width {
+                return Err(decode_error("invalid scanline width"));
+            }
+            pos += 4;
+            for c in 0..4 {
+                let mut i = 0;
+                while i < width {
+                    let count = *bytes.get(pos).ok_or_else(|| decode_error("truncated image data"))? as usize;
+                    if count > 128 {
+                        let value = *bytes.get(pos + 1).ok_or_else(|| decode_error("truncated image data"))?;
+                        if i + count - 128 > width {
+                            return Err(decode_error("invalid run length"));
+                        }
+                        (i..i + count - 128).for_each(|k| scanline[4*k + c] = value);
+                        i += count - 128;
+                        pos += 2;
+                    } else {
+                        if count == 0 || i + count > width || pos + 1 + count > bytes.len() {
+                            return Err(decode_error("invalid run length"));
+                        }
+                        (0..count).for_each(|k| scanline[4*(i + k) + c] = bytes[pos + 1 + k]);
+                        i += count;
+                        pos += 1 + count;
+                    }
+                }
+            }
+        } else {
+            if rest.len() < 4*width {
+                return Err(decode_error("truncated image data"));
+            }
+            scanline.copy_from_slice(&rest[..4*width]);
+            pos += 4*width;
+        }
+        data.extend(scanline.chunks_exact(4).map(|p| rgbe_to_rgb([p[0], p[1], p[2], p[3]])));
+    }
+    Ok(ImageTexture::new(width, height, data))
+}
+
+// shared exponent encoding of the radiance format
+fn rgbe_to_rgb(rgbe: [u8; 4]) -> Vec3 {
+    if rgbe[3] == 0 {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+    let scale = 2.0f32.powi(rgbe[3] as i32 - (128 + 8));
+    Vec3::new(rgbe[0] as f32, rgbe[1] as f32, rgbe[2] as f32)*scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approx_eq(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn solid_texture() {
+        let c = solid(Vec3::new(0.1, 0.2, 0.3)).value(0.5, 0.5, Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!((c.x, c.y, c.z), (0.1, 0.2, 0.3));
+        assert_eq!(constant(0.4).value(0.0, 0.0, Vec3::new(0.0, 0.0, 0.0)).z, 0.4);
+    }
+
+    #[test]
+    fn checker_texture() {
+        let checker = Checker::new(constant(1.0), constant(0.0), 0.5);
+        let at = |x: f32, y: f32, z: f32| checker.value(0.0, 0.0, Vec3::new(x, y, z)).x;
+        assert_eq!(at(0.1, 0.1, 0.1), 1.0);
+        assert_eq!(at(0.6, 0.1, 0.1), 0.0);
+        assert_eq!(at(0.6, 0.6, 0.1), 1.0);
+        // negative coordinates continue the pattern
+        assert_eq!(at(-0.1, 0.1, 0.1), 0.0);
+        assert_eq!(at(-0.6, 0.1, 0.1), 1.0);
+    }
+
+    #[test]
+    fn noise_textures() {
+        let dark = solid(Vec3::new(0.1, 0.0, 0.0));
+        let light = solid(Vec3::new(0.9, 1.0, 0.0));
+        let points: Vec<Vec3> = (0..500).map(|i| Vec3::new((i % 10) as f32 * 0.31, ((i / 10) % 10) as f32 * 0.47, (i / 100) as f32 * 0.59)).collect();
+        for &name in &["noise", "turbulence", "fbm", "marble", "wood"] {
+            let pattern = NoisePattern::from_name(name).unwrap();
+            let texture = NoiseTexture::new(pattern, 2.0, 3, dark.clone(), light.clone());
+            // blends of the two colors, actually varying in space
+            let values: Vec<f32> = points.iter().map(|&p| texture.value(0.0, 0.0, p).y).collect();
+            assert!(values.iter().all(|&v| (0.0..=1.0).contains(&v)), "{}", name);
+            assert!(points.iter().all(|&p| approx_eq(texture.value(0.0, 0.0, p).x, 0.1 + 0.8*texture.value(0.0, 0.0, p).y)));
+            let (min, max) = values.iter().fold((1.0f32, 0.0f32), |(a, b), &v| (a.min(v), b.max(v)));
+            assert!(max - min > 0.3, "{} {} {}", name, min, max);
+            // reproducible with the same seed
+            let again = NoiseTexture::new(pattern, 2.0, 3, dark.clone(), light.clone());
+            assert!(points.iter().all(|&p| texture.value(0.0, 0.0, p).y == again.value(0.0, 0.0, p).y));
+        }
+        assert_eq!(NoisePattern::from_name("granite"), None);
+    }
+
+    // 2x2 image: red, green on top; blue, white at the bottom
+    fn quad_image() -> ImageTexture {
+        ImageTexture::new(2, 2, vec![Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0),
+                                     Vec3::new(0.0, 0.0, 1.0), Vec3::new(1.0, 1.0, 1.0)])
+    }
+
+    #[test]
+    fn image_texture_lookup() {
+        let p = Vec3::new(0.0, 0.0, 0.0);
+        let image = quad_image().with_sampling(WrapMode::Clamp, Filter::Nearest);
+        assert_eq!(image.value(0.2, 0.9, p).x, 1.0);
+        assert_eq!(image.value(0.7, 0.6, p).y, 1.0);
+        assert_eq!(image.value(0.1, 0.4, p).z, 1.0);
+        assert_eq!(image.value(0.9, 0.1, p).x, 1.0);
+        // clamped outside
+        assert_eq!(image.value(-1.0, 2.0, p).x, 1.0);
+        assert_eq!(image.value(1.0, 3.0, p).y, 1.0);
+        // repeated and mirrored: just right of the image is the left column, or the right one again
+        let image = quad_image().with_sampling(WrapMode::Repeat, Filter::Nearest);
+        assert_eq!(image.value(1.2, 0.9, p).x, 1.0);
+        assert_eq!(image.value(-0.2, 0.9, p).y, 1.0);
+        let image = quad_image().with_sampling(WrapMode::Mirror, Filter::Nearest);
+        assert_eq!(image.value(1.2, 0.9, p).y, 1.0);
+        assert_eq!(image.value(1.7, 0.9, p).x, 1.0);
+        assert_eq!(image.value(-0.2, 0.9, p).x, 1.0);
+        assert_eq!(WrapMode::from_name("mirror"), Some(WrapMode::Mirror));
+        assert_eq!(Filter::from_name("cubic"), None);
+    }
+
+    #[test]
+    fn image_texture_bilinear() {
+        let p = Vec3::new(0.0, 0.0, 0.0);
+        // exact values at the pixel centers, interpolated in between
+        let image = quad_image().with_sampling(WrapMode::Clamp, Filter::Bilinear);
+        let c = image.value(0.25, 0.75, p);
+        assert_eq!((c.x, c.y, c.z), (1.0, 0.0, 0.0));
+        let c = image.value(0.5, 0.75, p);
+        assert!(approx_eq(c.x, 0.5) && approx_eq(c.y, 0.5) && approx_eq(c.z, 0.0));
+        let c = image.value(0.5, 0.5, p);
+        assert!(approx_eq(c.x, 0.5) && approx_eq(c.y, 0.5) && approx_eq(c.z, 0.5));
+        // the border pixels with clamping, blended across the edge with repetition
+        assert_eq!(image.value(0.0, 1.0, p).x, 1.0);
+        let image = quad_image().with_sampling(WrapMode::Repeat, Filter::Bilinear);
+        let c = image.value(0.0, 0.75, p);
+        assert!(approx_eq(c.x, 0.5) && approx_eq(c.y, 0.5));
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("path_tracer_texture_test_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn image_texture_load_png() {
+        let path = temp_path("image.png");
+        {
+            let file = std::fs::File::create(&path).unwrap();
+            let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), 2, 1);
+            encoder.set_color(png::ColorType::RGB);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.write_header().unwrap().write_image_data(&[255, 0, 0, 0, 51, 188]).unwrap();
+        }
+        let linear = ImageTexture::load(&path, false).unwrap().with_sampling(WrapMode::Clamp, Filter::Nearest);
+        let srgb = ImageTexture::load(&path, true).unwrap().with_sampling(WrapMode::Clamp, Filter::Nearest);
+        std::fs::remove_file(&path).unwrap();
+        let p = Vec3::new(0.0, 0.0, 0.0);
+        let right = linear.value(0.75, 0.5, p);
+        assert_eq!((right.x, right.y, right.z), (0.0, 0.2, 188.0/255.0));
+        // sRGB decoding keeps black and white, and darkens the midtones
+        let right = srgb.value(0.75, 0.5, p);
+        assert!(approx_eq(right.y, 0.0331) && approx_eq(right.z, 0.5029), "{:?}", right);
+        assert_eq!(srgb.value(0.25, 0.5, p).x, 1.0);
+        assert!(matches!(ImageTexture::load(Path::new("missing.png"), true), Err(TextureError::Io { .. })));
+        assert!(matches!(ImageTexture::load(Path::new("missing.jpg"), true), Err(TextureError::Io { .. })));
+        assert!(matches!(ImageTexture::load(Path::new("image.gif"), true), Err(TextureError::UnsupportedFormat { .. })));
+    }
+
+    #[test]
+    fn image_texture_load_hdr() {
+        // 8x2 image: a flat scanline of 1.0 (mantissa 128, exponent 129), then a run length encoded one
+        // of 0.5, with 4.0 in the last pixel
+        let mut bytes = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 8\n".to_vec();
+        (0..8).for_each(|_| bytes.extend_from_slice(&[128, 128, 128, 129]));
+        bytes.extend_from_slice(&[2, 2, 0, 8]);
+        for _ in 0..3 {
+            bytes.extend_from_slice(&[128 + 8, 128]);
+        }
+        bytes.extend_from_slice(&[128 + 7, 128, 1, 131]);
+        let path = temp_path("image.hdr");
+        std::fs::write(&path, &bytes).unwrap();
+        let image = ImageTexture::load(&path, true).unwrap().with_sampling(WrapMode::Clamp, Filter::Nearest);
+        std::fs::write(&path, b"#?RADIANCE\n\n+Y 2 +X 8\n").unwrap();
+        let flipped = ImageTexture::load(&path, true);
+        // images without pixels can't be sampled
+        std::fs::write(&path, b"#?RADIANCE\n\n-Y 0 +X 8\n").unwrap();
+        let empty = ImageTexture::load(&path, true);
+        std::fs::remove_file(&path).unwrap();
+        let p = Vec3::new(0.0, 0.0, 0.0);
+        assert_eq!(image.value(0.5, 0.9, p).y, 1.0);
+        assert_eq!(image.value(0.5, 0.1, p).z, 0.5);
+        assert_eq!(image.value(0.99, 0.1, p).x, 4.0);
+        assert!(matches!(flipped, Err(TextureError::Decode { .. })));
+        assert!(matches!(empty, Err(TextureError::Decode { .. })));
+    }
+}
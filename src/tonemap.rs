@@ -0,0 +1,143 @@
+use crate::pt_math::{Vec3, dot};
+
+// operators compressing the linear radiance to the [0,1] range of the displays
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Operator {
+    Clamp,
+    Reinhard,
+    ExtendedReinhard,
+    Aces,
+    Hable,
+}
+
+impl Operator {
+    pub fn from_name(name: &str) -> Option<Operator> {
+        match name {
+            "clamp" => Some(Operator::Clamp),
+            "reinhard" => Some(Operator::Reinhard),
+            "extended" => Some(Operator::ExtendedReinhard),
+            "aces" => Some(Operator::Aces),
+            "hable" => Some(Operator::Hable),
+            _ => None,
+        }
+    }
+}
+
+// conversion of the rendered radiance to the displayed colors, still linear (the display encoding is applied later)
+#[derive(Clone,Copy,Debug)]
+pub struct ToneMapping {
+    pub operator: Operator,
+    pub exposure: f32, // in stops: every stop doubles the radiance
+    pub white: f32,    // luminance mapped to white by the extended Reinhard operator
+}
+
+impl Default for ToneMapping {
+    fn default() -> ToneMapping {
+        ToneMapping { operator: Operator::Clamp, exposure: 0.0, white: 4.0 }
+    }
+}
+
+impl ToneMapping {
+    pub fn apply(&self, c: Vec3) -> Vec3 {
+        let c = c*2.0f32.powf(self.exposure);
+        let c = Vec3::new(c.x.max(0.0), c.y.max(0.0), c.z.max(0.0));
+        let mapped = match self.operator {
+            Operator::Clamp => c,
+            // the Reinhard operators compress the luminance, keeping the hue
+            Operator::Reinhard => scale_luminance(c, |l| l / (1.0 + l)),
+            Operator::ExtendedReinhard => {
+                let w2 = self.white*self.white;
+                scale_luminance(c, |l| l*(1.0 + l/w2) / (1.0 + l))
+            }
+            Operator::Aces => aces_fitted(c),
+            Operator::Hable => {
+                // exposure bias and white point of the original Uncharted 2 curve
+                const EXPOSURE_BIAS: f32 = 2.0;
+                const WHITE: f32 = 11.2;
+                let white_scale = 1.0 / hable_curve(WHITE);
+                Vec3::new(hable_curve(EXPOSURE_BIAS*c.x), hable_curve(EXPOSURE_BIAS*c.y), hable_curve(EXPOSURE_BIAS*c.z))*white_scale
+            }
+        };
+        Vec3::new(mapped.x.clamp(0.0, 1.0), mapped.y.clamp(0.0, 1.0), mapped.z.clamp(0.0, 1.0))
+    }
+}
+
+// Rec.709 luminance
+fn luminance(c: Vec3) -> f32 {
+    dot(c, Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+fn scale_luminance<F: Fn(f32) -> f32>(c: Vec3, f: F) -> Vec3 {
+    let l = luminance(c);
+    if l > 0.0 { c*(f(l)/l) } else { c }
+}
+
+// Stephen Hill's fit of the ACES reference rendering and output transforms, for sRGB displays
+fn aces_fitted(c: Vec3) -> Vec3 {
+    let mul = |m: [[f32; 3]; 3], v: Vec3| Vec3::new(dot(Vec3::new(m[0][0], m[0][1], m[0][2]), v),
+                                                    dot(Vec3::new(m[1][0], m[1][1], m[1][2]), v),
+                                                    dot(Vec3::new(m[2][0], m[2][1], m[2][2]), v));
+    const INPUT: [[f32; 3]; 3] = [[0.59719, 0.35458, 0.04823], [0.07600, 0.90834, 0.01566], [0.02840, 0.13383, 0.83777]];
+    const OUTPUT: [[f32; 3]; 3] = [[1.60475, -0.53108, -0.07367], [-0.10208, 1.10813, -0.00605], [-0.00327, -0.07276, 1.07602]];
+    let fit = |v: f32| (v*(v + 0.0245786) - 0.000090537) / (v*(0.983729*v + 0.432951) + 0.238081);
+    let v = mul(INPUT, c);
+    mul(OUTPUT, Vec3::new(fit(v.x), fit(v.y), fit(v.z)))
+}
+
+// John Hable's filmic curve
+fn hable_curve(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x*(a*x + c*b) + d*e) / (x*(a*x + b) + d*f) - e/f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approx_eq(a: f32, b: f32, epsilon: f32) -> bool {
+        (a - b).abs() < epsilon
+    }
+
+    fn tone_map(operator: Operator, v: f32) -> Vec3 {
+        ToneMapping { operator, ..ToneMapping::default() }.apply(Vec3::new(v, v, v))
+    }
+
+    #[test]
+    fn tone_mapping_operators() {
+        for &name in &["clamp", "reinhard", "extended", "aces", "hable"] {
+            let operator = Operator::from_name(name).unwrap();
+            // black stays black, and the curves are increasing and bounded
+            assert!(approx_eq(tone_map(operator, 0.0).x, 0.0, 1e-3), "{}", name);
+            let values: Vec<f32> = (0..100).map(|i| tone_map(operator, i as f32 * 0.2).y).collect();
+            assert!(values.windows(2).all(|w| w[1] >= w[0]), "{}", name);
+            assert!(values.iter().all(|&v| (0.0..=1.0).contains(&v)), "{}", name);
+        }
+        assert_eq!(Operator::from_name("drago"), None);
+        assert_eq!(tone_map(Operator::Clamp, 0.3).x, 0.3);
+        assert_eq!(tone_map(Operator::Clamp, 5.0).x, 1.0);
+        assert!(approx_eq(tone_map(Operator::Reinhard, 1.0).x, 0.5, 1e-6));
+        // the white point is mapped to 1
+        assert!(approx_eq(tone_map(Operator::ExtendedReinhard, 4.0).x, 1.0, 1e-6));
+        assert!(tone_map(Operator::ExtendedReinhard, 3.0).x < 1.0);
+        assert!(approx_eq(tone_map(Operator::Hable, 11.2/2.0).x, 1.0, 1e-6));
+        // mid grey is darkened a bit
+        let grey = tone_map(Operator::Aces, 0.18).x;
+        assert!(grey > 0.1 && grey < 0.18, "{}", grey);
+        assert!(tone_map(Operator::Aces, 100.0).x > 0.99);
+    }
+
+    #[test]
+    fn tone_mapping_hue() {
+        // the Reinhard operators scale the color, keeping the ratios between the channels
+        let c = ToneMapping { operator: Operator::Reinhard, ..ToneMapping::default() }.apply(Vec3::new(2.0, 1.0, 0.5));
+        assert!(approx_eq(c.x/c.y, 2.0, 1e-5) && approx_eq(c.y/c.z, 2.0, 1e-5));
+    }
+
+    #[test]
+    fn exposure() {
+        let tone_mapping = ToneMapping { exposure: 2.0, ..ToneMapping::default() };
+        assert_eq!(tone_mapping.apply(Vec3::new(0.1, 0.2, 0.0)).y, 0.8);
+        let tone_mapping = ToneMapping { exposure: -1.0, ..ToneMapping::default() };
+        assert_eq!(tone_mapping.apply(Vec3::new(1.6, 0.0, 0.0)).x, 0.8);
+    }
+}